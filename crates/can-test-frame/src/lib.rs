@@ -0,0 +1,57 @@
+//! CAN frame for host tests.
+//!
+//! The protocol crates build frames of any `embedded-hal` 0.2 frame type; their tests use
+//! this one, which just stores what it is given.
+
+#![no_std]
+#![deny(missing_debug_implementations)]
+#![deny(unsafe_code)]
+
+use embedded_hal::can::{Frame, Id};
+
+/// Data or remote frame with up to 8 bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TestFrame {
+    id: Id,
+    remote: bool,
+    data: [u8; 8],
+    dlc: usize,
+}
+
+impl Frame for TestFrame {
+    fn new(id: impl Into<Id>, data: &[u8]) -> Option<Self> {
+        if data.len() > 8 {
+            return None;
+        }
+        let mut frame = TestFrame { id: id.into(), remote: false, data: [0; 8], dlc: data.len() };
+        frame.data[..data.len()].copy_from_slice(data);
+        Some(frame)
+    }
+
+    fn new_remote(id: impl Into<Id>, dlc: usize) -> Option<Self> {
+        if dlc > 8 {
+            return None;
+        }
+        Some(TestFrame { id: id.into(), remote: true, data: [0; 8], dlc })
+    }
+
+    fn is_extended(&self) -> bool {
+        matches!(self.id, Id::Extended(_))
+    }
+
+    fn is_remote_frame(&self) -> bool {
+        self.remote
+    }
+
+    fn id(&self) -> Id {
+        self.id
+    }
+
+    fn dlc(&self) -> usize {
+        self.dlc
+    }
+
+    fn data(&self) -> &[u8] {
+        &self.data[..self.dlc]
+    }
+}
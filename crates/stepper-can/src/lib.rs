@@ -0,0 +1,474 @@
+//! CAN commands for a remote stepper axis.
+//!
+//! A controller sends commands to the axis with node ID `node`, which answers each one with
+//! an acknowledgement or a status frame. Both are standard (11 bit) data frames:
+//!
+//! | identifier     | direction             |
+//! |----------------|-----------------------|
+//! | 0x200 + node   | command to the axis   |
+//! | 0x240 + node   | reply from the axis   |
+//!
+//! so commands win the arbitration against replies, and both against the weather telemetry
+//! at 0x400 and above. Node IDs are 0-63.
+//!
+//! A command starts with its opcode and a tag chosen by the controller, which the reply
+//! carries back. Values are little endian:
+//!
+//! | opcode | command          | arguments                       | DLC |
+//! |--------|------------------|---------------------------------|-----|
+//! | 0x01   | move to          | target i32 (microsteps)         | 6   |
+//! | 0x02   | set speed        | u32 (steps/s)                   | 6   |
+//! | 0x03   | set acceleration | u32 (steps/s²)                  | 6   |
+//! | 0x04   | home             |                                 | 2   |
+//! | 0x05   | stop             |                                 | 2   |
+//! | 0x06   | query            |                                 | 2   |
+//!
+//! | opcode | reply  | payload after the tag                                  | DLC |
+//! |--------|--------|--------------------------------------------------------|-----|
+//! | 0x80   | ack    | command opcode, result (0 accepted, else `Reject`)     | 4   |
+//! | 0x81   | status | position i32 (microsteps), `State`, flags              | 8   |
+//!
+//! Status flags: bit 0 homed, bit 1 driver enabled.
+//!
+//! The axis decodes `Request`s and encodes `Response`s, a controller does the reverse with
+//! the same types. The axis side of the state machine is the `stepper-remote` crate.
+
+#![no_std]
+#![deny(missing_debug_implementations)]
+#![deny(unsafe_code)]
+
+use embedded_hal::can::{Frame, Id, StandardId};
+
+/// Identifier of the commands to node 0
+pub const COMMAND_BASE: u16 = 0x200;
+/// Identifier of the replies of node 0
+pub const REPLY_BASE: u16 = 0x240;
+/// Highest node ID
+pub const MAX_NODE: u8 = 0x3f;
+/// Identifier bits above the node ID
+const BASE_MASK: u16 = 0x7c0;
+
+const FLAG_HOMED: u8 = 0x01;
+const FLAG_ENABLED: u8 = 0x02;
+
+/// Errors
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// Node IDs are 0-63
+    InvalidNode(u8),
+    /// Extended or remote frame, or an identifier of another protocol
+    OtherProtocol,
+    /// Opcode is not defined
+    UnknownOpcode(u8),
+    /// Payload length does not match the opcode
+    Length(usize),
+    /// Field value is not defined
+    InvalidValue,
+    /// The frame type rejected the frame
+    Frame,
+}
+
+/// Commands to the axis. Positions are in microsteps (STEP pulses), speeds and accelerations
+/// in full steps like the `stepper-driver` API, so they mean the same in any microstep mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    /// Move to the absolute position `target`
+    MoveTo { target: i32 },
+    /// Speed of the following moves
+    SetSpeed { steps_per_s: u32 },
+    /// Acceleration of the following moves
+    SetAcceleration { steps_per_s2: u32 },
+    /// Find the home switch and zero the position there, acknowledged when done
+    Home,
+    /// Ramp down to a standstill
+    Stop,
+    /// Reply with the status
+    Query,
+}
+
+impl Command {
+    pub fn opcode(&self) -> u8 {
+        match self {
+            Command::MoveTo { .. } => 0x01,
+            Command::SetSpeed { .. } => 0x02,
+            Command::SetAcceleration { .. } => 0x03,
+            Command::Home => 0x04,
+            Command::Stop => 0x05,
+            Command::Query => 0x06,
+        }
+    }
+}
+
+/// Reasons a command is refused, sent as the result of its acknowledgement.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reject {
+    /// Wrong length or field value
+    Malformed = 1,
+    /// Opcode is not defined
+    UnknownCommand = 2,
+    /// Only stop and query are accepted while the axis moves
+    Busy = 3,
+    /// Target outside the travel limits
+    OutOfRange = 4,
+    /// Speed is zero or above what the driver can step
+    InvalidSpeed = 5,
+    /// Acceleration is zero, or not set before the first move
+    InvalidAcceleration = 6,
+    /// The axis has to be homed before moving
+    NotHomed = 7,
+    /// The driver reports a fault
+    Fault = 8,
+    /// The home switch was not found
+    HomingFailed = 9,
+}
+
+impl Reject {
+    fn from_code(code: u8) -> Option<Self> {
+        Some(match code {
+            1 => Reject::Malformed,
+            2 => Reject::UnknownCommand,
+            3 => Reject::Busy,
+            4 => Reject::OutOfRange,
+            5 => Reject::InvalidSpeed,
+            6 => Reject::InvalidAcceleration,
+            7 => Reject::NotHomed,
+            8 => Reject::Fault,
+            9 => Reject::HomingFailed,
+            _ => return None,
+        })
+    }
+}
+
+impl From<Error> for Reject {
+    fn from(error: Error) -> Self {
+        match error {
+            Error::UnknownOpcode(_) => Reject::UnknownCommand,
+            _ => Reject::Malformed,
+        }
+    }
+}
+
+/// What the axis is doing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Idle = 0,
+    Moving = 1,
+    /// Ramping down after a stop command
+    Stopping = 2,
+    /// The driver reports a fault or the last move failed
+    Fault = 3,
+}
+
+impl State {
+    fn from_code(code: u8) -> Option<Self> {
+        Some(match code {
+            0 => State::Idle,
+            1 => State::Moving,
+            2 => State::Stopping,
+            3 => State::Fault,
+            _ => return None,
+        })
+    }
+}
+
+/// Status of the axis.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Status {
+    /// Position (microsteps)
+    pub position: i32,
+    pub state: State,
+    pub homed: bool,
+    pub enabled: bool,
+}
+
+/// Replies of the axis.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reply {
+    /// Acknowledgement of the command with `opcode`
+    Ack { opcode: u8, result: Result<(), Reject> },
+    Status(Status),
+}
+
+/// A command to one axis.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Request {
+    /// Node ID of the axis (0-63)
+    pub node: u8,
+    /// Chosen by the controller, returned in the reply
+    pub tag: u8,
+    pub command: Command,
+}
+
+/// A reply of one axis.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Response {
+    /// Node ID of the axis (0-63)
+    pub node: u8,
+    /// Tag of the command answered
+    pub tag: u8,
+    pub reply: Reply,
+}
+
+fn id(base: u16, node: u8) -> Result<u16, Error> {
+    if node > MAX_NODE {
+        return Err(Error::InvalidNode(node));
+    }
+    Ok(base | u16::from(node))
+}
+
+/// Node ID of a standard data frame with an identifier of `base`.
+fn node<F: Frame>(base: u16, frame: &F) -> Option<u8> {
+    match frame.id() {
+        Id::Standard(id) if frame.is_data_frame() && id.as_raw() & BASE_MASK == base => {
+            Some((id.as_raw() & u16::from(MAX_NODE)) as u8)
+        }
+        _ => None,
+    }
+}
+
+fn to_frame<F: Frame>(id: u16, data: &[u8]) -> Result<F, Error> {
+    let id = StandardId::new(id).ok_or(Error::OtherProtocol)?;
+    F::new(id, data).ok_or(Error::Frame)
+}
+
+fn check_length(data: &[u8], expected: usize) -> Result<(), Error> {
+    if data.len() != expected {
+        return Err(Error::Length(data.len()));
+    }
+    Ok(())
+}
+
+fn u32_at(data: &[u8], index: usize) -> u32 {
+    u32::from_le_bytes([data[index], data[index + 1], data[index + 2], data[index + 3]])
+}
+
+/// Node ID `frame` is addressed to if it is a command, even a malformed one.
+pub fn command_node<F: Frame>(frame: &F) -> Option<u8> {
+    node(COMMAND_BASE, frame)
+}
+
+impl Request {
+    /// Standard identifier of the frame.
+    pub fn id(&self) -> Result<u16, Error> {
+        id(COMMAND_BASE, self.node)
+    }
+
+    /// Writes the payload into `data` and returns its length.
+    pub fn encode(&self, data: &mut [u8; 8]) -> usize {
+        data[0] = self.command.opcode();
+        data[1] = self.tag;
+        let argument = match self.command {
+            Command::MoveTo { target } => target.to_le_bytes(),
+            Command::SetSpeed { steps_per_s } => steps_per_s.to_le_bytes(),
+            Command::SetAcceleration { steps_per_s2 } => steps_per_s2.to_le_bytes(),
+            Command::Home | Command::Stop | Command::Query => return 2,
+        };
+        data[2..6].copy_from_slice(&argument);
+        6
+    }
+
+    /// Decodes the payload `data` of a command to `node`.
+    pub fn decode(node: u8, data: &[u8]) -> Result<Self, Error> {
+        let opcode = *data.first().ok_or(Error::Length(0))?;
+        let command = match opcode {
+            0x01..=0x03 => {
+                check_length(data, 6)?;
+                let argument = u32_at(data, 2);
+                match opcode {
+                    0x01 => Command::MoveTo { target: argument as i32 },
+                    0x02 => Command::SetSpeed { steps_per_s: argument },
+                    _ => Command::SetAcceleration { steps_per_s2: argument },
+                }
+            }
+            0x04..=0x06 => {
+                check_length(data, 2)?;
+                match opcode {
+                    0x04 => Command::Home,
+                    0x05 => Command::Stop,
+                    _ => Command::Query,
+                }
+            }
+            _ => return Err(Error::UnknownOpcode(opcode)),
+        };
+        Ok(Request { node, tag: data[1], command })
+    }
+
+    /// Builds the CAN frame.
+    pub fn to_frame<F: Frame>(&self) -> Result<F, Error> {
+        let mut data = [0; 8];
+        let len = self.encode(&mut data);
+        to_frame(self.id()?, &data[..len])
+    }
+
+    /// Decodes a received CAN frame.
+    pub fn from_frame<F: Frame>(frame: &F) -> Result<Self, Error> {
+        let node = command_node(frame).ok_or(Error::OtherProtocol)?;
+        Self::decode(node, frame.data())
+    }
+}
+
+impl Response {
+    /// Refusal of the malformed command `data` to `node`, which failed to decode with `error`.
+    pub fn malformed(node: u8, data: &[u8], error: Error) -> Self {
+        Response {
+            node,
+            tag: data.get(1).copied().unwrap_or(0),
+            reply: Reply::Ack { opcode: data.first().copied().unwrap_or(0), result: Err(error.into()) },
+        }
+    }
+
+    /// Standard identifier of the frame.
+    pub fn id(&self) -> Result<u16, Error> {
+        id(REPLY_BASE, self.node)
+    }
+
+    /// Writes the payload into `data` and returns its length.
+    pub fn encode(&self, data: &mut [u8; 8]) -> usize {
+        data[1] = self.tag;
+        match self.reply {
+            Reply::Ack { opcode, result } => {
+                data[0] = 0x80;
+                data[2] = opcode;
+                data[3] = match result {
+                    Ok(()) => 0,
+                    Err(reject) => reject as u8,
+                };
+                4
+            }
+            Reply::Status(status) => {
+                data[0] = 0x81;
+                data[2..6].copy_from_slice(&status.position.to_le_bytes());
+                data[6] = status.state as u8;
+                data[7] = if status.homed { FLAG_HOMED } else { 0 }
+                    | if status.enabled { FLAG_ENABLED } else { 0 };
+                8
+            }
+        }
+    }
+
+    /// Decodes the payload `data` of a reply of `node`.
+    pub fn decode(node: u8, data: &[u8]) -> Result<Self, Error> {
+        let opcode = *data.first().ok_or(Error::Length(0))?;
+        let reply = match opcode {
+            0x80 => {
+                check_length(data, 4)?;
+                let result = match data[3] {
+                    0 => Ok(()),
+                    code => Err(Reject::from_code(code).ok_or(Error::InvalidValue)?),
+                };
+                Reply::Ack { opcode: data[2], result }
+            }
+            0x81 => {
+                check_length(data, 8)?;
+                Reply::Status(Status {
+                    position: u32_at(data, 2) as i32,
+                    state: State::from_code(data[6]).ok_or(Error::InvalidValue)?,
+                    homed: data[7] & FLAG_HOMED != 0,
+                    enabled: data[7] & FLAG_ENABLED != 0,
+                })
+            }
+            _ => return Err(Error::UnknownOpcode(opcode)),
+        };
+        Ok(Response { node, tag: data[1], reply })
+    }
+
+    /// Builds the CAN frame.
+    pub fn to_frame<F: Frame>(&self) -> Result<F, Error> {
+        let mut data = [0; 8];
+        let len = self.encode(&mut data);
+        to_frame(self.id()?, &data[..len])
+    }
+
+    /// Decodes a received CAN frame.
+    pub fn from_frame<F: Frame>(frame: &F) -> Result<Self, Error> {
+        let node = node(REPLY_BASE, frame).ok_or(Error::OtherProtocol)?;
+        Self::decode(node, frame.data())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use can_test_frame::TestFrame;
+    use embedded_hal::can::ExtendedId;
+
+    fn standard(id: u16) -> Id {
+        Id::Standard(StandardId::new(id).unwrap())
+    }
+
+    fn frame(id: u16, data: &[u8]) -> TestFrame {
+        TestFrame::new(StandardId::new(id).unwrap(), data).unwrap()
+    }
+
+    #[test]
+    fn encodes_commands() {
+        let request = Request { node: 3, tag: 9, command: Command::MoveTo { target: -1600 } };
+        let frame: TestFrame = request.to_frame().unwrap();
+        assert_eq!(frame.id(), standard(0x203));
+        assert_eq!(frame.data(), [0x01, 9, 0xc0, 0xf9, 0xff, 0xff]);
+        assert_eq!(Request::from_frame(&frame), Ok(request));
+
+        for command in [
+            Command::SetSpeed { steps_per_s: 400 },
+            Command::SetAcceleration { steps_per_s2: 2000 },
+            Command::Home,
+            Command::Stop,
+            Command::Query,
+        ] {
+            let request = Request { node: 63, tag: 1, command };
+            let frame: TestFrame = request.to_frame().unwrap();
+            assert_eq!(frame.id(), standard(0x23f));
+            assert_eq!(Request::from_frame(&frame), Ok(request));
+        }
+    }
+
+    #[test]
+    fn encodes_replies() {
+        let status = Status { position: 3200, state: State::Moving, homed: true, enabled: true };
+        let response = Response { node: 3, tag: 9, reply: Reply::Status(status) };
+        let frame: TestFrame = response.to_frame().unwrap();
+        assert_eq!(frame.id(), standard(0x243));
+        assert_eq!(frame.data(), [0x81, 9, 0x80, 0x0c, 0, 0, 1, 3]);
+        assert_eq!(Response::from_frame(&frame), Ok(response));
+
+        let response = Response { node: 3, tag: 10, reply: Reply::Ack { opcode: 0x01, result: Err(Reject::Busy) } };
+        let frame: TestFrame = response.to_frame().unwrap();
+        assert_eq!(frame.data(), [0x80, 10, 0x01, 3]);
+        assert_eq!(Response::from_frame(&frame), Ok(response));
+
+        let response = Response { node: 0, tag: 0, reply: Reply::Ack { opcode: 0x05, result: Ok(()) } };
+        let frame: TestFrame = response.to_frame().unwrap();
+        assert_eq!(Response::from_frame(&frame), Ok(response));
+    }
+
+    #[test]
+    fn rejects_malformed_commands() {
+        assert_eq!(Request::decode(1, &[]), Err(Error::Length(0)));
+        assert_eq!(Request::decode(1, &[0x01, 0, 1, 2]), Err(Error::Length(4)));
+        assert_eq!(Request::decode(1, &[0x05, 0, 0]), Err(Error::Length(3)));
+        assert_eq!(Request::decode(1, &[0x42, 7]), Err(Error::UnknownOpcode(0x42)));
+        assert_eq!(Response::decode(1, &[0x80, 0, 1, 200]), Err(Error::InvalidValue));
+        assert_eq!(Response::decode(1, &[0x81, 0, 0, 0, 0, 0, 9, 0]), Err(Error::InvalidValue));
+
+        let response = Response::malformed(1, &[0x42, 7], Error::UnknownOpcode(0x42));
+        let ack = Reply::Ack { opcode: 0x42, result: Err(Reject::UnknownCommand) };
+        assert_eq!(response, Response { node: 1, tag: 7, reply: ack });
+        let response = Response::malformed(1, &[0x01, 8, 0], Error::Length(3));
+        assert_eq!(response.reply, Reply::Ack { opcode: 0x01, result: Err(Reject::Malformed) });
+
+        let request = Request { node: 64, tag: 0, command: Command::Stop };
+        assert_eq!(request.to_frame::<TestFrame>(), Err(Error::InvalidNode(64)));
+    }
+
+    #[test]
+    fn filters_other_frames() {
+        // Replies, telemetry, remote and extended frames are not commands
+        assert_eq!(command_node(&frame(0x205, &[0x06, 0])), Some(5));
+        assert_eq!(command_node(&frame(0x245, &[0x06, 0])), None);
+        assert_eq!(command_node(&frame(0x405, &[0x06, 0])), None);
+        assert_eq!(command_node(&TestFrame::new_remote(StandardId::new(0x205).unwrap(), 2).unwrap()), None);
+        assert_eq!(command_node(&TestFrame::new(ExtendedId::new(0x205).unwrap(), &[0x06, 0]).unwrap()), None);
+        assert_eq!(Request::from_frame(&frame(0x245, &[0x06, 0])), Err(Error::OtherProtocol));
+        assert_eq!(Response::from_frame(&frame(0x205, &[0x80, 0, 0, 0])), Err(Error::OtherProtocol));
+    }
+}
@@ -0,0 +1,316 @@
+//! Coordinated linear moves of several axes.
+//!
+//! The axis with the longest distance (the dominant axis) follows the acceleration profile,
+//! the others are stepped in proportion by an N-dimensional Bresenham (DDA), so all axes start
+//! and arrive together and the path is a straight line in step space.
+
+use core::convert::TryFrom;
+use embedded_hal::blocking::delay::DelayUs;
+use embedded_hal::digital::v2::OutputPin;
+
+use {ControlPins, Error, ModePins, MotorDriver, Params, StepGenerator, TrapezoidalProfile};
+
+/// Axis that can take part in a coordinated move, implemented by `MotorDriver`.
+pub trait Axis {
+    type Error;
+
+    /// Fails with `Error::OutOfRange` if moving by `delta` steps would leave the travel
+    /// limits.
+    fn check_move(&self, delta: i64) -> Result<(), Error<Self::Error>>;
+
+    /// Enables the driver and sets the direction, see `MotorDriver::set_direction`. Fails
+    /// without enabling on a fault or an emergency stop.
+    fn prepare(&mut self, clock_work: bool) -> Result<(), Error<Self::Error>>;
+
+    /// Rising STEP edge, the step is counted. Fails instead on a fault or an emergency stop.
+    fn step_high(&mut self) -> Result<(), Error<Self::Error>>;
+
+    fn step_low(&mut self) -> Result<(), Error<Self::Error>>;
+
+    /// STEP high/low min time (microseconds)
+    fn step_min_time(&self) -> u32;
+}
+
+impl<G, CHIP, EN, MS, CTL> Axis for MotorDriver<G, CHIP, EN, MS, CTL>
+where
+    G: StepGenerator,
+    CHIP: Params,
+    EN: OutputPin<Error = G::Error>,
+    MS: ModePins<Error = G::Error>,
+    CTL: ControlPins<Error = G::Error>,
+{
+    type Error = G::Error;
+
+    fn check_move(&self, delta: i64) -> Result<(), Error<G::Error>> {
+        self.check_target(self.position.saturating_add(delta))
+    }
+
+    fn prepare(&mut self, clock_work: bool) -> Result<(), Error<G::Error>> {
+        self.energize()?;
+        self.set_direction(clock_work)?;
+        if self.settle_pending {
+            self.generator.delay_us(CHIP::ENABLE_SETTLE_TIME);
+            self.settle_pending = false;
+        }
+        Ok(())
+    }
+
+    fn step_high(&mut self) -> Result<(), Error<G::Error>> {
+        self.check_stop()?;
+        self.generator.set_step(true)?;
+        self.count_step();
+        self.idle_time = 0;
+        Ok(())
+    }
+
+    fn step_low(&mut self) -> Result<(), Error<G::Error>> {
+        self.generator.set_step(false)?;
+        Ok(())
+    }
+
+    fn step_min_time(&self) -> u32 {
+        CHIP::STEP_MIN_TIME
+    }
+}
+
+/// Steps of one tick of a coordinated move.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StepEvent<const N: usize> {
+    /// Time to the next event (microseconds)
+    pub interval: u32,
+    /// Axes stepping at this tick, the dominant axis always does
+    pub steps: [bool; N],
+}
+
+/// Linear move of `N` axes, an iterator of `StepEvent`s.
+///
+/// `P` yields the step intervals of the dominant axis, one per step of it.
+#[derive(Debug, Clone)]
+pub struct LinearMove<P, const N: usize> {
+    deltas: [i64; N],
+    /// steps of the dominant axis
+    dominant: u64,
+    remaining: u64,
+    errors: [u64; N],
+    profile: P,
+}
+
+impl<const N: usize> LinearMove<TrapezoidalProfile, N> {
+    /// Moves every axis by its delta (microsteps) with a constant acceleration profile,
+    /// `max_speed` (microsteps/s) and `acceleration` (microsteps/s²) apply to the dominant
+    /// axis.
+    ///
+    /// Fails like `dominant_steps`.
+    pub fn trapezoidal<E>(deltas: [i64; N], max_speed: f32, acceleration: f32) -> Result<Self, Error<E>> {
+        let steps = Self::dominant_steps(&deltas)?;
+        Ok(LinearMove::new(deltas, TrapezoidalProfile::new(steps, max_speed, acceleration)))
+    }
+}
+
+impl<P, const N: usize> LinearMove<P, N>
+where
+    P: Iterator<Item = u32>,
+{
+    /// Moves every axis by its delta (microsteps), timed by `profile`.
+    ///
+    /// `profile` should be planned for `dominant_steps(&deltas)` steps, the move ends early
+    /// if it yields fewer.
+    pub fn new(deltas: [i64; N], profile: P) -> Self {
+        let dominant = deltas.iter().map(|d| d.unsigned_abs()).max().unwrap_or(0);
+        // Start half way so the rounding is spread evenly over the move
+        LinearMove { deltas, dominant, remaining: dominant, errors: [dominant / 2; N], profile }
+    }
+
+    /// Number of steps of the longest axis, which the profile has to cover.
+    ///
+    /// Fails with `Error::InvalidProfile` if they do not fit a profile.
+    pub fn dominant_steps<E>(deltas: &[i64; N]) -> Result<u32, Error<E>> {
+        let steps = deltas.iter().map(|d| d.unsigned_abs()).max().unwrap_or(0);
+        u32::try_from(steps).map_err(|_| Error::InvalidProfile)
+    }
+
+    /// Direction of each axis, `true` is clockwise.
+    pub fn directions(&self) -> [bool; N] {
+        let mut directions = [false; N];
+        for (direction, &delta) in directions.iter_mut().zip(self.deltas.iter()) {
+            *direction = delta > 0;
+        }
+        directions
+    }
+
+    /// Runs the move on `axes`, timed with `delay`.
+    ///
+    /// Stepping axes get their STEP pulses together, the pulse lasts the longest
+    /// `step_min_time` among them. Fails with `Error::OutOfRange` without moving if an axis
+    /// would leave its travel limits.
+    pub fn run<D, E>(self, delay: &mut D, axes: &mut [&mut dyn Axis<Error = E>; N]) -> Result<(), Error<E>>
+    where
+        D: DelayUs<u32>,
+    {
+        for (axis, &delta) in axes.iter().zip(self.deltas.iter()) {
+            axis.check_move(delta)?;
+        }
+        // Idle axes keep their direction and enable state, so no backlash is taken up
+        for ((axis, clock_work), &delta) in axes.iter_mut().zip(self.directions().iter()).zip(self.deltas.iter()) {
+            if delta != 0 {
+                axis.prepare(*clock_work)?;
+            }
+        }
+        let min_time = axes.iter().map(|axis| axis.step_min_time()).max().unwrap_or(0);
+
+        for event in self {
+            for (axis, &step) in axes.iter_mut().zip(event.steps.iter()) {
+                if step {
+                    axis.step_high()?;
+                }
+            }
+            delay.delay_us(min_time);
+            for (axis, &step) in axes.iter_mut().zip(event.steps.iter()) {
+                if step {
+                    axis.step_low()?;
+                }
+            }
+            let rest = if event.interval > min_time { event.interval - min_time } else { min_time };
+            delay.delay_us(rest);
+        }
+        Ok(())
+    }
+}
+
+impl<P, const N: usize> Iterator for LinearMove<P, N>
+where
+    P: Iterator<Item = u32>,
+{
+    type Item = StepEvent<N>;
+
+    fn next(&mut self) -> Option<StepEvent<N>> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let interval = self.profile.next()?;
+        self.remaining -= 1;
+
+        let dominant = self.dominant;
+        let mut steps = [false; N];
+        for ((error, delta), step) in self.errors.iter_mut().zip(self.deltas.iter()).zip(steps.iter_mut()) {
+            *error += delta.unsigned_abs();
+            if *error >= dominant {
+                *error -= dominant;
+                *step = true;
+            }
+        }
+        Some(StepEvent { interval, steps })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::convert::Infallible;
+    use std::vec::Vec;
+    use tests::{driver, MockPin, NoDelay};
+
+    fn linear_move<const N: usize>(deltas: [i64; N]) -> LinearMove<TrapezoidalProfile, N> {
+        LinearMove::trapezoidal::<Infallible>(deltas, 1000.0, 2000.0).unwrap()
+    }
+
+    fn events<const N: usize>(deltas: [i64; N]) -> Vec<StepEvent<N>> {
+        linear_move(deltas).collect()
+    }
+
+    #[test]
+    fn axes_arrive_together() {
+        let deltas = [1000, -333, 7, 0];
+        let events = events(deltas);
+        assert_eq!(events.len(), 1000);
+
+        let mut counts = [0i64; 4];
+        for (k, event) in events.iter().enumerate() {
+            assert!(event.steps[0], "dominant axis skipped tick {}", k);
+            for i in 0..4 {
+                if event.steps[i] {
+                    counts[i] += 1;
+                }
+                // Never more than one step off the straight line
+                let ideal = (k + 1) as f64 * deltas[i].abs() as f64 / 1000.0;
+                assert!((counts[i] as f64 - ideal).abs() <= 1.0, "axis {} at tick {}", i, k);
+            }
+        }
+        assert_eq!(counts, [1000, 333, 7, 0]);
+    }
+
+    #[test]
+    fn minor_steps_are_spread_evenly() {
+        let ticks: Vec<usize> = events([10, 3])
+            .iter()
+            .enumerate()
+            .filter(|&(_, event)| event.steps[1])
+            .map(|(k, _)| k)
+            .collect();
+        assert_eq!(ticks, vec![1, 4, 8]);
+    }
+
+    #[test]
+    fn follows_the_profile_of_the_dominant_axis() {
+        let intervals: Vec<u32> = events([-200, 150]).iter().map(|e| e.interval).collect();
+        let profile: Vec<u32> = TrapezoidalProfile::new(200, 1000.0, 2000.0).collect();
+        assert_eq!(intervals, profile);
+        assert_eq!(linear_move([-200, 150]).directions(), [false, true]);
+    }
+
+    #[test]
+    fn drives_motors_to_target() {
+        let (mut pan, _, pan_step) = driver();
+        let (mut tilt, tilt_dir, tilt_step) = driver();
+        pan.set_position(50);
+
+        linear_move([120, -45]).run(&mut NoDelay, &mut [&mut pan, &mut tilt]).unwrap();
+        assert_eq!(pan.position(), 170);
+        assert_eq!(tilt.position(), -45);
+        assert_eq!(pan_step.pulses(), 120);
+        assert_eq!(tilt_step.pulses(), 45);
+        assert!(tilt_dir.is_high());
+    }
+
+    #[test]
+    fn leaves_idle_axes_alone() {
+        let (mut pan, _, pan_step) = driver();
+        let (tilt, _, tilt_step) = driver();
+        let mut tilt = tilt.with_enable_pin(MockPin::default()).unwrap();
+        tilt.set_backlash(10);
+        tilt.move_to(5).unwrap();
+        tilt.disable().unwrap();
+
+        linear_move([120, 0]).run(&mut NoDelay, &mut [&mut pan, &mut tilt]).unwrap();
+        assert_eq!(pan_step.pulses(), 120);
+        assert_eq!(tilt_step.pulses(), 5);
+        assert_eq!(tilt.position(), 5);
+        assert!(!tilt.is_enabled());
+    }
+
+    #[test]
+    fn checks_every_axis_before_moving() {
+        let (mut pan, _, pan_step) = driver();
+        let (mut tilt, _, tilt_step) = driver();
+        tilt.set_limits(Some((-40, 40)));
+
+        let result = linear_move([120, -45]).run(&mut NoDelay, &mut [&mut pan, &mut tilt]);
+        assert_eq!(result, Err(Error::OutOfRange(-45)));
+        assert_eq!(pan_step.pulses(), 0);
+        assert_eq!(tilt_step.pulses(), 0);
+    }
+
+    #[test]
+    fn rejects_moves_too_long_for_a_profile() {
+        type Pair = LinearMove<TrapezoidalProfile, 2>;
+        let deltas = [1 << 32, 5];
+        assert_eq!(Pair::dominant_steps::<Infallible>(&deltas), Err(Error::InvalidProfile));
+        assert_eq!(Pair::trapezoidal::<Infallible>(deltas, 1.0, 1.0).err(), Some(Error::InvalidProfile));
+        assert_eq!(Pair::dominant_steps::<Infallible>(&[-(1 << 32) + 1, 5]), Ok(u32::MAX));
+    }
+
+    #[test]
+    fn empty_move() {
+        assert!(events([0, 0]).is_empty());
+    }
+}
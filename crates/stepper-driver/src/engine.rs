@@ -0,0 +1,516 @@
+//! Interrupt driven stepping.
+//!
+//! `StepEngine` runs a move in the background: a hardware timer fires an interrupt for every
+//! STEP edge and the handler calls `StepEngine::on_interrupt`, which toggles the pin and
+//! programs the next alarm. The interval of the following step is taken from the profile
+//! while the STEP pulse is high, so the timing never waits for the profile math.
+//!
+//! The engine is usually kept in a `Mutex<RefCell<Option<StepEngine<..>>>>` shared between
+//! the application and the interrupt handler.
+//!
+//! Like `MotorDriver`, it keeps moves within the software travel limits and checks the
+//! emergency stop and the nFAULT line of the control pins before every step.
+
+use core::convert::TryFrom;
+use core::marker::PhantomData;
+use embedded_hal::digital::v2::OutputPin;
+
+use {ControlPins, EmergencyStop, Error, NoPin, Params, TrapezoidalProfile};
+
+/// One-shot hardware timer driving a `StepEngine`.
+pub trait StepTimer {
+    /// Fires the interrupt `us` microseconds from now.
+    fn start(&mut self, us: u32);
+
+    /// Fires the interrupt `us` microseconds after the previous one, so interrupt latency
+    /// does not add up over a move. Also acknowledges the pending interrupt.
+    fn schedule(&mut self, us: u32);
+
+    /// Stops the timer and acknowledges the pending interrupt.
+    fn cancel(&mut self);
+}
+
+/// Background stepper, see the module documentation.
+///
+/// `P` is the profile type of the moves, any iterator of step intervals (microseconds) like
+/// `TrapezoidalProfile` or `SCurveProfile`.
+#[derive(Debug)]
+pub struct StepEngine<DIR, STEP, CHIP, T, P = TrapezoidalProfile, CTL = NoPin<<STEP as OutputPin>::Error>>
+where
+    DIR: OutputPin<Error = STEP::Error>,
+    STEP: OutputPin,
+    CHIP: Params,
+    T: StepTimer,
+    P: Iterator<Item = u32>,
+    CTL: ControlPins<Error = STEP::Error>,
+{
+    dir_pin: DIR,
+    step_pin: STEP,
+    timer: T,
+    control_pins: CTL,
+    _chip: PhantomData<CHIP>,
+    /// checked before every step, ends the move once triggered
+    stop_request: Option<&'static EmergencyStop>,
+
+    /// remaining steps of the running move
+    profile: Option<P>,
+    /// interval of the step started by the next rising edge
+    next: Option<u32>,
+    /// interval of the step in progress
+    interval: u32,
+    /// STEP is high, the next interrupt ends the pulse
+    pulse_high: bool,
+
+    position: i64,
+    clockwise: bool,
+    /// software travel limits (microsteps, inclusive)
+    limits: Option<(i64, i64)>,
+    on_complete: Option<fn()>,
+}
+
+impl<DIR, STEP, CHIP, T, P> StepEngine<DIR, STEP, CHIP, T, P>
+where
+    DIR: OutputPin<Error = STEP::Error>,
+    STEP: OutputPin,
+    CHIP: Params,
+    T: StepTimer,
+    P: Iterator<Item = u32>,
+{
+    pub fn new(dir_pin: DIR, mut step_pin: STEP, timer: T) -> Result<Self, Error<STEP::Error>> {
+        step_pin.set_low()?;
+        Ok(StepEngine {
+            dir_pin,
+            step_pin,
+            timer,
+            control_pins: NoPin::new(),
+            _chip: PhantomData,
+            stop_request: None,
+            profile: None,
+            next: None,
+            interval: 0,
+            pulse_high: false,
+            position: 0,
+            clockwise: false,
+            limits: None,
+            on_complete: None,
+        })
+    }
+}
+
+impl<DIR, STEP, CHIP, T, P, CTL> StepEngine<DIR, STEP, CHIP, T, P, CTL>
+where
+    DIR: OutputPin<Error = STEP::Error>,
+    STEP: OutputPin,
+    CHIP: Params,
+    T: StepTimer,
+    P: Iterator<Item = u32>,
+    CTL: ControlPins<Error = STEP::Error>,
+{
+    /// Attaches the nFAULT, nSLEEP and nRESET lines, releases reset and wakes the driver.
+    ///
+    /// Moves then stop with `Error::Fault` as soon as nFAULT is active.
+    #[allow(unknown_lints, clippy::type_complexity)]
+    pub fn with_control_pins<PINS>(self, mut control_pins: PINS)
+        -> Result<StepEngine<DIR, STEP, CHIP, T, P, PINS>, Error<STEP::Error>>
+    where
+        PINS: ControlPins<Error = STEP::Error>,
+    {
+        control_pins.set_reset(false)?;
+        control_pins.set_sleep(false)?;
+        Ok(StepEngine {
+            dir_pin: self.dir_pin,
+            step_pin: self.step_pin,
+            timer: self.timer,
+            control_pins,
+            _chip: PhantomData,
+            stop_request: self.stop_request,
+            profile: self.profile,
+            next: self.next,
+            interval: self.interval,
+            pulse_high: self.pulse_high,
+            position: self.position,
+            clockwise: self.clockwise,
+            limits: self.limits,
+            on_complete: self.on_complete,
+        })
+    }
+
+    /// Checks `stop` before every step, `None` detaches it.
+    pub fn set_emergency_stop(&mut self, stop: Option<&'static EmergencyStop>) {
+        self.stop_request = stop;
+    }
+
+    /// Returns true while the driver reports a fault on nFAULT.
+    pub fn is_faulted(&self) -> Result<bool, Error<STEP::Error>> {
+        Ok(self.control_pins.is_faulted()?)
+    }
+
+    /// Restricts moves to positions within `min..=max` microsteps. `None` removes the limits.
+    pub fn set_limits(&mut self, limits: Option<(i64, i64)>) {
+        self.limits = limits;
+    }
+
+    /// Returns the software travel limits.
+    pub fn limits(&self) -> Option<(i64, i64)> {
+        self.limits
+    }
+
+    /// Calls `callback` from the interrupt handler whenever a move completes or is stopped.
+    pub fn set_on_complete(&mut self, callback: Option<fn()>) {
+        self.on_complete = callback;
+    }
+
+    /// Starts stepping through `profile`, see `MotorDriver::set_direction` for `clock_work`.
+    ///
+    /// A move already running is replaced without deceleration. Fails without moving with
+    /// `Error::EmergencyStop` or `Error::Fault` while either is active, and with
+    /// `Error::OutOfRange` if the profile has a known length that ends outside the limits.
+    /// Profiles of unknown length are stopped at the limit by `on_interrupt`.
+    pub fn start_move(&mut self, profile: P, clock_work: bool) -> Result<(), Error<STEP::Error>> {
+        self.check_stop()?;
+        if let (steps, Some(upper)) = profile.size_hint() {
+            if steps == upper {
+                let steps = i64::try_from(steps).unwrap_or(i64::MAX);
+                let delta = if clock_work { steps } else { -steps };
+                self.check_limits(self.position.saturating_add(delta))?;
+            }
+        }
+
+        self.timer.cancel();
+        if self.pulse_high {
+            self.step_pin.set_low()?;
+            self.pulse_high = false;
+        }
+
+        let mut profile = profile;
+        self.next = profile.next();
+        if self.next.is_none() {
+            self.profile = None;
+            return Ok(());
+        }
+        if clock_work {
+            self.dir_pin.set_low()?;
+        } else {
+            self.dir_pin.set_high()?;
+        }
+        self.clockwise = clock_work;
+        self.profile = Some(profile);
+        // DIR setup time before the first STEP edge
+        self.timer.start(CHIP::STEP_MIN_TIME);
+        Ok(())
+    }
+
+    /// True while a move is running.
+    pub fn is_moving(&self) -> bool {
+        self.profile.is_some()
+    }
+
+    /// Stops immediately, without deceleration.
+    ///
+    /// A pulse in progress is ended, so the position stays exact, but at speed the motor
+    /// can lose steps against its inertia.
+    pub fn stop(&mut self) -> Result<(), Error<STEP::Error>> {
+        self.timer.cancel();
+        if self.pulse_high {
+            self.step_pin.set_low()?;
+            self.pulse_high = false;
+        }
+        if self.profile.take().is_some() {
+            self.complete();
+        }
+        Ok(())
+    }
+
+    /// Current position in microsteps (STEP pulses), counting up clockwise.
+    pub fn position(&self) -> i64 {
+        self.position
+    }
+
+    /// Declares the current position to be `position` microsteps, without moving.
+    pub fn set_position(&mut self, position: i64) {
+        self.position = position;
+    }
+
+    /// Advances the move, call it from the timer interrupt handler.
+    ///
+    /// Before every step it checks the emergency stop, nFAULT and the limits. If one of them
+    /// fails, the move ends like with `stop` and the error is returned.
+    pub fn on_interrupt(&mut self) -> Result<(), Error<STEP::Error>> {
+        if self.profile.is_none() {
+            self.timer.cancel();
+            return Ok(());
+        }
+
+        if self.pulse_high {
+            self.step_pin.set_low()?;
+            self.pulse_high = false;
+            if self.next.is_some() {
+                // Wait the rest of the interval but at least the minimal low time
+                let rest = if self.interval > 2 * CHIP::STEP_MIN_TIME {
+                    self.interval - CHIP::STEP_MIN_TIME
+                } else {
+                    CHIP::STEP_MIN_TIME
+                };
+                self.timer.schedule(rest);
+            } else {
+                self.timer.cancel();
+                self.profile = None;
+                self.complete();
+            }
+            return Ok(());
+        }
+
+        if let Some(interval) = self.next {
+            let target = self.position + if self.clockwise { 1 } else { -1 };
+            if let Err(e) = self.check_stop().and_then(|()| self.check_limits(target)) {
+                self.stop()?;
+                return Err(e);
+            }
+            self.step_pin.set_high()?;
+            self.pulse_high = true;
+            self.position = target;
+            self.interval = interval;
+            self.timer.schedule(CHIP::STEP_MIN_TIME);
+            // Computed while the pulse is high
+            self.next = self.profile.as_mut().and_then(|profile| profile.next());
+        }
+        Ok(())
+    }
+
+    /// Releases the pins and the timer, dropping the control pins.
+    pub fn release(self) -> (DIR, STEP, T) {
+        (self.dir_pin, self.step_pin, self.timer)
+    }
+
+    /// Fails if an emergency stop is requested or the driver reports a fault.
+    fn check_stop(&self) -> Result<(), Error<STEP::Error>> {
+        if self.stop_request.is_some_and(EmergencyStop::is_triggered) {
+            return Err(Error::EmergencyStop);
+        }
+        if self.control_pins.is_faulted()? {
+            return Err(Error::Fault);
+        }
+        Ok(())
+    }
+
+    fn check_limits(&self, target: i64) -> Result<(), Error<STEP::Error>> {
+        match self.limits {
+            Some((min, max)) if target < min || target > max => Err(Error::OutOfRange(target)),
+            _ => Ok(()),
+        }
+    }
+
+    fn complete(&self) {
+        if let Some(callback) = self.on_complete {
+            callback();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use a4988;
+    use core::convert::Infallible;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use embedded_hal::digital::v2::InputPin;
+    use std::cell::Cell;
+    use std::rc::Rc;
+    use std::vec::Vec;
+    use tests::MockPin;
+
+    /// Records the alarms (microseconds after the previous one).
+    #[derive(Debug, Default)]
+    struct MockTimer {
+        alarms: Vec<u32>,
+        armed: bool,
+    }
+
+    impl StepTimer for MockTimer {
+        fn start(&mut self, us: u32) {
+            self.alarms.push(us);
+            self.armed = true;
+        }
+
+        fn schedule(&mut self, us: u32) {
+            self.alarms.push(us);
+            self.armed = true;
+        }
+
+        fn cancel(&mut self) {
+            self.armed = false;
+        }
+    }
+
+    type Engine<P> = StepEngine<MockPin, MockPin, a4988, MockTimer, P>;
+
+    fn engine<P: Iterator<Item = u32>>() -> (Engine<P>, MockPin) {
+        let step = MockPin::default();
+        (StepEngine::new(MockPin::default(), step.clone(), MockTimer::default()).unwrap(), step)
+    }
+
+    /// Fires interrupts until the timer is no longer armed, at most `limit`.
+    fn run<P: Iterator<Item = u32>>(engine: &mut Engine<P>, limit: usize) {
+        for _ in 0..limit {
+            if !engine.timer.armed {
+                return;
+            }
+            engine.on_interrupt().unwrap();
+        }
+    }
+
+    #[test]
+    fn runs_profile_in_background() {
+        static COMPLETED: AtomicUsize = AtomicUsize::new(0);
+        fn completed() {
+            COMPLETED.fetch_add(1, Ordering::SeqCst);
+        }
+
+        let (mut engine, step) = engine();
+        engine.set_on_complete(Some(completed));
+        let profile = TrapezoidalProfile::new(100, 1000.0, 2000.0);
+        let total: u32 = profile.clone().sum();
+        engine.start_move(profile, true).unwrap();
+        assert!(engine.is_moving());
+
+        run(&mut engine, 1000);
+        assert!(!engine.is_moving());
+        assert_eq!(step.pulses(), 100);
+        assert!(!step.is_high());
+        assert_eq!(engine.position(), 100);
+        assert_eq!(COMPLETED.load(Ordering::SeqCst), 1);
+
+        // Alarms add up to the profile, except the wait after the last step
+        let scheduled: u32 = engine.timer.alarms.iter().sum();
+        let last = TrapezoidalProfile::new(100, 1000.0, 2000.0).last().unwrap();
+        assert_eq!(scheduled, 1 + total - last + 1);
+    }
+
+    #[test]
+    fn stop_ends_move() {
+        static STOPPED: AtomicUsize = AtomicUsize::new(0);
+        fn stopped() {
+            STOPPED.fetch_add(1, Ordering::SeqCst);
+        }
+
+        let (mut engine, step) = engine();
+        engine.set_on_complete(Some(stopped));
+        engine.start_move(vec![500; 50].into_iter(), false).unwrap();
+        run(&mut engine, 21);
+        engine.stop().unwrap();
+
+        assert!(!engine.is_moving());
+        assert!(!engine.timer.armed);
+        assert!(!step.is_high());
+        // The 11th pulse was high when stopped
+        assert_eq!(step.pulses(), 11);
+        assert_eq!(engine.position(), -11);
+        assert_eq!(STOPPED.load(Ordering::SeqCst), 1);
+
+        engine.stop().unwrap();
+        assert_eq!(STOPPED.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn empty_move_does_nothing() {
+        let (mut engine, step) = engine();
+        engine.start_move(Vec::new().into_iter(), true).unwrap();
+        assert!(!engine.is_moving());
+        assert!(engine.timer.alarms.is_empty());
+        assert_eq!(step.pulses(), 0);
+    }
+
+    #[test]
+    fn rejects_moves_past_the_limits() {
+        let (mut engine, step) = engine();
+        engine.set_limits(Some((-10, 100)));
+        let profile = TrapezoidalProfile::new(101, 1000.0, 2000.0);
+        assert_eq!(engine.start_move(profile, true), Err(Error::OutOfRange(101)));
+        assert!(!engine.is_moving());
+
+        engine.start_move(TrapezoidalProfile::new(100, 1000.0, 2000.0), true).unwrap();
+        run(&mut engine, 1000);
+        assert_eq!(engine.position(), 100);
+        assert_eq!(step.pulses(), 100);
+    }
+
+    #[test]
+    fn stops_endless_profile_at_the_limit() {
+        static STOPPED: AtomicUsize = AtomicUsize::new(0);
+        fn stopped() {
+            STOPPED.fetch_add(1, Ordering::SeqCst);
+        }
+
+        let (mut engine, step) = engine();
+        engine.set_on_complete(Some(stopped));
+        engine.set_limits(Some((-5, 5)));
+        engine.start_move(core::iter::repeat(500), false).unwrap();
+        let result = (0..100).map(|_| engine.on_interrupt()).find(Result::is_err);
+        assert_eq!(result, Some(Err(Error::OutOfRange(-6))));
+        assert!(!engine.is_moving());
+        assert!(!engine.timer.armed);
+        assert_eq!(engine.position(), -5);
+        assert_eq!(step.pulses(), 5);
+        assert_eq!(STOPPED.load(Ordering::SeqCst), 1);
+    }
+
+    /// nFAULT, active while the cell is true.
+    #[derive(Debug, Clone, Default)]
+    struct FaultPin(Rc<Cell<bool>>);
+
+    impl InputPin for FaultPin {
+        type Error = Infallible;
+
+        fn is_high(&self) -> Result<bool, Infallible> {
+            Ok(!self.0.get())
+        }
+
+        fn is_low(&self) -> Result<bool, Infallible> {
+            Ok(self.0.get())
+        }
+    }
+
+    #[test]
+    fn fault_ends_move() {
+        let (engine, step) = engine::<TrapezoidalProfile>();
+        let fault = FaultPin::default();
+        let pins = (fault.clone(), MockPin::default(), MockPin::default());
+        let mut engine = engine.with_control_pins(pins).unwrap();
+
+        engine.start_move(TrapezoidalProfile::new(100, 1000.0, 2000.0), true).unwrap();
+        for _ in 0..20 {
+            engine.on_interrupt().unwrap();
+        }
+        fault.0.set(true);
+        assert_eq!(engine.on_interrupt(), Err(Error::Fault));
+        assert!(!engine.is_moving());
+        assert_eq!(engine.position(), 10);
+        assert_eq!(step.pulses(), 10);
+
+        assert_eq!(engine.is_faulted(), Ok(true));
+        let profile = TrapezoidalProfile::new(10, 1000.0, 2000.0);
+        assert_eq!(engine.start_move(profile, true), Err(Error::Fault));
+    }
+
+    #[test]
+    fn emergency_stop_ends_move() {
+        static STOP: EmergencyStop = EmergencyStop::new();
+        let (mut engine, step) = engine();
+        engine.set_emergency_stop(Some(&STOP));
+        engine.start_move(vec![500; 50].into_iter(), true).unwrap();
+        for _ in 0..6 {
+            engine.on_interrupt().unwrap();
+        }
+
+        STOP.trigger();
+        assert_eq!(engine.on_interrupt(), Err(Error::EmergencyStop));
+        assert!(!engine.is_moving());
+        assert_eq!(step.pulses(), 3);
+        assert_eq!(engine.start_move(vec![500; 5].into_iter(), true), Err(Error::EmergencyStop));
+
+        STOP.clear();
+        engine.start_move(vec![500; 5].into_iter(), true).unwrap();
+        run(&mut engine, 100);
+        assert_eq!(engine.position(), 8);
+    }
+}
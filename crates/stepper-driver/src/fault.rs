@@ -0,0 +1,350 @@
+//! Fault monitoring, emergency stop and the sleep/reset inputs.
+//!
+//! DRV88xx drivers report overcurrent, overtemperature and undervoltage on an open drain
+//! nFAULT output and latch the fault until nRESET is pulsed or the driver sleeps. With the
+//! control pins attached, every step checks nFAULT first and the move is aborted with
+//! `Error::Fault` instead of stepping a motor that does not move.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use embedded_hal::digital::v2::{InputPin, OutputPin};
+
+use {Error, ModePins, MotorDriver, NoPin, Params, StepGenerator};
+
+/// nFAULT, nSLEEP and nRESET lines of a driver.
+///
+/// Implemented for `(nFAULT, nSLEEP, nRESET)` tuples, where `NoPin` stands in for lines that
+/// are not connected, and for `NoPin` alone.
+pub trait ControlPins {
+    type Error;
+
+    /// True while the driver reports a fault.
+    fn is_faulted(&self) -> Result<bool, Self::Error>;
+
+    /// Puts the driver to sleep (`true`) or wakes it up.
+    fn set_sleep(&mut self, sleep: bool) -> Result<(), Self::Error>;
+
+    /// Holds the driver in reset (`true`) or releases it.
+    fn set_reset(&mut self, reset: bool) -> Result<(), Self::Error>;
+}
+
+impl<E> ControlPins for NoPin<E> {
+    type Error = E;
+
+    fn is_faulted(&self) -> Result<bool, E> {
+        Ok(false)
+    }
+
+    fn set_sleep(&mut self, _sleep: bool) -> Result<(), E> {
+        Ok(())
+    }
+
+    fn set_reset(&mut self, _reset: bool) -> Result<(), E> {
+        Ok(())
+    }
+}
+
+/// All three lines are active low.
+impl<FLT, SLP, RST, E> ControlPins for (FLT, SLP, RST)
+where
+    FLT: InputPin<Error = E>,
+    SLP: OutputPin<Error = E>,
+    RST: OutputPin<Error = E>,
+{
+    type Error = E;
+
+    fn is_faulted(&self) -> Result<bool, E> {
+        self.0.is_low()
+    }
+
+    fn set_sleep(&mut self, sleep: bool) -> Result<(), E> {
+        if sleep {
+            self.1.set_low()
+        } else {
+            self.1.set_high()
+        }
+    }
+
+    fn set_reset(&mut self, reset: bool) -> Result<(), E> {
+        if reset {
+            self.2.set_low()
+        } else {
+            self.2.set_high()
+        }
+    }
+}
+
+/// Emergency stop request that can be raised from an interrupt handler.
+///
+/// Kept in a `static` and attached to drivers with `MotorDriver::set_emergency_stop`. Once
+/// triggered, the next step of any attached driver stops it, see `MotorDriver::emergency_stop`,
+/// and moves fail with `Error::EmergencyStop` until the request is cleared.
+#[derive(Debug)]
+pub struct EmergencyStop(AtomicBool);
+
+impl EmergencyStop {
+    pub const fn new() -> Self {
+        EmergencyStop(AtomicBool::new(false))
+    }
+
+    /// Requests the stop. Never blocks, safe to call from any interrupt handler.
+    pub fn trigger(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    /// Withdraws the request, drivers can move again.
+    pub fn clear(&self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+impl Default for EmergencyStop {
+    fn default() -> Self {
+        EmergencyStop::new()
+    }
+}
+
+impl<G, CHIP, EN, MS, CTL> MotorDriver<G, CHIP, EN, MS, CTL>
+where
+    G: StepGenerator,
+    CHIP: Params,
+    EN: OutputPin<Error = G::Error>,
+    MS: ModePins<Error = G::Error>,
+    CTL: ControlPins<Error = G::Error>,
+{
+    /// Attaches the nFAULT, nSLEEP and nRESET lines, releases reset and wakes the driver.
+    #[allow(unknown_lints, clippy::type_complexity)]
+    pub fn with_control_pins<PINS>(self, control_pins: PINS)
+        -> Result<MotorDriver<G, CHIP, EN, MS, PINS>, Error<G::Error>>
+    where
+        PINS: ControlPins<Error = G::Error>,
+    {
+        let mut driver = self.map_pins(|enable_pin, mode_pins, _| (enable_pin, mode_pins, control_pins));
+        driver.control_pins.set_reset(false)?;
+        driver.control_pins.set_sleep(false)?;
+        driver.sleeping = false;
+        driver.settle_pending = true;
+        Ok(driver)
+    }
+
+    /// Checks `stop` before every step, `None` detaches it.
+    pub fn set_emergency_stop(&mut self, stop: Option<&'static EmergencyStop>) {
+        self.stop_request = stop;
+    }
+
+    /// Stops at once and de-energizes the motor: STEP low, outputs disabled and nSLEEP
+    /// asserted, velocity mode halted.
+    ///
+    /// Neither waits nor ramps down, so it can be called from an interrupt handler sharing
+    /// the driver. The position keeps the steps made so far. Without EN and nSLEEP pins the
+    /// coils stay energized.
+    pub fn emergency_stop(&mut self) -> Result<(), Error<G::Error>> {
+        self.abort();
+        self.generator.set_step(false)?;
+        self.disable()?;
+        self.sleep()
+    }
+
+    /// Returns true while the driver reports a fault on nFAULT.
+    pub fn is_faulted(&self) -> Result<bool, Error<G::Error>> {
+        Ok(self.control_pins.is_faulted()?)
+    }
+
+    /// Puts the driver to sleep, the outputs are off and it draws almost no current.
+    ///
+    /// The next move wakes it up and waits `CHIP::ENABLE_SETTLE_TIME` before the first step.
+    pub fn sleep(&mut self) -> Result<(), Error<G::Error>> {
+        self.control_pins.set_sleep(true)?;
+        self.sleeping = true;
+        Ok(())
+    }
+
+    /// Returns true while the driver sleeps.
+    pub fn is_sleeping(&self) -> bool {
+        self.sleeping
+    }
+
+    /// Pulses nRESET, which clears a latched fault and returns the translator to its home
+    /// microstep.
+    ///
+    /// The coil currents jump to the home state, so the rotor can snap by up to two full
+    /// steps. The position does not account for it, home the axis again if it matters.
+    pub fn reset(&mut self) -> Result<(), Error<G::Error>> {
+        self.control_pins.set_reset(true)?;
+        // Same minimal pulse width as STEP
+        self.generator.delay_us(CHIP::STEP_MIN_TIME);
+        self.control_pins.set_reset(false)?;
+        self.settle_pending = true;
+        Ok(())
+    }
+
+    /// Fails if an emergency stop is requested or the driver reports a fault, called before
+    /// every step.
+    pub(crate) fn check_stop(&mut self) -> Result<(), Error<G::Error>> {
+        if self.stop_request.is_some_and(EmergencyStop::is_triggered) {
+            self.emergency_stop()?;
+            return Err(Error::EmergencyStop);
+        }
+        if self.control_pins.is_faulted()? {
+            self.abort();
+            return Err(Error::Fault);
+        }
+        Ok(())
+    }
+
+    /// Forgets the velocity mode state after an aborted move.
+    fn abort(&mut self) {
+        self.target_velocity = 0.0;
+        self.jog_speed = 0.0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use a4988;
+    use core::convert::Infallible;
+    use std::cell::Cell;
+    use std::rc::Rc;
+    use tests::{driver, MockPin, NoDelay};
+    use {GpioStepGenerator, HomingConfig, LinearMove, TrapezoidalProfile};
+
+    /// nFAULT reading inactive for the given number of reads, then active.
+    #[derive(Debug, Clone)]
+    struct FaultPin(Rc<Cell<u32>>);
+
+    impl InputPin for FaultPin {
+        type Error = Infallible;
+
+        fn is_high(&self) -> Result<bool, Infallible> {
+            self.is_low().map(|low| !low)
+        }
+
+        fn is_low(&self) -> Result<bool, Infallible> {
+            let reads = self.0.get();
+            if reads == 0 {
+                return Ok(true);
+            }
+            self.0.set(reads - 1);
+            Ok(false)
+        }
+    }
+
+    type Driver = MotorDriver<GpioStepGenerator<NoDelay, MockPin, MockPin>, a4988, MockPin, NoPin<Infallible>,
+                              (FaultPin, MockPin, MockPin)>;
+
+    /// Driver with EN, nFAULT turning active after `reads` checks, nSLEEP and nRESET.
+    fn setup(reads: u32) -> (Driver, MockPin, MockPin, MockPin, MockPin) {
+        let (driver, _, step) = driver();
+        let (enable, sleep, reset) = (MockPin::default(), MockPin::default(), MockPin::default());
+        let fault = FaultPin(Rc::new(Cell::new(reads)));
+        let driver = driver
+            .with_enable_pin(enable.clone())
+            .unwrap()
+            .with_control_pins((fault, sleep.clone(), reset.clone()))
+            .unwrap();
+        (driver, step, enable, sleep, reset)
+    }
+
+    #[test]
+    fn fault_aborts_move() {
+        // One check before the move, then one per step
+        let (mut driver, step, _, _, _) = setup(41);
+        assert_eq!(driver.move_to(100), Err(Error::Fault));
+        assert_eq!(driver.position(), 40);
+        assert_eq!(step.pulses(), 40);
+        assert_eq!(driver.is_faulted(), Ok(true));
+    }
+
+    #[test]
+    fn fault_stops_velocity_mode() {
+        let (mut driver, _, _, _, _) = setup(11);
+        driver.set_acceleration(1000.0).unwrap();
+        driver.set_target_velocity(500.0).unwrap();
+        assert_eq!(driver.run_velocity(1_000_000), Err(Error::Fault));
+        assert_eq!(driver.position(), 10);
+        assert!(!driver.is_running());
+    }
+
+    #[test]
+    fn emergency_stop_disables_driver() {
+        static STOP: EmergencyStop = EmergencyStop::new();
+        let (mut driver, step, enable, sleep, _) = setup(u32::MAX);
+        driver.set_emergency_stop(Some(&STOP));
+        driver.move_to(10).unwrap();
+        assert!(driver.is_enabled());
+
+        STOP.trigger();
+        assert_eq!(driver.move_to(20), Err(Error::EmergencyStop));
+        assert_eq!(driver.position(), 10);
+        assert_eq!(step.pulses(), 10);
+        assert!(!driver.is_enabled());
+        // EN is active low on the A4988, nSLEEP is asserted
+        assert!(enable.is_high());
+        assert!(!sleep.is_high());
+        assert!(driver.is_sleeping());
+
+        // Latched until cleared
+        assert_eq!(driver.move_to(20), Err(Error::EmergencyStop));
+        STOP.clear();
+        driver.move_to(20).unwrap();
+        assert_eq!(driver.position(), 20);
+        assert!(sleep.is_high());
+        assert!(!enable.is_high());
+    }
+
+    #[test]
+    fn latched_stop_keeps_driver_off() {
+        static STOP: EmergencyStop = EmergencyStop::new();
+        let (mut driver, step, enable, sleep, _) = setup(u32::MAX);
+        driver.set_emergency_stop(Some(&STOP));
+        driver.set_backlash(3);
+        driver.move_to(10).unwrap();
+        STOP.trigger();
+        assert_eq!(driver.move_to(20), Err(Error::EmergencyStop));
+        let (enable_writes, sleep_writes) = (enable.0.borrow().len(), sleep.0.borrow().len());
+
+        // Every way to move refuses before touching EN or nSLEEP, the reversal would also
+        // take up backlash
+        let stopped = Err(Error::EmergencyStop);
+        assert_eq!(driver.move_to(0), stopped);
+        assert_eq!(driver.move_instant(5), stopped);
+        assert_eq!(driver.move_smooth(5, 1, 1), stopped);
+        assert_eq!(driver.move_profile(TrapezoidalProfile::new(5, 100.0, 100.0)), stopped);
+        assert_eq!(driver.move_until(-5, 100.0, |_| false).map(|_| ()), stopped);
+        driver.set_acceleration(1000.0).unwrap();
+        driver.set_target_velocity(-100.0).unwrap();
+        assert_eq!(driver.run_velocity(100_000), stopped);
+        let mut switch = FaultPin(Rc::new(Cell::new(u32::MAX)));
+        assert_eq!(driver.home(&mut switch, &HomingConfig::default()), stopped);
+        let linear_move = LinearMove::trapezoidal::<Infallible>([-5], 100.0, 100.0).unwrap();
+        assert_eq!(linear_move.run(&mut NoDelay, &mut [&mut driver]), stopped);
+
+        // EN stays inactive (high) and nSLEEP asserted (low)
+        assert!(enable.0.borrow()[enable_writes..].iter().all(|&level| level));
+        assert!(sleep.0.borrow()[sleep_writes..].iter().all(|&level| !level));
+        assert_eq!(driver.position(), 10);
+        assert_eq!(step.pulses(), 10);
+    }
+
+    #[test]
+    fn sleeps_and_resets() {
+        let (mut driver, step, _, sleep, reset) = setup(u32::MAX);
+        assert!(sleep.is_high());
+        assert!(reset.is_high());
+
+        driver.sleep().unwrap();
+        assert!(!sleep.is_high());
+        driver.move_by(-5).unwrap();
+        assert!(sleep.is_high());
+        assert!(!driver.is_sleeping());
+        assert_eq!(step.pulses(), 5);
+
+        driver.reset().unwrap();
+        assert_eq!(*reset.0.borrow(), vec![true, false, true]);
+        assert_eq!(driver.position(), -5);
+    }
+}
@@ -0,0 +1,244 @@
+//! Step pulse generation.
+//!
+//! `MotorDriver` decides when to step, a `StepGenerator` makes the STEP and DIR signals.
+//! `GpioStepGenerator` bit-bangs two output pins and blocks on a delay, the way the driver
+//! always worked. `RecordingStepGenerator` keeps a virtual clock instead and records every
+//! edge, so profiles and pulse timing can be checked on the host.
+
+use core::convert::Infallible;
+use embedded_hal::blocking::delay::DelayUs;
+use embedded_hal::digital::v2::OutputPin;
+
+use Error;
+
+/// Source of STEP and DIR signals.
+///
+/// Only the pin levels and the delay are required, `step` is built on them and can be
+/// overridden by generators with hardware pulse timing. `Error` is the error type of the
+/// pins, generators that fail otherwise return `Error::StepGenerator`.
+///
+/// Generators may queue pulses and delays and emit them later, but always in order and
+/// before the next DIR change. `flush` waits until everything queued is out.
+pub trait StepGenerator {
+    type Error;
+
+    /// Drives DIR high (`true`) or low.
+    fn set_dir(&mut self, high: bool) -> Result<(), Error<Self::Error>>;
+
+    /// Drives STEP high (`true`) or low, for callers timing the pulse themselves.
+    fn set_step(&mut self, high: bool) -> Result<(), Error<Self::Error>>;
+
+    /// Waits `us` microseconds.
+    fn delay_us(&mut self, us: u32);
+
+    /// Emits a STEP pulse `high_time` microseconds long and returns `interval` microseconds
+    /// after its rising edge, but no sooner than `high_time` after the falling edge.
+    fn step(&mut self, high_time: u32, interval: u32) -> Result<(), Error<Self::Error>> {
+        self.set_step(true)?;
+        self.delay_us(high_time);
+        self.set_step(false)?;
+        // Wait the rest of the interval but at least the minimal low time
+        let rest = if interval > high_time { interval - high_time } else { high_time };
+        self.delay_us(rest);
+        Ok(())
+    }
+
+    /// Returns once all queued pulses and delays are done. Generators that never queue
+    /// have nothing to do.
+    fn flush(&mut self) -> Result<(), Error<Self::Error>> {
+        Ok(())
+    }
+}
+
+/// STEP and DIR output pins timed with a blocking delay.
+#[derive(Debug)]
+pub struct GpioStepGenerator<D, DIR, STEP> {
+    delay: D,
+    dir_pin: DIR,
+    step_pin: STEP,
+}
+
+impl<D, DIR, STEP> GpioStepGenerator<D, DIR, STEP>
+where
+    D: DelayUs<u32>,
+    DIR: OutputPin<Error = STEP::Error>,
+    STEP: OutputPin,
+{
+    pub fn new(delay: D, dir_pin: DIR, step_pin: STEP) -> Self {
+        GpioStepGenerator { delay, dir_pin, step_pin }
+    }
+
+    /// Releases the delay and the pins.
+    pub fn release(self) -> (D, DIR, STEP) {
+        (self.delay, self.dir_pin, self.step_pin)
+    }
+}
+
+impl<D, DIR, STEP> StepGenerator for GpioStepGenerator<D, DIR, STEP>
+where
+    D: DelayUs<u32>,
+    DIR: OutputPin<Error = STEP::Error>,
+    STEP: OutputPin,
+{
+    type Error = STEP::Error;
+
+    fn set_dir(&mut self, high: bool) -> Result<(), Error<STEP::Error>> {
+        if high {
+            self.dir_pin.set_high()?;
+        } else {
+            self.dir_pin.set_low()?;
+        }
+        Ok(())
+    }
+
+    fn set_step(&mut self, high: bool) -> Result<(), Error<STEP::Error>> {
+        if high {
+            self.step_pin.set_high()?;
+        } else {
+            self.step_pin.set_low()?;
+        }
+        Ok(())
+    }
+
+    fn delay_us(&mut self, us: u32) {
+        self.delay.delay_us(us);
+    }
+}
+
+/// Pin levels after an edge, see `RecordingStepGenerator`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StepRecord {
+    /// Time of the edge (microseconds since the generator was created)
+    pub time: u64,
+    /// STEP level
+    pub step: bool,
+    /// DIR level
+    pub dir: bool,
+}
+
+/// Records a `StepRecord` for every STEP or DIR edge into `C`, delays only advance a
+/// virtual clock.
+///
+/// `C` is any collection that can be extended, like a `Vec` on the host or a
+/// `heapless::Vec` on the target.
+#[derive(Debug)]
+pub struct RecordingStepGenerator<C> {
+    records: C,
+    time: u64,
+    step: bool,
+    dir: bool,
+}
+
+impl<C> RecordingStepGenerator<C>
+where
+    C: Extend<StepRecord>,
+{
+    /// Starts at time 0 with both pins low, appending to `records`.
+    pub fn new(records: C) -> Self {
+        RecordingStepGenerator { records, time: 0, step: false, dir: false }
+    }
+
+    pub fn records(&self) -> &C {
+        &self.records
+    }
+
+    /// Time on the virtual clock (microseconds).
+    pub fn time(&self) -> u64 {
+        self.time
+    }
+
+    pub fn release(self) -> C {
+        self.records
+    }
+
+    fn record(&mut self) {
+        let record = StepRecord { time: self.time, step: self.step, dir: self.dir };
+        self.records.extend(core::iter::once(record));
+    }
+}
+
+impl<C> StepGenerator for RecordingStepGenerator<C>
+where
+    C: Extend<StepRecord>,
+{
+    type Error = Infallible;
+
+    fn set_dir(&mut self, high: bool) -> Result<(), Error<Infallible>> {
+        if self.dir != high {
+            self.dir = high;
+            self.record();
+        }
+        Ok(())
+    }
+
+    fn set_step(&mut self, high: bool) -> Result<(), Error<Infallible>> {
+        if self.step != high {
+            self.step = high;
+            self.record();
+        }
+        Ok(())
+    }
+
+    fn delay_us(&mut self, us: u32) {
+        self.time += u64::from(us);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+    use {a4988, MotorDriver, Params, TrapezoidalProfile};
+
+    type Recorder = RecordingStepGenerator<Vec<StepRecord>>;
+
+    /// Times of the rising STEP edges.
+    fn step_times(records: &[StepRecord]) -> Vec<u64> {
+        records.iter().filter(|r| r.step).map(|r| r.time).collect()
+    }
+
+    #[test]
+    fn records_edges() {
+        let mut generator = Recorder::new(Vec::new());
+        generator.set_dir(true).unwrap();
+        generator.step(2, 10).unwrap();
+        generator.step(2, 1).unwrap();
+        generator.set_dir(true).unwrap();
+        assert_eq!(generator.time(), 14);
+        assert_eq!(*generator.records(), vec![
+            StepRecord { time: 0, step: false, dir: true },
+            StepRecord { time: 0, step: true, dir: true },
+            StepRecord { time: 2, step: false, dir: true },
+            StepRecord { time: 10, step: true, dir: true },
+            StepRecord { time: 12, step: false, dir: true },
+        ]);
+    }
+
+    #[test]
+    fn driver_follows_profile() {
+        let mut driver: MotorDriver<Recorder, a4988> =
+            MotorDriver::new(Recorder::new(Vec::new()), 200, 1, 60.0).unwrap();
+        driver.set_direction(true).unwrap();
+        driver.move_accelerated(200, 200.0, 400.0).unwrap();
+        driver.move_to(150).unwrap();
+
+        let records = driver.release().release();
+        let steps: Vec<&StepRecord> = records.iter().filter(|r| r.step).collect();
+        assert_eq!(steps.len(), 250);
+        // Clockwise is DIR low, the way back to 150 is counter-clockwise
+        assert!(steps[..200].iter().all(|r| !r.dir));
+        assert!(steps[200..].iter().all(|r| r.dir));
+
+        let times = step_times(&records);
+        let intervals: Vec<u64> = times.windows(2).map(|w| w[1] - w[0]).collect();
+        let profile: Vec<u64> = TrapezoidalProfile::new(200, 200.0, 400.0).map(u64::from).collect();
+        assert_eq!(intervals[..200], profile[..]);
+        // 50 steps at 60 rpm, 5 ms each
+        assert!(intervals[200..].iter().all(|&interval| interval == 5000));
+
+        // Every pulse lasts the minimal high time
+        for pulse in records.windows(2).filter(|w| w[0].step && !w[1].step) {
+            assert_eq!(pulse[1].time - pulse[0].time, u64::from(a4988::STEP_MIN_TIME));
+        }
+    }
+}
@@ -0,0 +1,338 @@
+//! Homing against a limit switch.
+//!
+//! The axis seeks the switch at `seek_speed`, backs off `back_off` steps and approaches again
+//! at `approach_speed`, so the final trip point does not depend on the (fast, overshooting)
+//! first contact. The position where the switch trips the second time becomes
+//! `home_position`.
+
+use embedded_hal::digital::v2::{InputPin, OutputPin};
+
+use {check_speed, ControlPins, Error, ModePins, MotorDriver, Params, StepGenerator};
+
+/// Reasons homing can fail
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HomingError {
+    /// The switch was not reached within `max_travel` steps
+    MaxTravel,
+    /// Homing took longer than `timeout`
+    Timeout,
+    /// The switch stayed active after backing off
+    SwitchStuck,
+}
+
+/// Homing parameters. Distances are in microsteps (STEP pulses), speeds in full steps/s.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HomingConfig {
+    /// Direction towards the switch, see `MotorDriver::set_direction`
+    pub clockwise: bool,
+    /// Speed of the first approach
+    pub seek_speed: f32,
+    /// Speed of the second, precise approach
+    pub approach_speed: f32,
+    /// Distance to move away from the switch after the first contact
+    pub back_off: u32,
+    /// Longest distance travelled looking for the switch
+    pub max_travel: u32,
+    /// Time the switch has to stay active to count as tripped (microseconds)
+    pub debounce: u32,
+    /// Longest time the whole procedure may take (microseconds), `None` for no limit
+    pub timeout: Option<u32>,
+    /// Switch reads low when pressed
+    pub active_low: bool,
+    /// Position assigned to the trip point
+    pub home_position: i64,
+}
+
+impl Default for HomingConfig {
+    fn default() -> Self {
+        HomingConfig {
+            clockwise: false,
+            seek_speed: 200.0,
+            approach_speed: 20.0,
+            back_off: 200,
+            max_travel: 100_000,
+            debounce: 5000,
+            timeout: None,
+            active_low: true,
+            home_position: 0,
+        }
+    }
+}
+
+impl<G, CHIP, EN, MS, CTL> MotorDriver<G, CHIP, EN, MS, CTL>
+where
+    G: StepGenerator,
+    CHIP: Params,
+    EN: OutputPin<Error = G::Error>,
+    MS: ModePins<Error = G::Error>,
+    CTL: ControlPins<Error = G::Error>,
+{
+    /// Finds the limit `switch` and zeroes the position there, see `HomingConfig`.
+    ///
+    /// Software travel limits are ignored while homing. On error the position is left as
+    /// counted, the axis is still unhomed. Fails with `Error::InvalidSpeed` before moving if
+    /// a speed in `config` is not usable.
+    pub fn home<SW>(&mut self, switch: &mut SW, config: &HomingConfig) -> Result<(), Error<G::Error>>
+    where
+        SW: InputPin<Error = G::Error>,
+    {
+        let division = self.step_division as f32;
+        let (seek_speed, approach_speed) = (config.seek_speed * division, config.approach_speed * division);
+        check_speed::<CHIP, G::Error>(seek_speed)?;
+        check_speed::<CHIP, G::Error>(approach_speed)?;
+        let mut homing = Homing { switch, config, elapsed: 0 };
+        self.energize()?;
+
+        self.set_direction(config.clockwise)?;
+        homing.seek(self, seek_speed, config.max_travel)?;
+
+        self.set_direction(!config.clockwise)?;
+        for _ in 0..config.back_off {
+            homing.step(self, seek_speed)?;
+        }
+        self.generator.flush()?;
+        if homing.switch_active()? {
+            return Err(Error::Homing(HomingError::SwitchStuck));
+        }
+
+        // The switch is at most back_off away, allow as much again for a slow approach.
+        self.set_direction(config.clockwise)?;
+        homing.seek(self, approach_speed, config.back_off.saturating_mul(2))?;
+
+        self.position = config.home_position;
+        self.idle_time = 0;
+        Ok(())
+    }
+}
+
+/// State of a running homing procedure.
+struct Homing<'a, SW: 'a> {
+    switch: &'a mut SW,
+    config: &'a HomingConfig,
+    /// Time spent so far (microseconds)
+    elapsed: u32,
+}
+
+impl<'a, SW> Homing<'a, SW>
+where
+    SW: InputPin,
+{
+    fn switch_active(&self) -> Result<bool, SW::Error> {
+        if self.config.active_low {
+            self.switch.is_low()
+        } else {
+            self.switch.is_high()
+        }
+    }
+
+    fn wait(&mut self, us: u32) -> Result<(), Error<SW::Error>> {
+        self.elapsed = self.elapsed.saturating_add(us);
+        match self.config.timeout {
+            Some(timeout) if self.elapsed > timeout => Err(Error::Homing(HomingError::Timeout)),
+            _ => Ok(()),
+        }
+    }
+
+    /// True once the switch has been active for the debounce time.
+    fn tripped<G, CHIP, EN, MS, CTL>(&mut self, driver: &mut MotorDriver<G, CHIP, EN, MS, CTL>)
+        -> Result<bool, Error<SW::Error>>
+    where
+        G: StepGenerator<Error = SW::Error>,
+        CHIP: Params,
+        EN: OutputPin<Error = SW::Error>,
+        MS: ModePins<Error = SW::Error>,
+        CTL: ControlPins<Error = SW::Error>,
+    {
+        // The switch reflects the steps emitted so far
+        driver.generator.flush()?;
+        if !self.switch_active()? {
+            return Ok(false);
+        }
+        driver.generator.delay_us(self.config.debounce);
+        driver.generator.flush()?;
+        let debounce = self.config.debounce;
+        self.wait(debounce)?;
+        Ok(self.switch_active()?)
+    }
+
+    /// One step at `speed` (STEP pulses/s).
+    fn step<G, CHIP, EN, MS, CTL>(&mut self, driver: &mut MotorDriver<G, CHIP, EN, MS, CTL>, speed: f32)
+        -> Result<(), Error<SW::Error>>
+    where
+        G: StepGenerator<Error = SW::Error>,
+        CHIP: Params,
+        EN: OutputPin<Error = SW::Error>,
+        MS: ModePins<Error = SW::Error>,
+        CTL: ControlPins<Error = SW::Error>,
+    {
+        let step_interval = (1_000_000.0 / speed) as u32;
+        driver.pulse(step_interval)?;
+        self.wait(step_interval)
+    }
+
+    /// Steps towards the switch until it trips, at most `max_steps` steps.
+    fn seek<G, CHIP, EN, MS, CTL>(&mut self,
+                                             driver: &mut MotorDriver<G, CHIP, EN, MS, CTL>,
+                                             speed: f32,
+                                             max_steps: u32)
+        -> Result<(), Error<SW::Error>>
+    where
+        G: StepGenerator<Error = SW::Error>,
+        CHIP: Params,
+        EN: OutputPin<Error = SW::Error>,
+        MS: ModePins<Error = SW::Error>,
+        CTL: ControlPins<Error = SW::Error>,
+    {
+        for _ in 0..max_steps {
+            if self.tripped(driver)? {
+                return Ok(());
+            }
+            self.step(driver, speed)?;
+        }
+        if self.tripped(driver)? {
+            Ok(())
+        } else {
+            Err(Error::Homing(HomingError::MaxTravel))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use a4988;
+    use embedded_hal::blocking::delay::DelayUs;
+    use GpioStepGenerator;
+    use core::convert::Infallible;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /// Simulated axis with a limit switch `trip_at` steps counter-clockwise of the start.
+    #[derive(Debug, Default)]
+    struct Axis {
+        position: i64,
+        clockwise: bool,
+        step_high: bool,
+        trip_at: Option<i64>,
+        /// Position where the switch bounces active for one read
+        glitch_at: Option<i64>,
+        stuck: bool,
+    }
+
+    #[derive(Debug, Clone)]
+    struct Pin(Rc<RefCell<Axis>>, bool);
+
+    impl OutputPin for Pin {
+        type Error = Infallible;
+
+        fn set_low(&mut self) -> Result<(), Infallible> {
+            let mut axis = self.0.borrow_mut();
+            if self.1 {
+                axis.clockwise = true;
+            } else {
+                axis.step_high = false;
+            }
+            Ok(())
+        }
+
+        fn set_high(&mut self) -> Result<(), Infallible> {
+            let mut axis = self.0.borrow_mut();
+            if self.1 {
+                axis.clockwise = false;
+            } else if !axis.step_high {
+                axis.step_high = true;
+                axis.position += if axis.clockwise { 1 } else { -1 };
+            }
+            Ok(())
+        }
+    }
+
+    /// Active low switch
+    impl InputPin for Pin {
+        type Error = Infallible;
+
+        fn is_high(&self) -> Result<bool, Infallible> {
+            self.is_low().map(|low| !low)
+        }
+
+        fn is_low(&self) -> Result<bool, Infallible> {
+            let mut axis = self.0.borrow_mut();
+            if axis.glitch_at == Some(axis.position) {
+                axis.glitch_at = None;
+                return Ok(true);
+            }
+            let pressed = axis.trip_at.is_some_and(|trip| axis.position <= -trip);
+            Ok(axis.stuck || pressed)
+        }
+    }
+
+    struct NoDelay;
+
+    impl DelayUs<u32> for NoDelay {
+        fn delay_us(&mut self, _us: u32) {}
+    }
+
+    fn setup(axis: Axis) -> (MotorDriver<GpioStepGenerator<NoDelay, Pin, Pin>, a4988>, Pin) {
+        let axis = Rc::new(RefCell::new(axis));
+        let dir = Pin(axis.clone(), true);
+        let step = Pin(axis.clone(), false);
+        let driver = MotorDriver::a4988(NoDelay, dir, step, 200, 1, 60.0).unwrap();
+        (driver, Pin(axis, false))
+    }
+
+    #[test]
+    fn homes_to_switch() {
+        let (mut driver, mut switch) = setup(Axis { trip_at: Some(1234), ..Axis::default() });
+        driver.set_position(500);
+        driver.home(&mut switch, &HomingConfig::default()).unwrap();
+        assert_eq!(driver.position(), 0);
+        assert_eq!(switch.0.borrow().position, -1234);
+
+        driver.move_to(100).unwrap();
+        assert_eq!(switch.0.borrow().position, -1134);
+    }
+
+    #[test]
+    fn home_position_is_configurable() {
+        let (mut driver, mut switch) = setup(Axis { trip_at: Some(10), ..Axis::default() });
+        let config = HomingConfig { home_position: -5, back_off: 3, ..HomingConfig::default() };
+        driver.home(&mut switch, &config).unwrap();
+        assert_eq!(driver.position(), -5);
+        assert_eq!(switch.0.borrow().position, -10);
+    }
+
+    #[test]
+    fn ignores_bounces() {
+        let (mut driver, mut switch) = setup(Axis {
+            trip_at: Some(300),
+            glitch_at: Some(-100),
+            ..Axis::default()
+        });
+        driver.home(&mut switch, &HomingConfig::default()).unwrap();
+        assert_eq!(switch.0.borrow().position, -300);
+    }
+
+    #[test]
+    fn gives_up_after_max_travel() {
+        let (mut driver, mut switch) = setup(Axis::default());
+        let config = HomingConfig { max_travel: 500, ..HomingConfig::default() };
+        assert_eq!(driver.home(&mut switch, &config), Err(Error::Homing(HomingError::MaxTravel)));
+        assert_eq!(switch.0.borrow().position, -500);
+    }
+
+    #[test]
+    fn times_out() {
+        let (mut driver, mut switch) = setup(Axis { trip_at: Some(5000), ..Axis::default() });
+        // 1000 steps/s, the step that ends after 2 s is the last
+        let config = HomingConfig { seek_speed: 1000.0, timeout: Some(2_000_000), ..HomingConfig::default() };
+        assert_eq!(driver.home(&mut switch, &config), Err(Error::Homing(HomingError::Timeout)));
+        assert_eq!(switch.0.borrow().position, -2001);
+    }
+
+    #[test]
+    fn detects_stuck_switch() {
+        let (mut driver, mut switch) = setup(Axis { stuck: true, ..Axis::default() });
+        let result = driver.home(&mut switch, &HomingConfig::default());
+        assert_eq!(result, Err(Error::Homing(HomingError::SwitchStuck)));
+    }
+}
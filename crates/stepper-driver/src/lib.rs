@@ -7,7 +7,7 @@
 //!
 //! # Examples
 //!
-//! ```rust,no-run
+//! ```rust,ignore
 //! #![deny(unsafe_code)]
 //! #![deny(warnings)]
 //! #![no_std]
@@ -36,23 +36,35 @@
 //!         200,
 //!         16,
 //!         100f32
-//!     );
+//!     ).unwrap();
 //!
 //!     loop {
-//!         driver.set_speed(100f32);
-//!         driver.set_direction(true);
-//!         driver.move_instant(600);
-//!         driver.set_direction(false);
-//!         driver.move_instant(600);
+//!         driver.set_speed(100f32).unwrap();
+//!         driver.set_direction(true).unwrap();
+//!         driver.move_instant(600).unwrap();
+//!         driver.set_direction(false).unwrap();
+//!         driver.move_instant(600).unwrap();
 //!
-//!         driver.set_speed(300f32);
-//!         driver.set_direction(true);
-//!         driver.move_smooth(1600, 150, 150);
-//!         driver.set_direction(false);
-//!         driver.move_smooth(1600, 150, 150);
+//!         driver.set_speed(300f32).unwrap();
+//!         driver.set_direction(true).unwrap();
+//!         driver.move_smooth(1600, 150, 150).unwrap();
+//!         driver.set_direction(false).unwrap();
+//!         driver.move_smooth(1600, 150, 150).unwrap();
 //!     }
 //! }
 //! ```
+//!
+//! # Units
+//!
+//! Positions, limits, backlash and the distances of `move_to`, `move_by` and homing are
+//! counted in microsteps (STEP pulses). Speeds are in full steps per second and
+//! accelerations in steps/s², whatever the microstep mode, so they still hold after
+//! `set_step_division`. `set_speed` takes rpm, and the relative moves (`move_instant`,
+//! `move_smooth`, `move_accelerated`, `move_scurve`) count full steps. The `*_units` methods
+//! take millimetres or degrees instead, see `AxisConfig`.
+//!
+//! The building blocks below the driver (`TrapezoidalProfile`, `SCurveProfile`, `LinearMove`
+//! and `StepEngine`) do not know the step division and count STEP pulses throughout.
 
 #![no_std]
 
@@ -66,81 +78,467 @@
 #![deny(unused_import_braces)]
 // #![deny(unused_qualifications)]
 
+#[cfg(test)]
+#[macro_use]
+extern crate std;
+
 extern crate embedded_hal;
+#[cfg(feature = "esp32c3")]
+extern crate esp32c3_hal;
+extern crate libm;
+
+mod coordinated;
+mod engine;
+mod fault;
+mod generator;
+mod homing;
+mod planner;
+mod rmt;
+mod scurve;
+mod units;
+
+pub use coordinated::{Axis, LinearMove, StepEvent};
+pub use engine::{StepEngine, StepTimer};
+pub use fault::{ControlPins, EmergencyStop};
+pub use generator::{GpioStepGenerator, RecordingStepGenerator, StepGenerator, StepRecord};
+pub use homing::{HomingConfig, HomingError};
+pub use planner::TrapezoidalProfile;
+pub use rmt::RmtEncoder;
+#[cfg(feature = "esp32c3")]
+pub use rmt::RmtStepGenerator;
+pub use scurve::SCurveProfile;
+pub use units::{AxisConfig, Unit};
 
+use core::convert::TryFrom;
 use core::marker::PhantomData;
+use libm::sqrtf;
 use embedded_hal::blocking::delay::DelayUs;
-use embedded_hal::digital::v2::OutputPin;
+use embedded_hal::digital::v2::{InputPin, OutputPin};
+
+/// Errors
+#[derive(Debug, PartialEq)]
+pub enum Error<E> {
+    /// GPIO error
+    Pin(E),
+    /// The chip has no microstep mode with this step division
+    UnsupportedStepDivision(u8),
+    /// Speed is not positive and finite, or the step rate is above what the chip accepts
+    InvalidSpeed,
+    /// Acceleration or jerk is not positive and finite, or the move does not add up
+    InvalidProfile,
+    /// Target position (microsteps) outside the software travel limits
+    OutOfRange(i64),
+    /// Gear ratio or lead is not positive and finite, or a distance in units is negative or
+    /// not finite
+    InvalidAxis,
+    /// Homing failed
+    Homing(HomingError),
+    /// The driver reported a fault on its nFAULT output
+    Fault,
+    /// The move was aborted by an `EmergencyStop`
+    EmergencyStop,
+    /// The step generator failed to emit the pulses
+    StepGenerator,
+}
+
+impl<E> From<E> for Error<E> {
+    fn from(e: E) -> Self {
+        Error::Pin(e)
+    }
+}
+
+/// Placeholder for an optional pin that is not connected.
+///
+/// Takes the error type of the pins it stands in for, so drivers without the pin keep the
+/// same error type as drivers with it.
+#[derive(Debug)]
+pub struct NoPin<E>(PhantomData<E>);
+
+impl<E> NoPin<E> {
+    pub fn new() -> Self {
+        NoPin(PhantomData)
+    }
+}
+
+impl<E> Default for NoPin<E> {
+    fn default() -> Self {
+        NoPin::new()
+    }
+}
+
+impl<E> OutputPin for NoPin<E> {
+    type Error = E;
+
+    fn set_low(&mut self) -> Result<(), E> {
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), E> {
+        Ok(())
+    }
+}
 
-// TODO: support EN pin
-//trait Enablable {
-//    pub fn enable();
-//    pub fn disable();
-//}
+/// Reads high, the inactive level of an active low input like nFAULT.
+impl<E> InputPin for NoPin<E> {
+    type Error = E;
+
+    fn is_high(&self) -> Result<bool, E> {
+        Ok(true)
+    }
+
+    fn is_low(&self) -> Result<bool, E> {
+        Ok(false)
+    }
+}
 
-/// Stepping mode (1:step_division)
-static STEP_DIVISION: [u8; 8] = [1,2,4,8,16,32,64,128];
+/// Microstep mode select pins (MS1-MS3 on A49xx, M0-M2 on DRV88xx).
+///
+/// Implemented for tuples of two or three output pins, in `Params::STEP_MODES` order, and
+/// for `NoPin` when the mode is strapped in hardware.
+pub trait ModePins {
+    type Error;
+
+    /// Drives the pins, `true` is high.
+    fn set_levels(&mut self, levels: [bool; 3]) -> Result<(), Self::Error>;
+}
+
+fn set_level<P: OutputPin>(pin: &mut P, high: bool) -> Result<(), P::Error> {
+    if high {
+        pin.set_high()
+    } else {
+        pin.set_low()
+    }
+}
+
+impl<E> ModePins for NoPin<E> {
+    type Error = E;
+
+    fn set_levels(&mut self, _levels: [bool; 3]) -> Result<(), E> {
+        Ok(())
+    }
+}
+
+impl<P1, P2, E> ModePins for (P1, P2)
+where
+    P1: OutputPin<Error = E>,
+    P2: OutputPin<Error = E>,
+{
+    type Error = E;
+
+    fn set_levels(&mut self, levels: [bool; 3]) -> Result<(), E> {
+        set_level(&mut self.0, levels[0])?;
+        set_level(&mut self.1, levels[1])
+    }
+}
+
+impl<P1, P2, P3, E> ModePins for (P1, P2, P3)
+where
+    P1: OutputPin<Error = E>,
+    P2: OutputPin<Error = E>,
+    P3: OutputPin<Error = E>,
+{
+    type Error = E;
+
+    fn set_levels(&mut self, levels: [bool; 3]) -> Result<(), E> {
+        set_level(&mut self.0, levels[0])?;
+        set_level(&mut self.1, levels[1])?;
+        set_level(&mut self.2, levels[2])
+    }
+}
 
 /// A stepper motor driver generic struct
 #[derive(Debug)]
-pub struct MotorDriver<D, DIR, STEP, CHIP>
+pub struct MotorDriver<G, CHIP,
+                       EN = NoPin<<G as StepGenerator>::Error>,
+                       MS = NoPin<<G as StepGenerator>::Error>,
+                       CTL = NoPin<<G as StepGenerator>::Error>>
 where
-    D: DelayUs<u32>,
-    DIR: OutputPin,
-    STEP: OutputPin,
+    G: StepGenerator,
     CHIP: Params,
+    EN: OutputPin<Error = G::Error>,
+    MS: ModePins<Error = G::Error>,
+    CTL: ControlPins<Error = G::Error>,
 {
-    delay: D,
-    dir_pin: DIR,
-    step_pin: STEP,
-//    TODO: support EN pin
-//    enable_pin: OutputPin,
-//    TODO: support driver specific stepping mode
-//    driver_impl: Some(),
+    generator: G,
+    enable_pin: Option<EN>,
+    mode_pins: MS,
+    control_pins: CTL,
     _chip: PhantomData<CHIP>,
 
+    /// driver outputs are enabled
+    enabled: bool,
+    /// nSLEEP is asserted, the next move wakes the driver
+    sleeping: bool,
+    /// ENABLE_SETTLE_TIME has to pass before the next step
+    settle_pending: bool,
+    /// checked before every step, stops the driver once triggered
+    stop_request: Option<&'static EmergencyStop>,
+    /// disable the driver after being idle that long (microseconds)
+    idle_timeout: Option<u32>,
+    /// time since the last move (microseconds)
+    idle_time: u32,
+
     /// usually 200
     number_of_steps: u16,
-    /// stepping mode (1:step_division) [1,2,4,8,16,32,64,128]
+    /// stepping mode (1:step_division), one of `CHIP::STEP_MODES`
     step_division: u8,
+    /// speed (revolutions per minute)
+    rpm: f32,
     /// step pulse duration (microseconds)
     step_interval: u32,
+
+    /// current position (microsteps), counts up while turning clockwise
+    position: i64,
+    /// direction of the next steps
+    clockwise: bool,
+    /// software travel limits (microsteps, inclusive)
+    limits: Option<(i64, i64)>,
+    /// extra steps taking up the slack after a reversal (microsteps)
+    backlash: u32,
+    /// direction of the last counted step, the side the slack is taken up on
+    last_step_clockwise: Option<bool>,
+
+    /// velocity mode acceleration (microsteps/s²)
+    acceleration: f32,
+    /// velocity mode target, positive clockwise (microsteps/s)
+    target_velocity: f32,
+    /// velocity mode speed in the current direction (microsteps/s)
+    jog_speed: f32,
+
+    /// motor revolutions per output revolution
+    gear_ratio: f32,
+    /// travel per output revolution of a lead screw (mm), `None` for rotary axes
+    lead: Option<f32>,
 }
 
-impl<D, DIR, STEP, CHIP> MotorDriver<D, DIR, STEP, CHIP>
+impl<G, CHIP, EN, MS, CTL> MotorDriver<G, CHIP, EN, MS, CTL>
 where
-    D: DelayUs<u32>,
-    DIR: OutputPin,
-    STEP: OutputPin,
+    G: StepGenerator,
     CHIP: Params,
+    EN: OutputPin<Error = G::Error>,
+    MS: ModePins<Error = G::Error>,
+    CTL: ControlPins<Error = G::Error>,
 {
+    /// Attaches an EN pin. The driver starts disabled and is enabled by the next move.
+    #[allow(unknown_lints, clippy::type_complexity)]
+    pub fn with_enable_pin<PIN>(self, enable_pin: PIN)
+        -> Result<MotorDriver<G, CHIP, PIN, MS, CTL>, Error<G::Error>>
+    where
+        PIN: OutputPin<Error = G::Error>,
+    {
+        let mut driver = self.map_pins(|_, mode_pins, control_pins| (Some(enable_pin), mode_pins, control_pins));
+        driver.enabled = true;
+        driver.settle_pending = false;
+        driver.idle_time = 0;
+        driver.disable()?;
+        Ok(driver)
+    }
+
+    /// Attaches the microstep mode pins and drives them for the current step division.
+    #[allow(unknown_lints, clippy::type_complexity)]
+    pub fn with_mode_pins<PINS>(self, mode_pins: PINS)
+        -> Result<MotorDriver<G, CHIP, EN, PINS, CTL>, Error<G::Error>>
+    where
+        PINS: ModePins<Error = G::Error>,
+    {
+        let mut driver = self.map_pins(|enable_pin, _, control_pins| (enable_pin, mode_pins, control_pins));
+        let step_division = driver.step_division;
+        driver.set_step_division(step_division)?;
+        Ok(driver)
+    }
+
+    /// Moves the driver over to other optional pins, keeping its state.
+    #[allow(unknown_lints, clippy::type_complexity)]
+    fn map_pins<EN2, MS2, CTL2, F>(self, f: F) -> MotorDriver<G, CHIP, EN2, MS2, CTL2>
+    where
+        EN2: OutputPin<Error = G::Error>,
+        MS2: ModePins<Error = G::Error>,
+        CTL2: ControlPins<Error = G::Error>,
+        F: FnOnce(Option<EN>, MS, CTL) -> (Option<EN2>, MS2, CTL2),
+    {
+        let (enable_pin, mode_pins, control_pins) = f(self.enable_pin, self.mode_pins, self.control_pins);
+        MotorDriver {
+            generator: self.generator,
+            enable_pin,
+            mode_pins,
+            control_pins,
+            _chip: PhantomData,
+            enabled: self.enabled,
+            sleeping: self.sleeping,
+            settle_pending: self.settle_pending,
+            stop_request: self.stop_request,
+            idle_timeout: self.idle_timeout,
+            idle_time: self.idle_time,
+            number_of_steps: self.number_of_steps,
+            step_division: self.step_division,
+            rpm: self.rpm,
+            step_interval: self.step_interval,
+            position: self.position,
+            clockwise: self.clockwise,
+            limits: self.limits,
+            backlash: self.backlash,
+            last_step_clockwise: self.last_step_clockwise,
+            acceleration: self.acceleration,
+            target_velocity: self.target_velocity,
+            jog_speed: self.jog_speed,
+            gear_ratio: self.gear_ratio,
+            lead: self.lead,
+        }
+    }
+
+    /// Switches the microstep mode (1:step_division), keeping the speed in rpm.
+    ///
+    /// Waits for the queued pulses, they were computed for the old mode. Everything counted
+    /// in microsteps is converted to the new mode, so the axis keeps its position, limits,
+    /// backlash and velocity mode state even mid-travel. A position between two steps of a
+    /// coarser mode is rounded to the nearest one, the limits are rounded inwards.
+    ///
+    /// Fails with `Error::UnsupportedStepDivision` if the chip has no such mode, and with
+    /// `Error::InvalidSpeed` if the velocity mode would step too fast for the chip in it.
+    pub fn set_step_division(&mut self, step_division: u8) -> Result<(), Error<G::Error>> {
+        let levels = step_mode::<CHIP, G::Error>(step_division)?;
+        let step_interval = step_interval::<CHIP, G::Error>(self.number_of_steps, step_division, self.rpm)?;
+        let (from, to) = (self.step_division, step_division);
+        let ratio = to as f32 / from as f32;
+        let (jog_speed, target_velocity) = (self.jog_speed * ratio, self.target_velocity * ratio);
+        for speed in [jog_speed, target_velocity.abs()] {
+            if speed != 0.0 {
+                check_speed::<CHIP, G::Error>(speed)?;
+            }
+        }
+
+        self.generator.flush()?;
+        self.mode_pins.set_levels(levels)?;
+        // MSx/Mx setup time before the next STEP edge
+        self.generator.delay_us(CHIP::STEP_MIN_TIME);
+        self.step_division = step_division;
+        self.step_interval = step_interval;
+
+        self.position = rescale(self.position, from, to, Round::Nearest);
+        self.limits = self.limits.map(|(min, max)| {
+            (rescale(min, from, to, Round::Up), rescale(max, from, to, Round::Down))
+        });
+        // At most 255 times a u32
+        self.backlash = rescale(self.backlash.into(), from, to, Round::Nearest) as u32;
+        self.acceleration *= ratio;
+        self.jog_speed = jog_speed;
+        self.target_velocity = target_velocity;
+        Ok(())
+    }
+
+    /// Returns the current stepping mode (1:step_division).
+    pub fn step_division(&self) -> u8 {
+        self.step_division
+    }
+
+    /// Enables the driver outputs (energizes the coils), waking the driver if it sleeps.
+    ///
+    /// The first step after enabling waits `CHIP::ENABLE_SETTLE_TIME`.
+    pub fn enable(&mut self) -> Result<(), Error<G::Error>> {
+        self.idle_time = 0;
+        if self.sleeping {
+            self.control_pins.set_sleep(false)?;
+            self.sleeping = false;
+            self.settle_pending = true;
+        }
+        if self.enabled {
+            return Ok(());
+        }
+        if let Some(pin) = self.enable_pin.as_mut() {
+            if CHIP::ENABLE_ACTIVE_LOW {
+                pin.set_low()?;
+            } else {
+                pin.set_high()?;
+            }
+            self.settle_pending = true;
+        }
+        self.enabled = true;
+        Ok(())
+    }
+
+    /// Disables the driver outputs, the motor can turn freely and holds no torque.
+    ///
+    /// Without an EN pin the driver cannot be disabled and this does nothing.
+    pub fn disable(&mut self) -> Result<(), Error<G::Error>> {
+        if let Some(pin) = self.enable_pin.as_mut() {
+            if CHIP::ENABLE_ACTIVE_LOW {
+                pin.set_high()?;
+            } else {
+                pin.set_low()?;
+            }
+            self.enabled = false;
+        }
+        Ok(())
+    }
+
+    /// Returns true if the driver outputs are enabled.
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Disables the driver once it has been idle for `timeout` microseconds, see `idle`.
+    /// `None` keeps the driver enabled.
+    pub fn set_idle_timeout(&mut self, timeout: Option<u32>) {
+        self.idle_timeout = timeout;
+    }
+
+    /// Reports `elapsed` microseconds spent without moving.
+    ///
+    /// Call it from the application loop, the driver is disabled once the idle timeout
+    /// passes and enabled again by the next move.
+    pub fn idle(&mut self, elapsed: u32) -> Result<(), Error<G::Error>> {
+        self.idle_time = self.idle_time.saturating_add(elapsed);
+        match self.idle_timeout {
+            Some(timeout) if self.enabled && self.idle_time >= timeout => self.disable(),
+            _ => Ok(()),
+        }
+    }
+
     /// Sets the speed in revolutions per minute (1-200 is a reasonable range)
-    pub fn set_speed(&mut self, rpm: f32) {
-        self.step_interval =
-            (60000000f32 / self.number_of_steps as f32 / rpm / self.step_division as f32) as u32;
+    ///
+    /// Fails with `Error::InvalidSpeed` and keeps the old speed if `rpm` is not positive or
+    /// the step rate would be too high for the chip.
+    pub fn set_speed(&mut self, rpm: f32) -> Result<(), Error<G::Error>> {
+        self.step_interval = step_interval::<CHIP, G::Error>(self.number_of_steps, self.step_division, rpm)?;
+        self.rpm = rpm;
+        Ok(())
     }
 
     /// Moves the motor steps_to_move steps
+    ///
+    /// Fails with `Error::OutOfRange` without moving if the target is outside the limits.
     pub fn move_instant(&mut self, steps_to_move: u64)
-        -> Result<(), <STEP as embedded_hal::digital::v2::OutputPin>::Error> {
-        let steps_to_move = steps_to_move * self.step_division as u64;
-        for i in 0..steps_to_move {
+        -> Result<(), Error<G::Error>> {
+        let steps_to_move: u64 = self.microsteps(steps_to_move)?;
+        self.check_travel(steps_to_move)?;
+        self.energize()?;
+        for _ in 0..steps_to_move {
             self.step(None)?;
         }
-        Ok(())
+        self.finish_move()
     }
 
     /// Moves the motor smoothly `steps_to_move` steps.
     /// Increasing speed during the `steps_acc` and decreasing during `steps_dec` steps.
+    ///
+    /// `steps_acc + steps_dec` must not exceed `steps_to_move`. Fails with `Error::OutOfRange`
+    /// without moving if the target is outside the limits.
     pub fn move_smooth(&mut self,
                        steps_to_move: u64,
                        steps_acc: u64,
                        steps_dec: u64)
-        -> Result<(), <STEP as embedded_hal::digital::v2::OutputPin>::Error> {
-        let steps_to_move = (steps_to_move - steps_acc - steps_dec) * self.step_division as u64;
-        let steps_acc = steps_acc * self.step_division as u64;
-        let steps_dec = steps_dec * self.step_division as u64;
+        -> Result<(), Error<G::Error>> {
+        // Fails with InvalidProfile when the ramps are longer than the move
+        let steps_cruise = steps_acc
+            .checked_add(steps_dec)
+            .and_then(|ramps| steps_to_move.checked_sub(ramps))
+            .ok_or(Error::InvalidProfile)?;
+        let steps_to_move: u64 = self.microsteps(steps_cruise)?;
+        let steps_acc: u64 = self.microsteps(steps_acc)?;
+        let steps_dec: u64 = self.microsteps(steps_dec)?;
+        self.check_travel(steps_to_move.saturating_add(steps_acc).saturating_add(steps_dec))?;
 
+        self.energize()?;
         for i in 1..=steps_acc {
             self.step(Some((i, steps_acc)))?;
         }
@@ -150,113 +548,974 @@ where
         for i in (1..=steps_dec).rev() {
             self.step(Some((i, steps_dec)))?;
         }
+        self.finish_move()
+    }
+
+    /// Returns the current position in microsteps (STEP pulses).
+    pub fn position(&self) -> i64 {
+        self.position
+    }
+
+    /// Declares the current position to be `position` microsteps, without moving.
+    pub fn set_position(&mut self, position: i64) {
+        self.position = position;
+    }
+
+    /// Restricts `move_to` and `move_by` to targets within `min..=max` microsteps.
+    /// `None` removes the limits.
+    pub fn set_limits(&mut self, limits: Option<(i64, i64)>) {
+        self.limits = limits;
+    }
+
+    /// Returns the software travel limits.
+    pub fn limits(&self) -> Option<(i64, i64)> {
+        self.limits
+    }
+
+    /// Moves to the absolute `target` position (microsteps) at the current speed.
+    ///
+    /// Fails with `Error::OutOfRange` without moving if `target` is outside the limits or
+    /// too far from the current position to count the steps.
+    pub fn move_to(&mut self, target: i64) -> Result<(), Error<G::Error>> {
+        let delta = target.checked_sub(self.position).ok_or(Error::OutOfRange(target))?;
+        self.move_by(delta)
+    }
+
+    /// Moves `delta` microsteps at the current speed, clockwise if positive.
+    ///
+    /// Fails with `Error::OutOfRange` without moving if the target is outside the limits.
+    pub fn move_by(&mut self, delta: i64) -> Result<(), Error<G::Error>> {
+        if !self.prepare_move(delta)? {
+            return Ok(());
+        }
+        let step_interval = self.step_interval;
+        self.move_profile((0..delta.unsigned_abs()).map(|_| step_interval))?;
+        Ok(())
+    }
+
+    /// Moves up to `delta` microsteps at `speed` (steps/s), stopping early once `stop`
+    /// returns true.
+    ///
+    /// `stop` is called before every step with the number of steps taken so far, after the
+    /// pulses before it are out, so it can poll a sensor. Returns the steps taken. Fails like
+    /// `move_by`, and with `Error::InvalidSpeed` before moving.
+    pub fn move_until<F>(&mut self, delta: i64, speed: f32, mut stop: F) -> Result<u64, Error<G::Error>>
+    where
+        F: FnMut(u64) -> bool,
+    {
+        let speed = speed * self.step_division as f32;
+        check_speed::<CHIP, G::Error>(speed)?;
+        if !self.prepare_move(delta)? {
+            return Ok(0);
+        }
+        self.energize()?;
+        let step_interval = (1_000_000.0 / speed) as u32;
+        let mut steps = 0;
+        while steps < delta.unsigned_abs() {
+            self.generator.flush()?;
+            if stop(steps) {
+                break;
+            }
+            self.pulse(step_interval)?;
+            steps += 1;
+        }
+        self.finish_move()?;
+        Ok(steps)
+    }
+
+    /// Checks the target of a move by `delta` against the limits and sets the direction.
+    /// Returns false if there is nothing to move.
+    fn prepare_move(&mut self, delta: i64) -> Result<bool, Error<G::Error>> {
+        self.check_target(self.position.saturating_add(delta))?;
+        if delta == 0 {
+            return Ok(false);
+        }
+        self.set_direction(delta > 0)?;
+        Ok(true)
+    }
+
+    /// Enables the driver for a move, after checking the emergency stop and nFAULT so a
+    /// refused move never wakes the outputs.
+    fn energize(&mut self) -> Result<(), Error<G::Error>> {
+        self.check_stop()?;
+        self.enable()
+    }
+
+    /// Emits one step per interval (microseconds) yielded by `profile`, in the current
+    /// direction.
+    ///
+    /// `profile` is usually a `TrapezoidalProfile` or `SCurveProfile`, counted in STEP pulses
+    /// (microsteps). Fails with `Error::OutOfRange` without moving if a profile of known
+    /// length ends outside the limits, other profiles fail with it at the limit.
+    pub fn move_profile<I>(&mut self, profile: I)
+        -> Result<(), Error<G::Error>>
+    where
+        I: IntoIterator<Item = u32>,
+    {
+        let profile = profile.into_iter();
+        if let (steps, Some(upper)) = profile.size_hint() {
+            if steps == upper {
+                self.check_travel(steps as u64)?;
+            }
+        }
+        self.energize()?;
+        for step_interval in profile {
+            self.check_travel(1)?;
+            self.pulse(step_interval)?;
+        }
+        self.finish_move()
+    }
+
+    /// Moves the motor `steps_to_move` steps with constant acceleration.
+    ///
+    /// Accelerates from standstill with `acceleration` (steps/s²) up to `max_speed`
+    /// (steps/s) and decelerates to a stop at the end. Short moves never reach `max_speed`.
+    /// Both must be positive. Fails with `Error::OutOfRange` without moving if the target is
+    /// outside the limits.
+    pub fn move_accelerated(&mut self, steps_to_move: u32, max_speed: f32, acceleration: f32)
+        -> Result<(), Error<G::Error>> {
+        check_speed::<CHIP, G::Error>(max_speed * self.step_division as f32)?;
+        check_limit(acceleration)?;
+        let division_f = self.step_division as f32;
+        let steps: u32 = self.microsteps(steps_to_move as u64)?;
+        self.move_profile(TrapezoidalProfile::new(
+            steps,
+            max_speed * division_f,
+            acceleration * division_f,
+        ))
+    }
+
+    /// Moves the motor `steps_to_move` steps with limited jerk (S-curve).
+    ///
+    /// Like `move_accelerated`, but acceleration builds up and decays with at most
+    /// `max_jerk` (steps/s³), which avoids the shake of sudden acceleration changes.
+    /// Fails like `move_accelerated`.
+    pub fn move_scurve(&mut self,
+                       steps_to_move: u32,
+                       max_speed: f32,
+                       max_acceleration: f32,
+                       max_jerk: f32)
+        -> Result<(), Error<G::Error>> {
+        check_speed::<CHIP, G::Error>(max_speed * self.step_division as f32)?;
+        check_limit(max_acceleration)?;
+        check_limit(max_jerk)?;
+        let division_f = self.step_division as f32;
+        let steps: u32 = self.microsteps(steps_to_move as u64)?;
+        self.move_profile(SCurveProfile::new(
+            steps,
+            max_speed * division_f,
+            max_acceleration * division_f,
+            max_jerk * division_f,
+        ))
+    }
+
+    /// Fails with `Error::OutOfRange` if `steps` microsteps in the current direction end
+    /// outside the limits.
+    fn check_travel(&self, steps: u64) -> Result<(), Error<G::Error>> {
+        let steps = i64::try_from(steps).unwrap_or(i64::MAX);
+        let delta = if self.clockwise { steps } else { -steps };
+        self.check_target(self.position.saturating_add(delta))
+    }
+
+    fn check_target(&self, target: i64) -> Result<(), Error<G::Error>> {
+        match self.limits {
+            Some((min, max)) if target < min || target > max => Err(Error::OutOfRange(target)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Converts full steps to STEP pulses, fails if they do not fit a profile.
+    fn microsteps<T: TryFrom<u64>>(&self, steps: u64) -> Result<T, Error<G::Error>> {
+        steps
+            .checked_mul(self.step_division as u64)
+            .and_then(|pulses| T::try_from(pulses).ok())
+            .ok_or(Error::InvalidProfile)
+    }
+
+    /// Sets the acceleration of the velocity mode (steps/s²).
+    pub fn set_acceleration(&mut self, acceleration: f32) -> Result<(), Error<G::Error>> {
+        check_limit(acceleration)?;
+        self.acceleration = acceleration * self.step_division as f32;
+        Ok(())
+    }
+
+    /// Sets the velocity (steps/s, positive clockwise) the velocity mode ramps to.
+    ///
+    /// Takes effect in `run_velocity`, which accelerates or decelerates with the configured
+    /// acceleration, reversing through zero if the sign changes.
+    pub fn set_target_velocity(&mut self, velocity: f32) -> Result<(), Error<G::Error>> {
+        let velocity = velocity * self.step_division as f32;
+        if velocity != 0.0 {
+            check_speed::<CHIP, G::Error>(velocity.abs())?;
+        }
+        if self.acceleration == 0.0 {
+            return Err(Error::InvalidProfile);
+        }
+        self.target_velocity = velocity;
         Ok(())
     }
 
+    /// Current velocity of the velocity mode (steps/s, positive clockwise).
+    pub fn velocity(&self) -> f32 {
+        let speed = self.jog_speed / self.step_division as f32;
+        if self.clockwise { speed } else { -speed }
+    }
+
+    /// True while the velocity mode is moving or about to.
+    pub fn is_running(&self) -> bool {
+        self.jog_speed > 0.0 || self.target_velocity != 0.0
+    }
+
+    /// Runs the velocity mode for about `duration` microseconds.
+    ///
+    /// Call it in a loop: no steps are emitted between calls, so they should follow each
+    /// other closely. Returns at once when standing still at zero velocity.
+    /// With travel limits set, the motor brakes so that it stops at the limit.
+    pub fn run_velocity(&mut self, duration: u32) -> Result<(), Error<G::Error>> {
+        let mut elapsed: u32 = 0;
+        while elapsed < duration {
+            match self.jog_step()? {
+                Some(step_interval) => elapsed = elapsed.saturating_add(step_interval),
+                None => break,
+            }
+        }
+        self.generator.flush()
+    }
+
+    /// Ramps down to a standstill with the configured acceleration.
+    pub fn stop(&mut self) -> Result<(), Error<G::Error>> {
+        self.target_velocity = 0.0;
+        while self.jog_step()?.is_some() {}
+        self.generator.flush()
+    }
+
+    /// One step of the velocity mode, returns its interval or `None` at standstill.
+    ///
+    /// The speed changes by the acceleration over each step: v'² = v² ± 2a.
+    fn jog_step(&mut self) -> Result<Option<u32>, Error<G::Error>> {
+        let a2 = 2.0 * self.acceleration;
+        let target_clockwise = self.target_velocity > 0.0;
+        let mut target_speed = self.target_velocity.abs();
+
+        if self.jog_speed == 0.0 {
+            if target_speed == 0.0 {
+                return Ok(None);
+            }
+            if target_clockwise != self.clockwise {
+                self.set_direction(target_clockwise)?;
+            }
+        } else if target_clockwise != self.clockwise {
+            // Reversing: stop first, the next call starts the other way
+            target_speed = 0.0;
+        }
+
+        // Brake for the travel limit in the direction of motion
+        if let Some((min, max)) = self.limits {
+            let room = if self.clockwise { max.saturating_sub(self.position) } else { self.position.saturating_sub(min) };
+            let braking = (self.jog_speed * self.jog_speed / a2) as i64;
+            if room <= braking.max(0) + 1 {
+                target_speed = 0.0;
+            }
+            if room <= 0 {
+                self.jog_speed = 0.0;
+                return Ok(None);
+            }
+        }
+
+        let speed = self.jog_speed;
+        let next = if speed < target_speed {
+            sqrtf(speed * speed + a2).min(target_speed)
+        } else {
+            let squared = speed * speed - a2;
+            if squared > 0.0 { sqrtf(squared).max(target_speed) } else { 0.0 }
+        };
+        if speed == 0.0 && next == 0.0 {
+            return Ok(None);
+        }
+
+        let step_interval = (2_000_000.0 / (speed + next)) as u32;
+        if speed == 0.0 {
+            self.energize()?;
+        } else {
+            // Running, `pulse` checks the stop
+            self.enable()?;
+        }
+        self.pulse(step_interval)?;
+        self.jog_speed = next;
+        Ok(Some(step_interval))
+    }
+
+    /// Sets the backlash compensation (microsteps), 0 disables it.
+    pub fn set_backlash(&mut self, steps: u32) {
+        self.backlash = steps;
+    }
+
+    /// Returns the backlash compensation (microsteps).
+    pub fn backlash(&self) -> u32 {
+        self.backlash
+    }
+
     /// Set the direction
+    ///
+    /// When the last step went the other way, the backlash compensation steps are emitted
+    /// right away at the current speed. They are not counted in the position.
     pub fn set_direction(&mut self, clock_work: bool)
-        -> Result<(), <DIR as embedded_hal::digital::v2::OutputPin>::Error> {
+        -> Result<(), Error<G::Error>> {
         if clock_work {
-            self.dir_pin.set_low()
+            self.generator.set_dir(false)?;
         } else {
-            self.dir_pin.set_high()
+            self.generator.set_dir(true)?;
+        }
+        self.clockwise = clock_work;
+
+        if self.backlash > 0 && self.last_step_clockwise == Some(!clock_work) {
+            self.energize()?;
+            // DIR setup time before the first STEP edge
+            self.generator.delay_us(CHIP::STEP_MIN_TIME);
+            for _ in 0..self.backlash {
+                let step_interval = self.step_interval;
+                self.raw_pulse(step_interval)?;
+            }
+            self.last_step_clockwise = Some(clock_work);
         }
+        Ok(())
+    }
+
+    /// Waits for the queued pulses, the idle time starts after them.
+    fn finish_move(&mut self) -> Result<(), Error<G::Error>> {
+        self.generator.flush()?;
+        self.idle_time = 0;
+        Ok(())
+    }
+
+    /// Counts a step in the current direction.
+    fn count_step(&mut self) {
+        self.position += if self.clockwise { 1 } else { -1 };
+        self.last_step_clockwise = Some(self.clockwise);
     }
 
     /// Toggle step and yield to step control.
     ///
-    /// !!!FIXME!!!
-    /// Super naive implementation due to limitaions of the `embedded-hal` crate.
-    /// One should use a timer instead of delay when `timer` and `time` API stabilize.
+    /// Blocks on the delay for the whole step, `StepEngine` steps from a timer interrupt
+    /// instead.
     fn step(&mut self, s: Option<(u64, u64)>)
-        -> Result<(), <STEP as embedded_hal::digital::v2::OutputPin>::Error> {
-        self.step_pin.set_high()?;
-
+        -> Result<(), Error<G::Error>> {
         let mut step_interval = self.step_interval;
         if let Some((s1, s2)) = s {
             let r1: f64 = s1 as f64 / s2 as f64;
             let r2: f64 = 0.1 + 0.2*r1 + 2.2*r1*r1 - 1.5*r1*r1*r1;
             step_interval = (self.step_interval as f64 / r2) as u32;
         }
+        self.pulse(step_interval)
+    }
 
-        // Wait at least step_min_time
-        self.delay.delay_us(CHIP::STEP_MIN_TIME);
-        self.step_pin.set_low();
+    /// Emits one STEP pulse and waits until `step_interval` microseconds have passed.
+    fn pulse(&mut self, step_interval: u32)
+        -> Result<(), Error<G::Error>> {
+        self.raw_pulse(step_interval)?;
+        self.count_step();
+        Ok(())
+    }
 
-        // Wait the rest of step_interval but at least step_min_time
-        let rest = if step_interval > CHIP::STEP_MIN_TIME {
-            step_interval - CHIP::STEP_MIN_TIME
-        } else {
-            CHIP::STEP_MIN_TIME
-        };
-        self.delay.delay_us(rest);
+    /// Like `pulse`, without counting the step.
+    fn raw_pulse(&mut self, step_interval: u32)
+        -> Result<(), Error<G::Error>> {
+        self.check_stop()?;
+        if self.settle_pending {
+            self.generator.delay_us(CHIP::ENABLE_SETTLE_TIME);
+            self.settle_pending = false;
+        }
+        self.generator.step(CHIP::STEP_MIN_TIME, step_interval)?;
         Ok(())
     }
 
-    /// Generic version of constructor
-    fn new(delay: D,
-           mut dir_pin: DIR,
-           mut step_pin: STEP,
-           number_of_steps: u16,
-           step_division: u8,
-           rpm: f32) -> Self {
-        dir_pin.set_high();
-        step_pin.set_low();
+    /// Returns the step generator.
+    pub fn step_generator(&self) -> &G {
+        &self.generator
+    }
 
-        MotorDriver {
-            delay,
-            dir_pin,
-            step_pin,
+    /// Releases the step generator, dropping the optional pins.
+    pub fn release(self) -> G {
+        self.generator
+    }
+}
+
+impl<G, CHIP> MotorDriver<G, CHIP>
+where
+    G: StepGenerator,
+    CHIP: Params,
+{
+    /// Generic version of constructor, stepping with any `StepGenerator`.
+    ///
+    /// Fails if the chip does not support `step_division`, if the speed is invalid, or if a
+    /// pin fails.
+    pub fn new(mut generator: G,
+               number_of_steps: u16,
+               step_division: u8,
+               rpm: f32) -> Result<Self, Error<G::Error>> {
+        step_mode::<CHIP, G::Error>(step_division)?;
+        let step_interval = step_interval::<CHIP, G::Error>(number_of_steps, step_division, rpm)?;
+        generator.set_dir(true)?;
+        generator.set_step(false)?;
+
+        Ok(MotorDriver {
+            generator,
+            enable_pin: None,
+            mode_pins: NoPin(PhantomData),
+            control_pins: NoPin(PhantomData),
             _chip: PhantomData,
+            enabled: true,
+            sleeping: false,
+            settle_pending: false,
+            stop_request: None,
+            idle_timeout: None,
+            idle_time: 0,
             number_of_steps,
             step_division,
-            step_interval: (60000000f32 / number_of_steps as f32
-                / rpm / step_division as f32) as u32,
-        }
+            rpm,
+            step_interval,
+            position: 0,
+            clockwise: false,
+            limits: None,
+            backlash: 0,
+            last_step_clockwise: None,
+            acceleration: 0.0,
+            target_velocity: 0.0,
+            jog_speed: 0.0,
+            gear_ratio: 1.0,
+            lead: None,
+        })
+    }
+}
+
+/// Pin levels (MS1-MS3 or M0-M2, `true` is high) selecting the given step division.
+fn step_mode<CHIP: Params, E>(step_division: u8) -> Result<[bool; 3], Error<E>> {
+    CHIP::STEP_MODES
+        .iter()
+        .find(|&&(division, _)| division == step_division)
+        .map(|&(_, levels)| levels)
+        .ok_or(Error::UnsupportedStepDivision(step_division))
+}
+
+/// Rounding of `rescale`
+#[derive(Debug, Clone, Copy)]
+enum Round {
+    Down,
+    Nearest,
+    Up,
+}
+
+/// Converts `value` microsteps at 1:`from` to microsteps at 1:`to`, saturating at the `i64`
+/// range.
+fn rescale(value: i64, from: u8, to: u8, round: Round) -> i64 {
+    let from = i128::from(from);
+    let bias = match round {
+        Round::Down => 0,
+        Round::Nearest => from / 2,
+        Round::Up => from - 1,
+    };
+    let scaled = (i128::from(value) * i128::from(to) + bias).div_euclid(from);
+    scaled.clamp(i64::MIN.into(), i64::MAX.into()) as i64
+}
+
+/// STEP period (microseconds) for `rpm`.
+fn step_interval<CHIP: Params, E>(number_of_steps: u16, step_division: u8, rpm: f32)
+    -> Result<u32, Error<E>> {
+    if number_of_steps == 0 {
+        return Err(Error::InvalidSpeed);
+    }
+    check_speed::<CHIP, E>(rpm / 60.0 * number_of_steps as f32 * step_division as f32)?;
+    Ok((60000000f32 / number_of_steps as f32 / rpm / step_division as f32) as u32)
+}
+
+/// Checks a step rate (STEP pulses per second) against the chip's minimal pulse times.
+fn check_speed<CHIP: Params, E>(pulses_per_second: f32) -> Result<(), Error<E>> {
+    let max = 1_000_000.0 / (2 * CHIP::STEP_MIN_TIME) as f32;
+    if pulses_per_second > 0.0 && pulses_per_second <= max {
+        Ok(())
+    } else {
+        // Also rejects NaN
+        Err(Error::InvalidSpeed)
+    }
+}
+
+/// Checks an acceleration or jerk limit.
+fn check_limit<E>(limit: f32) -> Result<(), Error<E>> {
+    if limit > 0.0 && limit.is_finite() {
+        Ok(())
+    } else {
+        Err(Error::InvalidProfile)
     }
 }
 
 /// Trait for motor driver parameters.
-/// Currently only `STEP_MIN_TIME`.
 pub trait Params {
     /// STEP high/low min value (microseconds)
     const STEP_MIN_TIME: u32;
+    /// EN pin polarity, true if the driver is enabled while the pin is low
+    const ENABLE_ACTIVE_LOW: bool;
+    /// Delay between enabling the outputs or waking the driver and the first STEP pulse
+    /// (microseconds)
+    const ENABLE_SETTLE_TIME: u32;
+    /// Supported microstep modes: step division and the mode pin levels selecting it
+    const STEP_MODES: &'static [(u8, [bool; 3])];
 }
 
+const L: bool = false;
+const H: bool = true;
+
+/// MS1, MS2, MS3
+const A4988_STEP_MODES: [(u8, [bool; 3]); 5] = [
+    (1, [L, L, L]),
+    (2, [H, L, L]),
+    (4, [L, H, L]),
+    (8, [H, H, L]),
+    (16, [H, H, H]),
+];
+
+/// M0, M1, M2
+const DRV8825_STEP_MODES: [(u8, [bool; 3]); 6] = [
+    (1, [L, L, L]),
+    (2, [H, L, L]),
+    (4, [L, H, L]),
+    (8, [H, H, L]),
+    (16, [L, L, H]),
+    (32, [H, L, H]),
+];
+
+/// M0, M1. 1:4 and 1:32 need M0 floating, which output pins cannot do.
+const DRV8834_STEP_MODES: [(u8, [bool; 3]); 4] = [
+    (1, [L, L, L]),
+    (2, [H, L, L]),
+    (8, [L, H, L]),
+    (16, [H, H, L]),
+];
+
+/// M0, M1. 1:8 and the non-circular modes need a floating pin, which output pins cannot do.
+const DRV8880_STEP_MODES: [(u8, [bool; 3]); 4] = [
+    (1, [L, L, L]),
+    (2, [L, H, L]),
+    (4, [H, H, L]),
+    (16, [H, L, L]),
+];
+
+/// MS1, MS2 in standalone mode. Over UART MS1/MS2 select the slave address instead and the
+/// resolution is set with CHOPCONF.MRES.
+const TMC2209_STEP_MODES: [(u8, [bool; 3]); 4] = [
+    (8, [L, L, L]),
+    (16, [H, H, L]),
+    (32, [H, L, L]),
+    (64, [L, H, L]),
+];
+
 macro_rules! driver {
-    ($name:ident, $time:expr) => {
+    ($name:ident, $time:expr, $enable_active_low:expr, $settle_time:expr, $modes:expr) => {
         #[allow(non_camel_case_types)]
         #[derive(Debug)]
         pub struct $name;
 
         impl Params for $name {
             const STEP_MIN_TIME: u32 = $time;
+            const ENABLE_ACTIVE_LOW: bool = $enable_active_low;
+            const ENABLE_SETTLE_TIME: u32 = $settle_time;
+            const STEP_MODES: &'static [(u8, [bool; 3])] = &$modes;
         }
 
-        impl<D, DIR, STEP> MotorDriver<D, DIR, STEP, $name>
+        impl<D, DIR, STEP> MotorDriver<GpioStepGenerator<D, DIR, STEP>, $name>
         where
             D: DelayUs<u32>,
-            DIR: OutputPin,
+            DIR: OutputPin<Error = STEP::Error>,
             STEP: OutputPin
         {
             /// Specialized constructor
+            ///
+            /// Fails if the chip does not support `step_division`, if the speed is invalid, or
+            /// if a pin fails.
             pub fn $name(delay: D,
                          dir_pin: DIR,
                          step_pin: STEP,
                          number_of_steps: u16,
-                         mut step_division: u8,
-                         rpm: f32) -> Self {
-                if !STEP_DIVISION.contains(&step_division) {
-                    step_division = 1;
-                }
-
-                Self::new(delay, dir_pin, step_pin, number_of_steps, step_division, rpm)
+                         step_division: u8,
+                         rpm: f32) -> Result<Self, Error<STEP::Error>> {
+                let generator = GpioStepGenerator::new(delay, dir_pin, step_pin);
+                Self::new(generator, number_of_steps, step_division, rpm)
             }
         }
     };
 }
 
-driver!(a4988, 1);
-driver!(drv8825, 2);
-driver!(drv8834, 2);
-driver!(drv8880, 1);
+driver!(a4988, 1, true, 1000, A4988_STEP_MODES);
+driver!(drv8825, 2, true, 1700, DRV8825_STEP_MODES);
+driver!(drv8834, 2, true, 1000, DRV8834_STEP_MODES);
+// DRV8880 has an active high ENABLE input
+driver!(drv8880, 1, false, 1000, DRV8880_STEP_MODES);
+driver!(tmc2209, 1, true, 1000, TMC2209_STEP_MODES);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::convert::Infallible;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::vec::Vec;
+
+    /// Output pin recording its levels into a shared log.
+    #[derive(Debug, Clone, Default)]
+    pub struct MockPin(pub Rc<RefCell<Vec<bool>>>);
+
+    impl MockPin {
+        /// Number of rising edges so far.
+        pub fn pulses(&self) -> usize {
+            self.0.borrow().windows(2).filter(|w| !w[0] && w[1]).count()
+        }
+
+        pub fn is_high(&self) -> bool {
+            self.0.borrow().last() == Some(&true)
+        }
+    }
+
+    impl OutputPin for MockPin {
+        type Error = Infallible;
+
+        fn set_low(&mut self) -> Result<(), Infallible> {
+            self.0.borrow_mut().push(false);
+            Ok(())
+        }
+
+        fn set_high(&mut self) -> Result<(), Infallible> {
+            self.0.borrow_mut().push(true);
+            Ok(())
+        }
+    }
+
+    #[derive(Debug)]
+    pub struct NoDelay;
+
+    impl DelayUs<u32> for NoDelay {
+        fn delay_us(&mut self, _us: u32) {}
+    }
+
+    pub type Driver = MotorDriver<GpioStepGenerator<NoDelay, MockPin, MockPin>, a4988>;
+
+    /// A4988 with 200 steps/rev at 1:1, and its DIR and STEP pins.
+    pub fn driver() -> (Driver, MockPin, MockPin) {
+        let (dir, step) = (MockPin::default(), MockPin::default());
+        let driver = MotorDriver::a4988(NoDelay, dir.clone(), step.clone(), 200, 1, 60.0).unwrap();
+        (driver, dir, step)
+    }
+
+    #[test]
+    fn move_to_tracks_position() {
+        let (mut driver, dir, step) = driver();
+        driver.move_to(150).unwrap();
+        assert_eq!(driver.position(), 150);
+        assert_eq!(step.pulses(), 150);
+        assert!(!dir.is_high());
+
+        driver.move_to(-50).unwrap();
+        assert_eq!(driver.position(), -50);
+        assert_eq!(step.pulses(), 350);
+        assert!(dir.is_high());
+
+        driver.move_by(20).unwrap();
+        driver.move_by(0).unwrap();
+        assert_eq!(driver.position(), -30);
+    }
+
+    #[test]
+    fn relative_moves_count_too() {
+        let (mut driver, _, _) = driver();
+        driver.set_direction(true).unwrap();
+        driver.move_instant(10).unwrap();
+        driver.set_direction(false).unwrap();
+        driver.move_accelerated(4, 100.0, 100.0).unwrap();
+        assert_eq!(driver.position(), 6);
+
+        driver.set_position(1000);
+        driver.move_by(-1).unwrap();
+        assert_eq!(driver.position(), 999);
+    }
+
+    #[test]
+    fn limits_reject_targets() {
+        let (mut driver, _, step) = driver();
+        driver.set_limits(Some((-100, 100)));
+        assert_eq!(driver.move_to(101), Err(Error::OutOfRange(101)));
+        assert_eq!(driver.move_by(-101), Err(Error::OutOfRange(-101)));
+        assert_eq!(step.pulses(), 0);
+
+        driver.move_to(100).unwrap();
+        assert_eq!(driver.move_by(1), Err(Error::OutOfRange(101)));
+        driver.move_to(-100).unwrap();
+        assert_eq!(driver.position(), -100);
+
+        driver.set_limits(None);
+        driver.move_by(-1).unwrap();
+        assert_eq!(driver.position(), -101);
+
+        // The distance does not fit an i64
+        assert_eq!(driver.move_to(i64::MAX), Err(Error::OutOfRange(i64::MAX)));
+        assert_eq!(step.pulses(), 301);
+    }
+
+    #[test]
+    fn moves_until_told_to_stop() {
+        let (mut driver, dir, step) = driver();
+        assert_eq!(driver.move_until(-100, 1000.0, |steps| steps == 30), Ok(30));
+        assert_eq!(driver.position(), -30);
+        assert_eq!(step.pulses(), 30);
+        assert!(dir.is_high());
+
+        assert_eq!(driver.move_until(20, 1000.0, |_| false), Ok(20));
+        assert_eq!(driver.position(), -10);
+        assert_eq!(driver.move_until(20, 0.0, |_| false), Err(Error::InvalidSpeed));
+        driver.set_limits(Some((-10, 0)));
+        assert_eq!(driver.move_until(20, 1000.0, |_| false), Err(Error::OutOfRange(10)));
+    }
+
+    #[test]
+    fn limits_apply_to_every_move() {
+        let (mut driver, _, step) = driver();
+        driver.set_limits(Some((-10, 10)));
+        driver.set_direction(true).unwrap();
+        assert_eq!(driver.move_instant(11), Err(Error::OutOfRange(11)));
+        assert_eq!(driver.move_smooth(11, 3, 3), Err(Error::OutOfRange(11)));
+        assert_eq!(driver.move_accelerated(11, 100.0, 100.0), Err(Error::OutOfRange(11)));
+        assert_eq!(driver.move_scurve(11, 100.0, 100.0, 1000.0), Err(Error::OutOfRange(11)));
+        assert_eq!(driver.move_profile(vec![1000; 11]), Err(Error::OutOfRange(11)));
+        assert_eq!(step.pulses(), 0);
+
+        driver.move_instant(10).unwrap();
+        driver.set_direction(false).unwrap();
+        driver.move_accelerated(20, 100.0, 100.0).unwrap();
+        assert_eq!(driver.position(), -10);
+
+        // Profiles of unknown length stop at the limit
+        driver.set_direction(true).unwrap();
+        let endless = core::iter::repeat(1000).filter(|_| true);
+        assert_eq!(driver.move_profile(endless), Err(Error::OutOfRange(11)));
+        assert_eq!(driver.position(), 10);
+    }
+
+    #[test]
+    fn rejects_invalid_speed() {
+        let (mut driver, _, _) = driver();
+        assert_eq!(driver.set_speed(0.0), Err(Error::InvalidSpeed));
+        assert_eq!(driver.set_speed(-10.0), Err(Error::InvalidSpeed));
+        assert_eq!(driver.set_speed(f32::NAN), Err(Error::InvalidSpeed));
+        // 500 kHz is the limit with 1 us pulses
+        assert_eq!(driver.set_speed(200_000.0), Err(Error::InvalidSpeed));
+        assert_eq!(driver.step_interval, 5000);
+        driver.set_speed(120.0).unwrap();
+        assert_eq!(driver.step_interval, 2500);
+
+        let result = MotorDriver::a4988(NoDelay, MockPin::default(), MockPin::default(), 200, 1, 0.0);
+        assert_eq!(result.err(), Some(Error::InvalidSpeed));
+        let result = MotorDriver::a4988(NoDelay, MockPin::default(), MockPin::default(), 0, 1, 60.0);
+        assert_eq!(result.err(), Some(Error::InvalidSpeed));
+    }
+
+    #[test]
+    fn rejects_invalid_profiles() {
+        let (mut driver, _, step) = driver();
+        assert_eq!(driver.move_smooth(100, 60, 50), Err(Error::InvalidProfile));
+        assert_eq!(driver.move_smooth(100, u64::MAX, 1), Err(Error::InvalidProfile));
+        assert_eq!(driver.move_accelerated(100, 0.0, 1000.0), Err(Error::InvalidSpeed));
+        assert_eq!(driver.move_accelerated(100, 1000.0, -1.0), Err(Error::InvalidProfile));
+        assert_eq!(driver.move_scurve(100, 1000.0, 1000.0, 0.0), Err(Error::InvalidProfile));
+        assert_eq!(step.pulses(), 0);
+
+        driver.move_smooth(100, 50, 50).unwrap();
+        assert_eq!(step.pulses(), 100);
+    }
+
+    /// Pin whose rising edges fail.
+    struct BrokenPin;
+
+    impl OutputPin for BrokenPin {
+        type Error = ();
+
+        fn set_low(&mut self) -> Result<(), ()> {
+            Ok(())
+        }
+
+        fn set_high(&mut self) -> Result<(), ()> {
+            Err(())
+        }
+    }
+
+    #[test]
+    fn propagates_pin_errors() {
+        let result = MotorDriver::a4988(NoDelay, BrokenPin, BrokenPin, 200, 1, 60.0);
+        assert_eq!(result.err(), Some(Error::Pin(())));
+    }
+
+    #[test]
+    fn backlash_steps_are_not_counted() {
+        let (mut driver, _, step) = driver();
+        driver.set_backlash(5);
+        driver.set_direction(false).unwrap();
+        driver.set_direction(true).unwrap();
+        assert_eq!(step.pulses(), 0);
+
+        driver.move_by(10).unwrap();
+        assert_eq!(step.pulses(), 10);
+        driver.move_by(-3).unwrap();
+        assert_eq!(step.pulses(), 18);
+        driver.move_by(-2).unwrap();
+        assert_eq!(step.pulses(), 20);
+        assert_eq!(driver.position(), 5);
+
+        // Every reversal takes up the slack, even without moving in between
+        driver.set_direction(true).unwrap();
+        driver.set_direction(true).unwrap();
+        driver.set_direction(false).unwrap();
+        assert_eq!(step.pulses(), 30);
+        driver.move_to(10).unwrap();
+        assert_eq!(step.pulses(), 40);
+        assert_eq!(driver.position(), 10);
+
+        driver.set_backlash(0);
+        driver.move_to(0).unwrap();
+        assert_eq!(step.pulses(), 50);
+    }
+
+    /// Runs the velocity mode until it stands still, returns the step intervals.
+    fn jog(driver: &mut Driver) -> Vec<u32> {
+        let mut intervals = Vec::new();
+        while let Some(interval) = driver.jog_step().unwrap() {
+            intervals.push(interval);
+            assert!(intervals.len() < 100_000, "never stops");
+        }
+        intervals
+    }
+
+    #[test]
+    fn velocity_mode_ramps_to_target() {
+        let (mut driver, _, _) = driver();
+        assert_eq!(driver.set_target_velocity(100.0), Err(Error::InvalidProfile));
+        driver.set_acceleration(1000.0).unwrap();
+        driver.set_target_velocity(200.0).unwrap();
+        assert!(driver.is_running());
+
+        driver.run_velocity(1_000_000).unwrap();
+        assert_eq!(driver.velocity(), 200.0);
+        // 20 steps to reach 200 steps/s at 1000 steps/s², then 5 ms per step
+        let position = driver.position();
+        assert!(position > 180 && position < 200, "position {}", position);
+
+        driver.stop().unwrap();
+        assert_eq!(driver.velocity(), 0.0);
+        assert!(!driver.is_running());
+        assert_eq!(driver.position(), position + 20);
+        driver.run_velocity(1_000_000).unwrap();
+        assert_eq!(driver.position(), position + 20);
+    }
+
+    #[test]
+    fn velocity_mode_reverses_through_zero() {
+        let (mut driver, _, _) = driver();
+        driver.set_acceleration(1000.0).unwrap();
+        driver.set_target_velocity(100.0).unwrap();
+        driver.run_velocity(100_000).unwrap();
+        assert_eq!(driver.velocity(), 100.0);
+
+        driver.set_target_velocity(-100.0).unwrap();
+        let turning = driver.position();
+        driver.run_velocity(1_000_000).unwrap();
+        assert_eq!(driver.velocity(), -100.0);
+        // Decelerated over 5 steps before turning around
+        assert!(driver.position() < turning);
+
+        // Slowing down without reversing keeps the direction
+        driver.set_target_velocity(-50.0).unwrap();
+        driver.run_velocity(200_000).unwrap();
+        assert_eq!(driver.velocity(), -50.0);
+    }
+
+    #[test]
+    fn step_division_change_mid_travel() {
+        let (mut driver, _, step) = driver();
+        driver.set_limits(Some((-100, 300)));
+        driver.set_backlash(2);
+        driver.move_to(100).unwrap();
+
+        driver.set_step_division(4).unwrap();
+        assert_eq!(driver.position(), 400);
+        assert_eq!(driver.limits(), Some((-400, 1200)));
+        assert_eq!(driver.backlash(), 8);
+        // Still the same travel to the limit
+        assert_eq!(driver.move_to(1201), Err(Error::OutOfRange(1201)));
+        driver.move_to(1200).unwrap();
+        assert_eq!(step.pulses(), 100 + 800);
+
+        // Between two steps of the coarser mode
+        driver.move_by(-3).unwrap();
+        driver.set_step_division(1).unwrap();
+        assert_eq!(driver.position(), 299);
+        assert_eq!(driver.limits(), Some((-100, 300)));
+        assert_eq!(driver.backlash(), 2);
+        assert_eq!(driver.set_step_division(3), Err(Error::UnsupportedStepDivision(3)));
+        assert_eq!(driver.step_division(), 1);
+    }
+
+    #[test]
+    fn step_division_change_keeps_velocity() {
+        let (mut driver, _, _) = driver();
+        driver.set_acceleration(1000.0).unwrap();
+        driver.set_target_velocity(100.0).unwrap();
+        driver.run_velocity(200_000).unwrap();
+        assert_eq!(driver.velocity(), 100.0);
+
+        driver.set_step_division(8).unwrap();
+        assert_eq!(driver.velocity(), 100.0);
+        let position = driver.position();
+        driver.run_velocity(100_000).unwrap();
+        // 100 steps/s for 0.1 s are 80 microsteps at 1:8
+        assert_eq!(driver.position() - position, 80);
+
+        // Ramps down over about the same distance as at 1:1 (5 steps)
+        driver.stop().unwrap();
+        let braking = driver.position() - position - 80;
+        assert!((40..=41).contains(&braking), "braked over {} microsteps", braking);
+
+        // At 40000 steps/s, 1:16 would need more pulses than the A4988 takes
+        driver.set_step_division(1).unwrap();
+        driver.set_target_velocity(40_000.0).unwrap();
+        driver.run_velocity(100_000).unwrap();
+        assert_eq!(driver.set_step_division(16), Err(Error::InvalidSpeed));
+        assert_eq!(driver.step_division(), 1);
+    }
+
+    #[test]
+    fn velocity_mode_stops_at_limits() {
+        let (mut driver, _, _) = driver();
+        driver.set_limits(Some((-1000, 300)));
+        driver.set_acceleration(1000.0).unwrap();
+        driver.set_target_velocity(500.0).unwrap();
+        let intervals = jog(&mut driver);
+        assert_eq!(driver.position(), 300);
+        // The last steps are slow, it braked before the limit
+        assert!(intervals[intervals.len() - 1] > 10_000);
+        // At the limit it does not start again
+        driver.run_velocity(100_000).unwrap();
+        assert_eq!(driver.position(), 300);
+    }
+
+    #[test]
+    fn velocity_mode_with_the_widest_limits() {
+        let (mut driver, _, _) = driver();
+        driver.set_limits(Some((i64::MIN, i64::MAX)));
+        driver.set_position(-10);
+        driver.set_acceleration(1000.0).unwrap();
+        driver.set_target_velocity(-100.0).unwrap();
+        driver.run_velocity(100_000).unwrap();
+        assert_eq!(driver.velocity(), -100.0);
+        assert!(driver.position() < -10);
+
+        driver.set_position(10);
+        driver.set_target_velocity(100.0).unwrap();
+        driver.run_velocity(200_000).unwrap();
+        assert_eq!(driver.velocity(), 100.0);
+    }
+}
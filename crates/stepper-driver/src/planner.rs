@@ -0,0 +1,166 @@
+//! Constant acceleration (trapezoidal) motion planner.
+//!
+//! Step intervals are computed with the recurrence from David Austin's "Generate stepper-motor
+//! speed profiles in real time" (the same one AccelStepper uses):
+//!
+//! ```text
+//! c0 = 0.676 * sqrt(2 / a)
+//! cn = cn-1 - 2 * cn-1 / (4n + 1)
+//! ```
+//!
+//! so a whole move needs a single square root. Moves too short to reach the maximum speed get
+//! a triangular profile, accelerating for the first half and decelerating for the second.
+
+use libm::sqrtf;
+
+/// Correction of the first interval for the error of the recurrence (Austin, eq. 15).
+const C0_CORRECTION: f32 = 0.676;
+
+/// Iterator over the step intervals of a constant acceleration move.
+///
+/// Each item is the time in microseconds from one step to the next, starting with the first
+/// step. Speeds and accelerations are in steps (STEP pulses) per second and second².
+#[derive(Debug, Clone)]
+pub struct TrapezoidalProfile {
+    steps: u32,
+    ramp_steps: u32,
+    triangular: bool,
+    min_interval: f32,
+    step: u32,
+    interval: f32,
+}
+
+impl TrapezoidalProfile {
+    /// Plans a move of `steps` steps starting and ending at standstill.
+    ///
+    /// `max_speed` and `acceleration` must be positive.
+    pub fn new(steps: u32, max_speed: f32, acceleration: f32) -> Self {
+        // Steps needed to reach max_speed: v² = 2as
+        let to_max_speed = (max_speed * max_speed / (2.0 * acceleration)) as u32;
+        let ramp_steps = to_max_speed.max(1).min(steps / 2);
+
+        TrapezoidalProfile {
+            steps,
+            ramp_steps,
+            triangular: to_max_speed > steps / 2,
+            min_interval: 1_000_000.0 / max_speed,
+            step: 0,
+            interval: C0_CORRECTION * sqrtf(2.0 / acceleration) * 1_000_000.0,
+        }
+    }
+
+    /// Total number of steps of the move.
+    pub fn steps(&self) -> u32 {
+        self.steps
+    }
+
+    /// Number of steps spent accelerating, the same number is spent decelerating.
+    pub fn ramp_steps(&self) -> u32 {
+        self.ramp_steps
+    }
+
+    /// True if the move is too short to reach the maximum speed.
+    pub fn is_triangular(&self) -> bool {
+        self.triangular
+    }
+}
+
+impl Iterator for TrapezoidalProfile {
+    type Item = u32;
+
+    fn next(&mut self) -> Option<u32> {
+        if self.step >= self.steps {
+            return None;
+        }
+        let remaining = self.steps - self.step;
+        let n = self.step as f32;
+
+        if self.step == 0 {
+            // c0 as computed in `new`
+        } else if remaining < self.ramp_steps {
+            // Mirror of the acceleration ramp: cn-1 = cn * (4n + 1) / (4n - 1)
+            let n = remaining as f32;
+            self.interval = self.interval * (4.0 * n + 1.0) / (4.0 * n - 1.0);
+        } else if self.step < self.ramp_steps {
+            self.interval -= 2.0 * self.interval / (4.0 * n + 1.0);
+            self.interval = self.interval.max(self.min_interval);
+        }
+        // Cruising, and the first deceleration step, keep the last interval.
+
+        self.step += 1;
+        Some(self.interval as u32)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = (self.steps - self.step) as usize;
+        (remaining, Some(remaining))
+    }
+}
+
+impl ExactSizeIterator for TrapezoidalProfile {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    fn intervals(steps: u32, max_speed: f32, acceleration: f32) -> Vec<u32> {
+        TrapezoidalProfile::new(steps, max_speed, acceleration).collect()
+    }
+
+    #[test]
+    fn yields_every_step() {
+        assert_eq!(intervals(0, 1000.0, 1000.0).len(), 0);
+        assert_eq!(intervals(1, 1000.0, 1000.0).len(), 1);
+        assert_eq!(intervals(1234, 1000.0, 1000.0).len(), 1234);
+    }
+
+    #[test]
+    fn never_exceeds_max_speed() {
+        let max_speed = 2000.0;
+        for &steps in &[3, 10, 100, 1000, 10_000] {
+            for interval in intervals(steps, max_speed, 4000.0) {
+                assert!(interval >= 499, "interval {} for {} steps", interval, steps);
+            }
+        }
+    }
+
+    #[test]
+    fn reaches_max_speed_and_cruises() {
+        let profile = TrapezoidalProfile::new(10_000, 1000.0, 1000.0);
+        assert_eq!(profile.ramp_steps(), 500);
+        assert!(!profile.is_triangular());
+        let intervals: Vec<u32> = profile.collect();
+        assert!(intervals[5000] <= 1001);
+    }
+
+    #[test]
+    fn ramps_are_symmetric() {
+        let intervals = intervals(2000, 2000.0, 4000.0);
+        let n = intervals.len();
+        for i in 0..500 {
+            let (up, down) = (intervals[i] as i64, intervals[n - 1 - i] as i64);
+            assert!((up - down).abs() <= 1, "step {}: {} vs {}", i, up, down);
+        }
+    }
+
+    #[test]
+    fn short_moves_are_triangular() {
+        let profile = TrapezoidalProfile::new(11, 2000.0, 4000.0);
+        assert_eq!(profile.ramp_steps(), 5);
+        assert!(profile.is_triangular());
+        let intervals: Vec<u32> = profile.collect();
+        // Speeds up, then slows down again without ever cruising at max speed.
+        assert!(intervals.windows(2).take(4).all(|w| w[1] < w[0]));
+        assert!(intervals.windows(2).skip(6).all(|w| w[1] > w[0]));
+        assert!(intervals.iter().all(|&c| c > 500));
+    }
+
+    #[test]
+    fn acceleration_matches_request() {
+        // After the ramp the elapsed time should be close to v / a.
+        let ramp: u64 = intervals(10_000, 1000.0, 500.0).iter().take(1000).map(|&c| c as u64).sum();
+        let expected = 1000.0 / 500.0 * 1_000_000.0;
+        assert!((ramp as f32 - expected).abs() / expected < 0.02, "ramp took {} us", ramp);
+    }
+}
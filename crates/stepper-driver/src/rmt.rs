@@ -0,0 +1,357 @@
+//! Hardware step generation with the RMT peripheral.
+//!
+//! The RMT transmitter plays a list of pulse codes, each two (level, duration) halves, without
+//! the CPU. `RmtEncoder` turns steps and delays into such codes with 1 µs ticks: every step
+//! is a code of its own, so the intervals of an acceleration ramp change from step to step,
+//! and delays become low time before the next pulse. Codes are collected in a buffer of `N`
+//! and sent when it is full or on `flush`.
+//!
+//! With the `esp32c3` feature, `RmtStepGenerator` drives an ESP32-C3 RMT TX channel as a
+//! `StepGenerator`. Configure the channel for 1 MHz ticks with the output idling low:
+//!
+//! ```rust,ignore
+//! let rmt = Rmt::new(peripherals.RMT, 1u32.MHz(), &clocks).unwrap();
+//! let channel = rmt.channel0.configure(step_pin, TxChannelConfig {
+//!     clk_divider: 1,
+//!     ..TxChannelConfig::default()
+//! }).unwrap();
+//! let generator: RmtStepGenerator<_, _, 0, 48> = RmtStepGenerator::new(channel, dir_pin);
+//! let mut driver = MotorDriver::<_, drv8825>::new(generator, 200, 16, 60.0).unwrap();
+//! ```
+//!
+//! Pulses inside a buffer are exact, between two buffers the low time grows by the time it
+//! takes to start the next transmission. Faults and emergency stops are checked when a step
+//! is queued, up to `N` steps before it is emitted.
+
+/// Longest half of a pulse code (ticks)
+const MAX_LENGTH: u32 = 0x7fff;
+
+/// Packs a pulse code: 15 bit duration and level for each half, first half in the low bits.
+fn code(first: (bool, u16), second: (bool, u16)) -> u32 {
+    u32::from(first.1) | u32::from(first.0) << 15 | u32::from(second.1) << 16 | u32::from(second.0) << 31
+}
+
+/// Encodes steps and delays (1 µs ticks) into RMT pulse codes, see the module documentation.
+///
+/// Every code ends low, so a transmission can stop after any of them. `N` must be at least 2,
+/// one code is kept for the end marker.
+#[derive(Debug)]
+pub struct RmtEncoder<const N: usize> {
+    codes: [u32; N],
+    len: usize,
+    /// first half of the next code
+    half: Option<(bool, u16)>,
+    /// low time not encoded yet (ticks)
+    low: u32,
+    /// steps in `codes`
+    buffered: u32,
+    /// steps sent successfully
+    emitted: u64,
+}
+
+impl<const N: usize> RmtEncoder<N> {
+    pub const fn new() -> Self {
+        RmtEncoder { codes: [0; N], len: 0, half: None, low: 0, buffered: 0, emitted: 0 }
+    }
+
+    /// Number of steps sent so far.
+    pub fn emitted(&self) -> u64 {
+        self.emitted
+    }
+
+    /// Queues a pulse `high` ticks long, followed by `low` ticks before anything else.
+    ///
+    /// `send` gets the codes, end marker included, whenever the buffer is full.
+    pub fn step<E, F>(&mut self, high: u32, low: u32, send: &mut F) -> Result<(), E>
+    where
+        F: FnMut(&[u32]) -> Result<(), E>,
+    {
+        let pending = core::mem::replace(&mut self.low, 0);
+        self.push(false, pending, send)?;
+        self.push(true, high.clamp(1, MAX_LENGTH), send)?;
+        self.low = low;
+        Ok(())
+    }
+
+    /// Queues `ticks` of low time.
+    pub fn delay(&mut self, ticks: u32) {
+        self.low = self.low.saturating_add(ticks);
+    }
+
+    /// Sends everything queued, including the low time after the last step.
+    pub fn flush<E, F>(&mut self, send: &mut F) -> Result<(), E>
+    where
+        F: FnMut(&[u32]) -> Result<(), E>,
+    {
+        let pending = core::mem::replace(&mut self.low, 0);
+        self.push(false, pending, send)?;
+        if let Some(half) = self.half.take() {
+            let (first, second) = split(half);
+            self.push_code(first, second, send)?;
+        }
+        if self.len > 0 {
+            self.send(send)?;
+        }
+        Ok(())
+    }
+
+    fn push<E, F>(&mut self, level: bool, ticks: u32, send: &mut F) -> Result<(), E>
+    where
+        F: FnMut(&[u32]) -> Result<(), E>,
+    {
+        let mut ticks = ticks;
+        while ticks > 0 {
+            let length = ticks.min(MAX_LENGTH);
+            ticks -= length;
+            let half = (level, length as u16);
+            match self.half.take() {
+                None => self.half = Some(half),
+                // A pulse never starts in the second half, so codes keep ending low
+                Some(first) if !first.0 && level => {
+                    let (first, second) = split(first);
+                    self.push_code(first, second, send)?;
+                    self.half = Some(half);
+                }
+                Some(first) => self.push_code(first, half, send)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn push_code<E, F>(&mut self, first: (bool, u16), second: (bool, u16), send: &mut F) -> Result<(), E>
+    where
+        F: FnMut(&[u32]) -> Result<(), E>,
+    {
+        self.codes[self.len] = code(first, second);
+        self.len += 1;
+        if first.0 {
+            self.buffered += 1;
+        }
+        if self.len + 1 >= N {
+            self.send(send)?;
+        }
+        Ok(())
+    }
+
+    fn send<E, F>(&mut self, send: &mut F) -> Result<(), E>
+    where
+        F: FnMut(&[u32]) -> Result<(), E>,
+    {
+        self.codes[self.len] = 0;
+        let result = send(&self.codes[..=self.len]);
+        self.len = 0;
+        let steps = core::mem::replace(&mut self.buffered, 0);
+        result?;
+        self.emitted += u64::from(steps);
+        Ok(())
+    }
+}
+
+impl<const N: usize> Default for RmtEncoder<N> {
+    fn default() -> Self {
+        RmtEncoder::new()
+    }
+}
+
+/// Splits one half into a whole code. A zero duration would end the transmission, so a
+/// single tick becomes two.
+fn split(half: (bool, u16)) -> ((bool, u16), (bool, u16)) {
+    let (level, length) = half;
+    if length >= 2 {
+        ((level, length / 2), (level, length - length / 2))
+    } else {
+        ((level, 1), (level, 1))
+    }
+}
+
+#[cfg(feature = "esp32c3")]
+pub use self::esp32c3::RmtStepGenerator;
+
+#[cfg(feature = "esp32c3")]
+mod esp32c3 {
+    use embedded_hal::digital::v2::OutputPin;
+    use esp32c3_hal::rmt::TxChannel;
+
+    use super::RmtEncoder;
+    use {Error, StepGenerator};
+
+    /// `StepGenerator` on an ESP32-C3 RMT TX channel, see the module documentation.
+    ///
+    /// `CHANNEL` is the RMT channel number and `N` the number of codes per transmission.
+    /// STEP is driven by the RMT, so `set_step` can only wait for the pulses to end and
+    /// coordinated moves, which time STEP themselves, are not supported.
+    #[derive(Debug)]
+    pub struct RmtStepGenerator<C, DIR, const CHANNEL: u8, const N: usize> {
+        /// `None` only while transmitting
+        channel: Option<C>,
+        dir_pin: DIR,
+        encoder: RmtEncoder<N>,
+    }
+
+    impl<C, DIR, const CHANNEL: u8, const N: usize> RmtStepGenerator<C, DIR, CHANNEL, N>
+    where
+        C: TxChannel<CHANNEL>,
+        DIR: OutputPin,
+    {
+        pub fn new(channel: C, dir_pin: DIR) -> Self {
+            RmtStepGenerator { channel: Some(channel), dir_pin, encoder: RmtEncoder::new() }
+        }
+
+        /// Number of STEP pulses the RMT has emitted.
+        pub fn steps_emitted(&self) -> u64 {
+            self.encoder.emitted()
+        }
+
+        /// Releases the channel and the DIR pin, call `flush` first to keep queued pulses.
+        pub fn release(self) -> (C, DIR) {
+            // Every transmission hands the channel back, even when it fails
+            (self.channel.expect("RMT channel"), self.dir_pin)
+        }
+    }
+
+    /// Sends `codes` and waits until they are out.
+    fn transmit<C, E, const CHANNEL: u8>(channel: &mut Option<C>, codes: &[u32]) -> Result<(), Error<E>>
+    where
+        C: TxChannel<CHANNEL>,
+    {
+        let tx = channel.take().ok_or(Error::StepGenerator)?;
+        match tx.transmit(codes).wait() {
+            Ok(tx) => {
+                *channel = Some(tx);
+                Ok(())
+            }
+            Err((_, tx)) => {
+                *channel = Some(tx);
+                Err(Error::StepGenerator)
+            }
+        }
+    }
+
+    impl<C, DIR, const CHANNEL: u8, const N: usize> StepGenerator for RmtStepGenerator<C, DIR, CHANNEL, N>
+    where
+        C: TxChannel<CHANNEL>,
+        DIR: OutputPin,
+    {
+        type Error = DIR::Error;
+
+        fn set_dir(&mut self, high: bool) -> Result<(), Error<DIR::Error>> {
+            // Queued pulses belong to the old direction
+            self.flush()?;
+            if high {
+                self.dir_pin.set_high()?;
+            } else {
+                self.dir_pin.set_low()?;
+            }
+            Ok(())
+        }
+
+        fn set_step(&mut self, high: bool) -> Result<(), Error<DIR::Error>> {
+            if high {
+                return Err(Error::StepGenerator);
+            }
+            self.flush()
+        }
+
+        fn delay_us(&mut self, us: u32) {
+            self.encoder.delay(us);
+        }
+
+        fn step(&mut self, high_time: u32, interval: u32) -> Result<(), Error<DIR::Error>> {
+            let rest = if interval > high_time { interval - high_time } else { high_time };
+            let channel = &mut self.channel;
+            self.encoder.step(high_time, rest, &mut |codes: &[u32]| transmit(channel, codes))
+        }
+
+        fn flush(&mut self) -> Result<(), Error<DIR::Error>> {
+            let channel = &mut self.channel;
+            self.encoder.flush(&mut |codes: &[u32]| transmit(channel, codes))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    /// (level, duration) halves of the sent codes, one list per transmission.
+    #[derive(Debug, Default)]
+    struct Transmissions(Vec<Vec<(bool, u32)>>);
+
+    impl Transmissions {
+        fn send(&mut self, codes: &[u32]) -> Result<(), ()> {
+            assert_eq!(codes.last(), Some(&0), "missing end marker");
+            let mut halves = Vec::new();
+            for &code in &codes[..codes.len() - 1] {
+                for half in [code & 0xffff, code >> 16].iter() {
+                    assert!(half & 0x7fff > 0, "zero length ends the transmission early");
+                    halves.push((half & 0x8000 != 0, half & 0x7fff));
+                }
+            }
+            assert_eq!(halves.last().map(|h| h.0), Some(false), "transmission ends high");
+            self.0.push(halves);
+            Ok(())
+        }
+
+        /// Rising edges and total duration over all transmissions.
+        fn summary(&self) -> (usize, u32) {
+            let halves: Vec<(bool, u32)> = self.0.iter().flatten().cloned().collect();
+            let edges = halves.windows(2).filter(|w| !w[0].0 && w[1].0).count();
+            let first_high = halves.first().is_some_and(|h| h.0);
+            (edges + first_high as usize, halves.iter().map(|h| h.1).sum())
+        }
+    }
+
+    #[test]
+    fn encodes_steps() {
+        let mut tx = Transmissions::default();
+        let mut encoder = RmtEncoder::<8>::new();
+        encoder.step(2, 498, &mut |codes: &[u32]| tx.send(codes)).unwrap();
+        encoder.step(2, 248, &mut |codes: &[u32]| tx.send(codes)).unwrap();
+        assert!(tx.0.is_empty());
+        encoder.flush(&mut |codes: &[u32]| tx.send(codes)).unwrap();
+        assert_eq!(tx.0, vec![vec![(true, 2), (false, 498), (true, 2), (false, 248)]]);
+        assert_eq!(encoder.emitted(), 2);
+    }
+
+    #[test]
+    fn delays_become_low_time() {
+        let mut tx = Transmissions::default();
+        let mut encoder = RmtEncoder::<8>::new();
+        // Settle time, then a pulse
+        encoder.delay(1001);
+        encoder.step(1, 99, &mut |codes: &[u32]| tx.send(codes)).unwrap();
+        // Longer than a half can hold
+        encoder.delay(100_000);
+        encoder.flush(&mut |codes: &[u32]| tx.send(codes)).unwrap();
+        assert_eq!(tx.0[0][..4], [(false, 500), (false, 501), (true, 1), (false, 32767)]);
+        assert_eq!(tx.summary(), (1, 1001 + 1 + 99 + 100_000));
+    }
+
+    #[test]
+    fn ramps_span_transmissions() {
+        let mut tx = Transmissions::default();
+        let mut encoder = RmtEncoder::<16>::new();
+        let intervals: Vec<u32> = (0..100).map(|i| 2000 - 15 * i).collect();
+        encoder.delay(1);
+        for &interval in &intervals {
+            encoder.step(2, interval - 2, &mut |codes: &[u32]| tx.send(codes)).unwrap();
+        }
+        encoder.flush(&mut |codes: &[u32]| tx.send(codes)).unwrap();
+
+        assert!(tx.0.len() > 6);
+        assert!(tx.0.iter().all(|halves| halves.len() <= 2 * 15));
+        // One tick is padded to two when the leading low time has to be split
+        assert_eq!(tx.summary(), (100, intervals.iter().sum::<u32>() + 2));
+        assert_eq!(encoder.emitted(), 100);
+    }
+
+    #[test]
+    fn failed_transmissions_are_not_counted() {
+        let mut encoder = RmtEncoder::<4>::new();
+        let result = encoder.step(2, 100, &mut |_: &[u32]| Ok::<(), ()>(()));
+        assert!(result.is_ok());
+        assert_eq!(encoder.flush(&mut |_: &[u32]| Err(())), Err(()));
+        assert_eq!(encoder.emitted(), 0);
+    }
+}
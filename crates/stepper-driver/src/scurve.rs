@@ -0,0 +1,276 @@
+//! Jerk limited (S-curve) motion profiles.
+//!
+//! The move is planned as the classic seven segment profile: jerk up, constant acceleration,
+//! jerk down, cruise, and the mirrored deceleration. Velocity, acceleration and jerk never
+//! exceed the given limits; when the move is too short the cruise and constant acceleration
+//! segments shrink away and the peak velocity is lowered.
+//!
+//! Step times are found by inverting the position of the profile at every whole step, so the
+//! intervals follow the ideal curve without accumulating rounding errors.
+
+use libm::{cbrt, sqrt};
+
+/// One segment of constant jerk.
+#[derive(Debug, Clone, Copy, Default)]
+struct Segment {
+    /// Start time (s)
+    t0: f64,
+    /// Position (steps), velocity and acceleration at `t0`
+    s0: f64,
+    v0: f64,
+    a0: f64,
+    /// Jerk (steps/s³)
+    j: f64,
+    /// Length (s)
+    duration: f64,
+}
+
+impl Segment {
+    fn position(&self, t: f64) -> f64 {
+        self.s0 + t * (self.v0 + t * (self.a0 / 2.0 + t * self.j / 6.0))
+    }
+
+    fn velocity(&self, t: f64) -> f64 {
+        self.v0 + t * (self.a0 + t * self.j / 2.0)
+    }
+
+    fn acceleration(&self, t: f64) -> f64 {
+        self.a0 + t * self.j
+    }
+
+    /// Segment following this one, with jerk `j`.
+    fn next(&self, j: f64, duration: f64) -> Segment {
+        let t = self.duration;
+        Segment {
+            t0: self.t0 + t,
+            s0: self.position(t),
+            v0: self.velocity(t),
+            a0: self.acceleration(t),
+            j,
+            duration,
+        }
+    }
+
+    /// Time within the segment at which `s` is reached, by Newton's method kept inside a
+    /// shrinking bracket. Velocity is never negative, so position is monotonic.
+    ///
+    /// Converges on time rather than position: near standstill a tiny position error is a
+    /// large time error.
+    fn time_at(&self, s: f64) -> f64 {
+        let (mut lo, mut hi) = (0.0, self.duration);
+        let mut t = hi / 2.0;
+        for _ in 0..100 {
+            let error = self.position(t) - s;
+            if error > 0.0 {
+                hi = t;
+            } else {
+                lo = t;
+            }
+            let v = self.velocity(t);
+            let newton = if v > 0.0 { t - error / v } else { lo - 1.0 };
+            let next = if newton > lo && newton < hi { newton } else { (lo + hi) / 2.0 };
+            if (next - t).abs() < 1e-9 {
+                return next;
+            }
+            t = next;
+        }
+        t
+    }
+}
+
+/// Jerk (`tj`) and constant acceleration (`ta`) time of a ramp from standstill to `v`.
+fn ramp_times(v: f64, a: f64, j: f64) -> (f64, f64) {
+    if v * j >= a * a {
+        (a / j, v / a - a / j)
+    } else {
+        // Acceleration limit is never reached
+        (sqrt(v / j), 0.0)
+    }
+}
+
+/// Iterator over the step intervals of a jerk limited move.
+///
+/// Items are microseconds from one step to the next, like `TrapezoidalProfile`, so both can
+/// be passed to `MotorDriver::move_profile`. Limits are in steps (STEP pulses) per second,
+/// second² and second³.
+#[derive(Debug, Clone)]
+pub struct SCurveProfile {
+    steps: u32,
+    segments: [Segment; 7],
+    segment: usize,
+    step: u32,
+    /// Time of the previous step, rounded to microseconds
+    last_us: u64,
+}
+
+impl SCurveProfile {
+    /// Plans a move of `steps` steps starting and ending at standstill.
+    ///
+    /// All limits must be positive.
+    pub fn new(steps: u32, max_speed: f32, max_acceleration: f32, max_jerk: f32) -> Self {
+        let distance = steps as f64;
+        let (a, j) = (max_acceleration as f64, max_jerk as f64);
+
+        let mut v = max_speed as f64;
+        let (mut tj, mut ta) = ramp_times(v, a, j);
+        let ramp_distance = v * (2.0 * tj + ta) / 2.0;
+        let tv = if 2.0 * ramp_distance <= distance {
+            (distance - 2.0 * ramp_distance) / v
+        } else {
+            // Highest speed both ramps fit in: 2 * ramp_distance(v) == distance
+            let with_ta = (-a * a / j + sqrt(a * a * a * a / (j * j) + 4.0 * a * distance)) / 2.0;
+            v = if with_ta * j >= a * a {
+                with_ta
+            } else {
+                cbrt(distance * distance * j / 4.0)
+            };
+            let times = ramp_times(v, a, j);
+            tj = times.0;
+            ta = times.1;
+            0.0
+        };
+
+        let first = Segment { j, duration: tj, ..Segment::default() };
+        let mut segments = [first; 7];
+        let shape = [(0.0, ta), (-j, tj), (0.0, tv), (-j, tj), (0.0, ta), (j, tj)];
+        for (i, &(jerk, duration)) in shape.iter().enumerate() {
+            segments[i + 1] = segments[i].next(jerk, duration);
+        }
+
+        SCurveProfile { steps, segments, segment: 0, step: 0, last_us: 0 }
+    }
+
+    /// Total number of steps of the move.
+    pub fn steps(&self) -> u32 {
+        self.steps
+    }
+
+    /// Duration of the whole move (s).
+    pub fn duration(&self) -> f32 {
+        let last = &self.segments[6];
+        (last.t0 + last.duration) as f32
+    }
+
+    /// Highest velocity reached (steps/s).
+    pub fn peak_speed(&self) -> f32 {
+        self.segments[3].v0 as f32
+    }
+
+    /// Highest acceleration reached (steps/s²).
+    pub fn peak_acceleration(&self) -> f32 {
+        self.segments[1].a0 as f32
+    }
+
+    /// Time (s) at which the move reaches position `s` (steps).
+    fn time_at(&mut self, s: f64) -> f64 {
+        while self.segment < 6 {
+            let segment = &self.segments[self.segment];
+            if segment.position(segment.duration) >= s {
+                break;
+            }
+            self.segment += 1;
+        }
+        let segment = &self.segments[self.segment];
+        segment.t0 + segment.time_at(s)
+    }
+}
+
+impl Iterator for SCurveProfile {
+    type Item = u32;
+
+    fn next(&mut self) -> Option<u32> {
+        if self.step >= self.steps {
+            return None;
+        }
+        self.step += 1;
+        let t = (self.time_at(self.step as f64) * 1_000_000.0 + 0.5) as u64;
+        let interval = t - self.last_us;
+        self.last_us = t;
+        Some(interval as u32)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = (self.steps - self.step) as usize;
+        (remaining, Some(remaining))
+    }
+}
+
+impl ExactSizeIterator for SCurveProfile {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    /// Checks the step timing against the limits.
+    ///
+    /// The speed over each interval is the average speed of the profile, which can never be
+    /// above the limit. Acceleration is taken between the average speeds of groups of steps,
+    /// single intervals are too coarse at microsecond resolution, with a small tolerance.
+    fn check_limits(steps: u32, v: f32, a: f32, j: f32) {
+        let intervals: Vec<u32> = SCurveProfile::new(steps, v, a, j).collect();
+        assert_eq!(intervals.len(), steps as usize);
+
+        for &c in &intervals {
+            let speed = 1e6 / c as f64;
+            assert!(speed <= v as f64 * 1.001, "speed {} above {}", speed, v);
+        }
+
+        let mut t = 0.0;
+        let speeds: Vec<(f64, f64)> = intervals
+            .chunks(16)
+            .map(|group| {
+                let dt = group.iter().map(|&c| c as f64 / 1e6).sum::<f64>();
+                t += dt;
+                (t - dt / 2.0, group.len() as f64 / dt)
+            })
+            .collect();
+        for w in speeds.windows(2) {
+            let acc = (w[1].1 - w[0].1) / (w[1].0 - w[0].0);
+            assert!(acc.abs() <= a as f64 * 1.05, "acceleration {} above {}", acc, a);
+        }
+    }
+
+    #[test]
+    fn long_move_respects_limits() {
+        let profile = SCurveProfile::new(20_000, 2000.0, 4000.0, 20_000.0);
+        assert_eq!(profile.peak_speed(), 2000.0);
+        assert_eq!(profile.peak_acceleration(), 4000.0);
+        check_limits(20_000, 2000.0, 4000.0, 20_000.0);
+    }
+
+    #[test]
+    fn acceleration_limit_not_reached() {
+        // v * j < a², the ramp is jerk up then jerk down only
+        let profile = SCurveProfile::new(5_000, 500.0, 4000.0, 10_000.0);
+        assert!(profile.peak_acceleration() < 4000.0);
+        check_limits(5_000, 500.0, 4000.0, 10_000.0);
+    }
+
+    #[test]
+    fn short_moves_lower_peak_speed() {
+        for &steps in &[1, 2, 10, 100, 1000] {
+            let profile = SCurveProfile::new(steps, 2000.0, 4000.0, 20_000.0);
+            assert!(profile.peak_speed() < 2000.0);
+            check_limits(steps, 2000.0, 4000.0, 20_000.0);
+        }
+    }
+
+    #[test]
+    fn ends_where_the_profile_ends() {
+        let profile = SCurveProfile::new(3000, 1000.0, 2000.0, 10_000.0);
+        let duration = profile.duration();
+        let total: u64 = profile.map(|c| c as u64).sum();
+        assert!((total as f32 / 1e6 - duration).abs() < 1e-5);
+    }
+
+    #[test]
+    fn profile_is_symmetric() {
+        let intervals: Vec<u32> = SCurveProfile::new(4000, 1000.0, 2000.0, 10_000.0).collect();
+        let n = intervals.len();
+        for i in 0..1000 {
+            let (up, down) = (intervals[i] as i64, intervals[n - 1 - i] as i64);
+            assert!((up - down).abs() <= 1, "step {}: {} vs {}", i, up, down);
+        }
+    }
+}
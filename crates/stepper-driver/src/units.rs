@@ -0,0 +1,294 @@
+//! Physical units for stepper axes.
+//!
+//! An `AxisConfig` describes the mechanics between the motor and the load: full steps per
+//! motor revolution, microstepping, gear ratio and, for linear axes, the lead of the screw.
+//! Linear axes are measured in millimetres, rotary axes in degrees of the output shaft.
+
+use core::convert::TryFrom;
+use embedded_hal::digital::v2::OutputPin;
+use libm::{ceilf, floorf, roundf};
+
+use {check_limit, check_speed, ControlPins, Error, ModePins, MotorDriver, Params, StepGenerator,
+     TrapezoidalProfile};
+
+/// Unit of an axis
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Unit {
+    /// Millimetres, lead screw axes
+    Millimetre,
+    /// Degrees of the output shaft, rotary axes
+    Degree,
+}
+
+/// Mechanics of an axis, converts between physical units and microsteps (STEP pulses).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AxisConfig {
+    /// Full steps per motor revolution, usually 200
+    pub steps_per_revolution: u16,
+    /// Microstepping (1:step_division)
+    pub step_division: u8,
+    /// Motor revolutions per output revolution, above 1 for a reduction
+    pub gear_ratio: f32,
+    /// Travel per output revolution (mm), `None` for rotary axes
+    pub lead: Option<f32>,
+}
+
+impl AxisConfig {
+    /// Rotary axis driven directly by the motor.
+    pub fn rotary(steps_per_revolution: u16, step_division: u8) -> Self {
+        AxisConfig { steps_per_revolution, step_division, gear_ratio: 1.0, lead: None }
+    }
+
+    /// Linear axis with a lead screw moving `lead` mm per revolution, driven directly.
+    pub fn lead_screw(steps_per_revolution: u16, step_division: u8, lead: f32) -> Self {
+        AxisConfig { lead: Some(lead), ..AxisConfig::rotary(steps_per_revolution, step_division) }
+    }
+
+    pub fn with_gear_ratio(mut self, gear_ratio: f32) -> Self {
+        self.gear_ratio = gear_ratio;
+        self
+    }
+
+    pub fn unit(&self) -> Unit {
+        match self.lead {
+            Some(_) => Unit::Millimetre,
+            None => Unit::Degree,
+        }
+    }
+
+    /// Microsteps per millimetre or degree.
+    pub fn steps_per_unit(&self) -> f32 {
+        let per_output_revolution =
+            self.steps_per_revolution as f32 * self.step_division as f32 * self.gear_ratio;
+        per_output_revolution / self.lead.unwrap_or(360.0)
+    }
+
+    /// Position or distance in microsteps, rounded to the nearest one.
+    pub fn to_steps(&self, distance: f32) -> i64 {
+        roundf(distance * self.steps_per_unit()) as i64
+    }
+
+    /// Position or distance in millimetres or degrees.
+    pub fn to_units(&self, steps: i64) -> f32 {
+        steps as f32 / self.steps_per_unit()
+    }
+
+    fn is_valid(&self) -> bool {
+        let positive = |value: f32| value > 0.0 && value.is_finite();
+        self.steps_per_revolution > 0
+            && self.step_division > 0
+            && positive(self.gear_ratio)
+            && self.lead.is_none_or(positive)
+    }
+}
+
+impl<G, CHIP, EN, MS, CTL> MotorDriver<G, CHIP, EN, MS, CTL>
+where
+    G: StepGenerator,
+    CHIP: Params,
+    EN: OutputPin<Error = G::Error>,
+    MS: ModePins<Error = G::Error>,
+    CTL: ControlPins<Error = G::Error>,
+{
+    /// Sets the mechanics after the motor, see `AxisConfig`. The motor steps and the step
+    /// division are the driver's own.
+    pub fn set_transmission(&mut self, gear_ratio: f32, lead: Option<f32>) -> Result<(), Error<G::Error>> {
+        let axis = AxisConfig { gear_ratio, lead, ..self.axis() };
+        if !axis.is_valid() {
+            return Err(Error::InvalidAxis);
+        }
+        self.gear_ratio = gear_ratio;
+        self.lead = lead;
+        Ok(())
+    }
+
+    /// Axis configuration, follows the step division of the driver.
+    pub fn axis(&self) -> AxisConfig {
+        AxisConfig {
+            steps_per_revolution: self.number_of_steps,
+            step_division: self.step_division,
+            gear_ratio: self.gear_ratio,
+            lead: self.lead,
+        }
+    }
+
+    /// Sets the backlash compensation in millimetres or degrees, rounded to microsteps.
+    pub fn set_backlash_units(&mut self, distance: f32) -> Result<(), Error<G::Error>> {
+        if !(distance >= 0.0 && distance.is_finite()) {
+            return Err(Error::InvalidAxis);
+        }
+        let steps = self.axis().to_steps(distance);
+        self.backlash = u32::try_from(steps).map_err(|_| Error::InvalidAxis)?;
+        Ok(())
+    }
+
+    /// Current position in millimetres or degrees.
+    pub fn position_units(&self) -> f32 {
+        self.axis().to_units(self.position)
+    }
+
+    /// Sets the speed of the position moves in mm/s or °/s, see `set_speed`.
+    pub fn set_speed_units(&mut self, speed: f32) -> Result<(), Error<G::Error>> {
+        let steps_per_second = speed * self.full_steps_per_unit();
+        self.set_speed(steps_per_second * 60.0 / self.number_of_steps as f32)
+    }
+
+    /// Sets the acceleration of velocity mode in mm/s² or °/s², see `set_acceleration`.
+    pub fn set_acceleration_units(&mut self, acceleration: f32) -> Result<(), Error<G::Error>> {
+        self.set_acceleration(acceleration * self.full_steps_per_unit())
+    }
+
+    /// Sets the velocity mode target in mm/s or °/s, see `set_target_velocity`.
+    pub fn set_target_velocity_units(&mut self, velocity: f32) -> Result<(), Error<G::Error>> {
+        self.set_target_velocity(velocity * self.full_steps_per_unit())
+    }
+
+    /// Current velocity in mm/s or °/s, see `velocity`.
+    pub fn velocity_units(&self) -> f32 {
+        self.velocity() / self.full_steps_per_unit()
+    }
+
+    /// Sets the travel limits in millimetres or degrees, rounded inwards to microsteps.
+    pub fn set_limits_units(&mut self, limits: Option<(f32, f32)>) -> Result<(), Error<G::Error>> {
+        let limits = match limits {
+            Some((min, max)) if min.is_finite() && max.is_finite() && min <= max => {
+                let steps_per_unit = self.axis().steps_per_unit();
+                let (min, max) = (ceilf(min * steps_per_unit) as i64, floorf(max * steps_per_unit) as i64);
+                if min > max {
+                    return Err(Error::InvalidAxis);
+                }
+                Some((min, max))
+            }
+            Some(_) => return Err(Error::InvalidAxis),
+            None => None,
+        };
+        self.set_limits(limits);
+        Ok(())
+    }
+
+    /// Travel limits in millimetres or degrees.
+    pub fn limits_units(&self) -> Option<(f32, f32)> {
+        let axis = self.axis();
+        self.limits.map(|(min, max)| (axis.to_units(min), axis.to_units(max)))
+    }
+
+    /// Full steps per millimetre or degree, speeds and accelerations count full steps.
+    fn full_steps_per_unit(&self) -> f32 {
+        self.axis().steps_per_unit() / self.step_division as f32
+    }
+
+    /// Moves to `target` (mm or °) with constant acceleration, `max_speed` in mm/s or °/s and
+    /// `acceleration` in mm/s² or °/s².
+    ///
+    /// The target is rounded to the nearest microstep and checked against the limits.
+    pub fn move_to_units(&mut self, target: f32, max_speed: f32, acceleration: f32)
+        -> Result<(), Error<G::Error>> {
+        let target = self.axis().to_steps(target);
+        let delta = target.checked_sub(self.position).ok_or(Error::OutOfRange(target))?;
+        self.move_by_steps_accelerated(delta, max_speed, acceleration)
+    }
+
+    /// Moves by `distance` (mm or °), see `move_to_units`.
+    pub fn move_by_units(&mut self, distance: f32, max_speed: f32, acceleration: f32)
+        -> Result<(), Error<G::Error>> {
+        let delta = self.axis().to_steps(distance);
+        self.move_by_steps_accelerated(delta, max_speed, acceleration)
+    }
+
+    fn move_by_steps_accelerated(&mut self, delta: i64, max_speed: f32, acceleration: f32)
+        -> Result<(), Error<G::Error>> {
+        let steps_per_unit = self.axis().steps_per_unit();
+        let max_speed = max_speed * steps_per_unit;
+        let acceleration = acceleration * steps_per_unit;
+        check_speed::<CHIP, G::Error>(max_speed)?;
+        check_limit(acceleration)?;
+        let steps = u32::try_from(delta.unsigned_abs()).map_err(|_| Error::InvalidProfile)?;
+
+        if !self.prepare_move(delta)? {
+            return Ok(());
+        }
+        self.move_profile(TrapezoidalProfile::new(steps, max_speed, acceleration))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tests::driver;
+
+    #[test]
+    fn lead_screw_steps_per_mm() {
+        // 200 steps, 1:16, 8 mm lead: 3200 microsteps per 8 mm
+        let axis = AxisConfig::lead_screw(200, 16, 8.0);
+        assert_eq!(axis.unit(), Unit::Millimetre);
+        assert_eq!(axis.steps_per_unit(), 400.0);
+        assert_eq!(axis.to_steps(12.5), 5000);
+        assert_eq!(axis.to_steps(-0.001), 0);
+        assert_eq!(axis.to_units(-400), -1.0);
+        assert_eq!(axis.with_gear_ratio(2.0).to_steps(1.0), 800);
+    }
+
+    #[test]
+    fn geared_rotary_axis() {
+        // 3:1 reduction, 200 * 8 * 3 microsteps per output revolution
+        let axis = AxisConfig::rotary(200, 8).with_gear_ratio(3.0);
+        assert_eq!(axis.unit(), Unit::Degree);
+        assert_eq!(axis.to_steps(360.0), 4800);
+        assert_eq!(axis.to_steps(90.0), 1200);
+        assert!((axis.to_units(1) - 0.075).abs() < 1e-6);
+    }
+
+    #[test]
+    fn driver_moves_in_units() {
+        let (mut driver, _, step) = driver();
+        driver.set_transmission(1.0, Some(2.0)).unwrap();
+        assert_eq!(driver.axis(), AxisConfig::lead_screw(200, 1, 2.0));
+
+        driver.move_to_units(5.0, 10.0, 50.0).unwrap();
+        assert_eq!(driver.position(), 500);
+        assert_eq!(driver.position_units(), 5.0);
+        driver.move_by_units(-1.5, 10.0, 50.0).unwrap();
+        assert_eq!(driver.position_units(), 3.5);
+        assert_eq!(step.pulses(), 650);
+
+        driver.set_limits(Some((0, 1000)));
+        assert_eq!(driver.move_to_units(10.5, 10.0, 50.0), Err(Error::OutOfRange(1050)));
+        assert_eq!(driver.move_to_units(1.0, 0.0, 50.0), Err(Error::InvalidSpeed));
+        // Saturates to i64::MAX steps, too far from a negative position
+        driver.set_limits(None);
+        driver.set_position(-10);
+        assert_eq!(driver.move_to_units(1e30, 10.0, 50.0), Err(Error::OutOfRange(i64::MAX)));
+        driver.set_position(350);
+        driver.set_limits(Some((0, 1000)));
+        driver.set_backlash_units(0.051).unwrap();
+        assert_eq!(driver.backlash(), 5);
+        assert_eq!(driver.set_backlash_units(-1.0), Err(Error::InvalidAxis));
+        assert_eq!(driver.set_transmission(0.0, None), Err(Error::InvalidAxis));
+        assert_eq!(driver.set_transmission(1.0, Some(-2.0)), Err(Error::InvalidAxis));
+    }
+
+    #[test]
+    fn speeds_and_limits_in_units() {
+        let (mut driver, _, _) = driver();
+        driver.set_transmission(1.0, Some(2.0)).unwrap();
+        driver.set_step_division(4).unwrap();
+
+        // 100 full steps per mm: 10 mm/s is 1000 steps/s or 300 rpm, whatever the division
+        driver.set_speed_units(10.0).unwrap();
+        assert_eq!(driver.step_interval, 250);
+        driver.set_acceleration_units(50.0).unwrap();
+        assert_eq!(driver.acceleration, 50.0 * 400.0);
+        driver.set_target_velocity_units(-5.0).unwrap();
+        assert_eq!(driver.target_velocity, -5.0 * 400.0);
+        assert_eq!(driver.set_speed_units(0.0), Err(Error::InvalidSpeed));
+
+        driver.set_limits_units(Some((-1.0, 2.345))).unwrap();
+        assert_eq!(driver.limits(), Some((-400, 938)));
+        assert_eq!(driver.limits_units(), Some((-1.0, 2.345)));
+        assert_eq!(driver.set_limits_units(Some((2.0, 1.0))), Err(Error::InvalidAxis));
+        assert_eq!(driver.set_limits_units(Some((0.0, f32::NAN))), Err(Error::InvalidAxis));
+        assert_eq!(driver.set_limits_units(Some((0.001, 0.002))), Err(Error::InvalidAxis));
+        driver.set_limits_units(None).unwrap();
+        assert_eq!(driver.limits_units(), None);
+    }
+}
@@ -0,0 +1,536 @@
+//! Stepper axis commanded over CAN, see the `stepper-can` crate for the frames.
+//!
+//! `poll` is called from the main loop. It answers the commands received since the last
+//! call and runs the current move for `SLICE_US`, so a stop takes effect within a slice.
+//! Moves use the velocity mode of the driver with the travel limit on the far side narrowed
+//! to the target, which makes it ramp down and stop there. Every move, finished or stopped,
+//! ends with a status frame carrying the tag of the command that started it.
+//!
+//! Homing blocks the loop and is acknowledged once done. Replies are queued and go out as
+//! the single transmit buffer of the TWAI frees up.
+
+#![no_std]
+#![deny(missing_debug_implementations)]
+#![deny(unsafe_code)]
+
+use embedded_hal::can::{Can, Frame};
+use embedded_hal::digital::v2::{InputPin, OutputPin};
+use heapless::Deque;
+use stepper_can::{Command, Reject, Reply, Request, Response, State, Status};
+use stepper_driver::{ControlPins, HomingConfig, ModePins, MotorDriver, Params, StepGenerator};
+
+/// Duration of the move between two polls (µs)
+const SLICE_US: u32 = 5_000;
+const REPLY_QUEUE: usize = 8;
+
+#[derive(Debug)]
+pub enum Error<CE, ME> {
+    Can(CE),
+    /// The move failed, the axis reports `State::Fault`
+    Motor(stepper_driver::Error<ME>),
+}
+
+#[derive(Debug)]
+enum Motion {
+    Idle,
+    Moving { target: i64, tag: u8 },
+    Stopping { tag: u8 },
+}
+
+#[derive(Debug)]
+pub struct RemoteStepper<SW> {
+    node: u8,
+    switch: SW,
+    homing: HomingConfig,
+    /// Moves are refused until homed
+    require_homing: bool,
+    homed: bool,
+    /// The last move failed
+    failed: bool,
+    /// Speed of the moves (steps/s), 0 until set
+    speed: f32,
+    motion: Motion,
+    /// Travel limits of the driver outside of moves
+    limits: Option<(i64, i64)>,
+    replies: Deque<Response, REPLY_QUEUE>,
+    /// Replies dropped because the queue was full
+    dropped: u32,
+}
+
+impl<SW> RemoteStepper<SW> {
+    /// Axis at `node` (0-63), homed against `switch`. Moves are refused until the axis is
+    /// homed and a speed is set.
+    pub fn new(node: u8, switch: SW, homing: HomingConfig) -> Result<Self, stepper_can::Error> {
+        if node > stepper_can::MAX_NODE {
+            return Err(stepper_can::Error::InvalidNode(node));
+        }
+        Ok(RemoteStepper {
+            node,
+            switch,
+            homing,
+            require_homing: true,
+            homed: false,
+            failed: false,
+            speed: 0.0,
+            motion: Motion::Idle,
+            limits: None,
+            replies: Deque::new(),
+            dropped: 0,
+        })
+    }
+
+    /// Allows moves before homing, for axes without a home switch.
+    pub fn set_require_homing(&mut self, require: bool) {
+        self.require_homing = require;
+    }
+
+    /// Sets the speed of the moves (steps/s), as a set speed command does.
+    pub fn set_speed(&mut self, steps_per_s: f32) {
+        self.speed = steps_per_s;
+    }
+
+    pub fn is_idle(&self) -> bool {
+        matches!(self.motion, Motion::Idle)
+    }
+
+    pub fn dropped(&self) -> u32 {
+        self.dropped
+    }
+
+    /// Handles the received commands, runs the current move for a slice and sends the
+    /// replies.
+    pub fn poll<C, G, CHIP, EN, MS, CTL>(&mut self, can: &mut C, motor: &mut MotorDriver<G, CHIP, EN, MS, CTL>)
+        -> Result<(), Error<C::Error, G::Error>>
+    where
+        C: Can,
+        G: StepGenerator,
+        CHIP: Params,
+        EN: OutputPin<Error = G::Error>,
+        MS: ModePins<Error = G::Error>,
+        CTL: ControlPins<Error = G::Error>,
+        SW: InputPin<Error = G::Error>,
+    {
+        loop {
+            match can.receive() {
+                Ok(frame) => self.receive(&frame, motor),
+                Err(nb::Error::WouldBlock) => break,
+                Err(nb::Error::Other(e)) => return Err(Error::Can(e)),
+            }
+        }
+        // The status of a failed move goes out before the error is returned
+        let result = self.run(motor);
+        self.transmit(can).map_err(Error::Can)?;
+        result.map_err(Error::Motor)
+    }
+
+    fn receive<F, G, CHIP, EN, MS, CTL>(&mut self, frame: &F, motor: &mut MotorDriver<G, CHIP, EN, MS, CTL>)
+    where
+        F: Frame,
+        G: StepGenerator,
+        CHIP: Params,
+        EN: OutputPin<Error = G::Error>,
+        MS: ModePins<Error = G::Error>,
+        CTL: ControlPins<Error = G::Error>,
+        SW: InputPin<Error = G::Error>,
+    {
+        // Frames of other nodes and protocols share the acceptance filter
+        if stepper_can::command_node(frame) != Some(self.node) {
+            return;
+        }
+        let request = match Request::decode(self.node, frame.data()) {
+            Ok(request) => request,
+            Err(e) => return self.reply(Response::malformed(self.node, frame.data(), e)),
+        };
+        let reply = match (request.command, self.execute(&request, motor)) {
+            (Command::Query, Ok(())) => Reply::Status(self.status(motor)),
+            (command, result) => Reply::Ack { opcode: command.opcode(), result },
+        };
+        self.reply(Response { node: self.node, tag: request.tag, reply });
+    }
+
+    /// Validates and starts `request`.
+    fn execute<G, CHIP, EN, MS, CTL>(&mut self, request: &Request, motor: &mut MotorDriver<G, CHIP, EN, MS, CTL>)
+        -> Result<(), Reject>
+    where
+        G: StepGenerator,
+        CHIP: Params,
+        EN: OutputPin<Error = G::Error>,
+        MS: ModePins<Error = G::Error>,
+        CTL: ControlPins<Error = G::Error>,
+        SW: InputPin<Error = G::Error>,
+    {
+        match request.command {
+            Command::Query => Ok(()),
+            Command::Stop => {
+                // The status at the end still answers the command that started the move
+                if let Motion::Moving { tag, .. } = self.motion {
+                    motor.set_target_velocity(0.0).map_err(|_| Reject::Fault)?;
+                    self.motion = Motion::Stopping { tag };
+                }
+                Ok(())
+            }
+            _ if !self.is_idle() => Err(Reject::Busy),
+            Command::SetSpeed { steps_per_s } => {
+                if steps_per_s == 0 {
+                    return Err(Reject::InvalidSpeed);
+                }
+                self.speed = steps_per_s as f32;
+                Ok(())
+            }
+            Command::SetAcceleration { steps_per_s2 } => {
+                motor.set_acceleration(steps_per_s2 as f32).map_err(|_| Reject::InvalidAcceleration)
+            }
+            Command::Home => {
+                if motor.is_faulted().unwrap_or(true) {
+                    return Err(Reject::Fault);
+                }
+                self.homed = false;
+                motor.home(&mut self.switch, &self.homing).map_err(|e| match e {
+                    stepper_driver::Error::Fault => Reject::Fault,
+                    _ => Reject::HomingFailed,
+                })?;
+                self.homed = true;
+                Ok(())
+            }
+            Command::MoveTo { target } => self.start_move(i64::from(target), request.tag, motor),
+        }
+    }
+
+    fn start_move<G, CHIP, EN, MS, CTL>(&mut self, target: i64, tag: u8, motor: &mut MotorDriver<G, CHIP, EN, MS, CTL>)
+        -> Result<(), Reject>
+    where
+        G: StepGenerator,
+        CHIP: Params,
+        EN: OutputPin<Error = G::Error>,
+        MS: ModePins<Error = G::Error>,
+        CTL: ControlPins<Error = G::Error>,
+    {
+        if self.require_homing && !self.homed {
+            return Err(Reject::NotHomed);
+        }
+        let limits = motor.limits();
+        let (min, max) = limits.unwrap_or((i64::MIN, i64::MAX));
+        if target < min || target > max {
+            return Err(Reject::OutOfRange);
+        }
+        if motor.is_faulted().unwrap_or(true) {
+            return Err(Reject::Fault);
+        }
+        if self.speed == 0.0 {
+            return Err(Reject::InvalidSpeed);
+        }
+
+        let clockwise = target >= motor.position();
+        let velocity = if clockwise { self.speed } else { -self.speed };
+        motor.set_target_velocity(velocity).map_err(|e| match e {
+            // No acceleration set yet
+            stepper_driver::Error::InvalidProfile => Reject::InvalidAcceleration,
+            _ => Reject::InvalidSpeed,
+        })?;
+        self.limits = limits;
+        motor.set_limits(Some(if clockwise { (min, target) } else { (target, max) }));
+        self.failed = false;
+        self.motion = Motion::Moving { target, tag };
+        Ok(())
+    }
+
+    /// Runs the current move for a slice, finishes it once the motor stands still.
+    fn run<G, CHIP, EN, MS, CTL>(&mut self, motor: &mut MotorDriver<G, CHIP, EN, MS, CTL>)
+        -> Result<(), stepper_driver::Error<G::Error>>
+    where
+        G: StepGenerator,
+        CHIP: Params,
+        EN: OutputPin<Error = G::Error>,
+        MS: ModePins<Error = G::Error>,
+        CTL: ControlPins<Error = G::Error>,
+    {
+        let tag = match self.motion {
+            Motion::Idle => return Ok(()),
+            Motion::Moving { tag, .. } | Motion::Stopping { tag } => tag,
+        };
+        let result = motor.run_velocity(SLICE_US).and_then(|()| {
+            if motor.velocity() != 0.0 {
+                return Ok(false);
+            }
+            // The velocity mode stops up to a step short of the limit
+            if let Motion::Moving { target, .. } = self.motion {
+                motor.move_to(target)?;
+            }
+            Ok(true)
+        });
+        if let Ok(false) = result {
+            return Ok(());
+        }
+
+        self.failed = result.is_err();
+        // Cannot fail, the acceleration was set to start the move
+        motor.set_target_velocity(0.0).ok();
+        motor.set_limits(self.limits);
+        self.motion = Motion::Idle;
+        let status = self.status(motor);
+        self.reply(Response { node: self.node, tag, reply: Reply::Status(status) });
+        result.map(|_| ())
+    }
+
+    fn status<G, CHIP, EN, MS, CTL>(&self, motor: &MotorDriver<G, CHIP, EN, MS, CTL>) -> Status
+    where
+        G: StepGenerator,
+        CHIP: Params,
+        EN: OutputPin<Error = G::Error>,
+        MS: ModePins<Error = G::Error>,
+        CTL: ControlPins<Error = G::Error>,
+    {
+        let state = match self.motion {
+            _ if self.failed || motor.is_faulted().unwrap_or(true) => State::Fault,
+            Motion::Idle => State::Idle,
+            Motion::Moving { .. } => State::Moving,
+            Motion::Stopping { .. } => State::Stopping,
+        };
+        Status {
+            position: motor.position().clamp(i32::MIN.into(), i32::MAX.into()) as i32,
+            state,
+            homed: self.homed,
+            enabled: motor.is_enabled(),
+        }
+    }
+
+    /// Queues `response`, dropping the oldest reply when the queue is full.
+    fn reply(&mut self, response: Response) {
+        if self.replies.is_full() {
+            self.replies.pop_front();
+            self.dropped += 1;
+        }
+        self.replies.push_back(response).ok();
+    }
+
+    /// Sends queued replies until the transmit buffer is busy.
+    fn transmit<C: Can>(&mut self, can: &mut C) -> Result<(), C::Error> {
+        while let Some(response) = self.replies.front() {
+            // The node was checked in `new`
+            let frame: C::Frame = response.to_frame().unwrap();
+            match can.transmit(&frame) {
+                Ok(_) => {
+                    self.replies.pop_front();
+                }
+                Err(nb::Error::WouldBlock) => break,
+                Err(nb::Error::Other(e)) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use can_test_frame::TestFrame;
+    use core::cell::RefCell;
+    use core::convert::Infallible;
+    use embedded_hal::blocking::delay::DelayUs;
+    use embedded_hal::can::ErrorKind;
+    use std::collections::VecDeque;
+    use std::rc::Rc;
+    use std::vec::Vec;
+    use stepper_can::{Command, Request};
+    use stepper_driver::{a4988, GpioStepGenerator};
+
+    const NODE: u8 = 2;
+
+    /// Bus with the frames sent to the axis and those it sent back.
+    #[derive(Debug, Default)]
+    struct Bus {
+        rx: VecDeque<TestFrame>,
+        tx: Vec<TestFrame>,
+    }
+
+    impl Bus {
+        fn send(&mut self, tag: u8, command: Command) {
+            self.rx.push_back(Request { node: NODE, tag, command }.to_frame().unwrap());
+        }
+
+        fn replies(&mut self) -> Vec<Response> {
+            self.tx.drain(..).map(|frame| Response::from_frame(&frame).unwrap()).collect()
+        }
+    }
+
+    impl Can for Bus {
+        type Frame = TestFrame;
+        type Error = ErrorKind;
+
+        fn transmit(&mut self, frame: &TestFrame) -> nb::Result<Option<TestFrame>, ErrorKind> {
+            self.tx.push(*frame);
+            Ok(None)
+        }
+
+        fn receive(&mut self) -> nb::Result<TestFrame, ErrorKind> {
+            self.rx.pop_front().ok_or(nb::Error::WouldBlock)
+        }
+    }
+
+    /// Position counted from the DIR and STEP pins, the home switch is pressed at -100 and
+    /// below.
+    #[derive(Debug, Default)]
+    struct Sim {
+        position: i64,
+        clockwise: bool,
+        step_high: bool,
+    }
+
+    #[derive(Debug, Clone)]
+    struct Pin(Rc<RefCell<Sim>>, bool);
+
+    impl OutputPin for Pin {
+        type Error = Infallible;
+
+        fn set_low(&mut self) -> Result<(), Infallible> {
+            let mut sim = self.0.borrow_mut();
+            if self.1 {
+                sim.clockwise = true;
+            } else {
+                sim.step_high = false;
+            }
+            Ok(())
+        }
+
+        fn set_high(&mut self) -> Result<(), Infallible> {
+            let mut sim = self.0.borrow_mut();
+            if self.1 {
+                sim.clockwise = false;
+            } else if !sim.step_high {
+                sim.step_high = true;
+                sim.position += if sim.clockwise { 1 } else { -1 };
+            }
+            Ok(())
+        }
+    }
+
+    /// Active low switch
+    impl InputPin for Pin {
+        type Error = Infallible;
+
+        fn is_high(&self) -> Result<bool, Infallible> {
+            self.is_low().map(|low| !low)
+        }
+
+        fn is_low(&self) -> Result<bool, Infallible> {
+            Ok(self.0.borrow().position <= -100)
+        }
+    }
+
+    #[derive(Debug)]
+    struct NoDelay;
+
+    impl DelayUs<u32> for NoDelay {
+        fn delay_us(&mut self, _us: u32) {}
+    }
+
+    type Motor = MotorDriver<GpioStepGenerator<NoDelay, Pin, Pin>, a4988>;
+
+    /// Axis limited to -1000..=5000 at 1:1, not homed, and its motor.
+    fn setup() -> (RemoteStepper<Pin>, Motor, Bus) {
+        let sim = Rc::new(RefCell::new(Sim::default()));
+        let mut motor =
+            MotorDriver::a4988(NoDelay, Pin(sim.clone(), true), Pin(sim.clone(), false), 200, 1, 60.0).unwrap();
+        motor.set_limits(Some((-1000, 5000)));
+        let axis = RemoteStepper::new(NODE, Pin(sim, false), HomingConfig::default()).unwrap();
+        (axis, motor, Bus::default())
+    }
+
+    fn ack(tag: u8, command: Command, result: Result<(), Reject>) -> Response {
+        Response { node: NODE, tag, reply: Reply::Ack { opcode: command.opcode(), result } }
+    }
+
+    /// Homed axis moving at 1000 steps/s with 2000 steps/s².
+    fn ready() -> (RemoteStepper<Pin>, Motor, Bus) {
+        let (mut axis, mut motor, mut bus) = setup();
+        bus.send(1, Command::Home);
+        bus.send(2, Command::SetSpeed { steps_per_s: 1000 });
+        bus.send(3, Command::SetAcceleration { steps_per_s2: 2000 });
+        axis.poll(&mut bus, &mut motor).unwrap();
+        assert_eq!(bus.replies().len(), 3);
+        (axis, motor, bus)
+    }
+
+    /// Polls until the move ends and returns its status.
+    fn finish(axis: &mut RemoteStepper<Pin>, motor: &mut Motor, bus: &mut Bus) -> Response {
+        for _ in 0..10_000 {
+            axis.poll(bus, motor).unwrap();
+            if axis.is_idle() {
+                let mut replies = bus.replies();
+                assert_eq!(replies.len(), 1);
+                return replies.pop().unwrap();
+            }
+        }
+        panic!("the move did not end");
+    }
+
+    #[test]
+    fn refuses_moves_until_homed() {
+        let (mut axis, mut motor, mut bus) = setup();
+        let move_to = Command::MoveTo { target: 100 };
+        bus.send(1, Command::SetSpeed { steps_per_s: 1000 });
+        bus.send(2, move_to);
+        bus.send(3, Command::Home);
+        bus.send(4, Command::Query);
+        axis.poll(&mut bus, &mut motor).unwrap();
+
+        let replies = bus.replies();
+        assert_eq!(replies[1], ack(2, move_to, Err(Reject::NotHomed)));
+        assert_eq!(replies[2], ack(3, Command::Home, Ok(())));
+        let status = Status { position: 0, state: State::Idle, homed: true, enabled: true };
+        assert_eq!(replies[3], Response { node: NODE, tag: 4, reply: Reply::Status(status) });
+        assert!(axis.is_idle());
+    }
+
+    #[test]
+    fn moves_within_narrowed_limits() {
+        let (mut axis, mut motor, mut bus) = ready();
+        let move_to = Command::MoveTo { target: 1200 };
+        bus.send(7, move_to);
+        bus.send(8, Command::MoveTo { target: 6000 });
+        axis.poll(&mut bus, &mut motor).unwrap();
+        assert_eq!(bus.replies(), [ack(7, move_to, Ok(())), ack(8, Command::MoveTo { target: 6000 }, Err(Reject::Busy))]);
+        assert_eq!(motor.limits(), Some((-1000, 1200)));
+
+        let status = Status { position: 1200, state: State::Idle, homed: true, enabled: true };
+        assert_eq!(finish(&mut axis, &mut motor, &mut bus), Response { node: NODE, tag: 7, reply: Reply::Status(status) });
+        assert_eq!(motor.position(), 1200);
+        assert_eq!(motor.limits(), Some((-1000, 5000)));
+
+        bus.send(9, Command::MoveTo { target: 6000 });
+        bus.send(10, Command::MoveTo { target: -300 });
+        axis.poll(&mut bus, &mut motor).unwrap();
+        let replies = bus.replies();
+        assert_eq!(replies[0], ack(9, Command::MoveTo { target: 6000 }, Err(Reject::OutOfRange)));
+        assert_eq!(replies[1], ack(10, Command::MoveTo { target: -300 }, Ok(())));
+        assert_eq!(motor.limits(), Some((-300, 5000)));
+        finish(&mut axis, &mut motor, &mut bus);
+        assert_eq!(motor.position(), -300);
+        assert_eq!(motor.limits(), Some((-1000, 5000)));
+    }
+
+    #[test]
+    fn stop_ends_with_the_tag_of_the_move() {
+        let (mut axis, mut motor, mut bus) = ready();
+        bus.send(7, Command::MoveTo { target: 4000 });
+        for _ in 0..20 {
+            axis.poll(&mut bus, &mut motor).unwrap();
+        }
+        bus.send(8, Command::Stop);
+        axis.poll(&mut bus, &mut motor).unwrap();
+        assert_eq!(bus.replies()[1..], [ack(8, Command::Stop, Ok(()))]);
+
+        let response = finish(&mut axis, &mut motor, &mut bus);
+        assert_eq!(response.tag, 7);
+        match response.reply {
+            Reply::Status(status) => {
+                assert_eq!(status.state, State::Idle);
+                assert!(status.position > 0 && status.position < 4000, "stopped at {}", status.position);
+            }
+            reply => panic!("unexpected {:?}", reply),
+        }
+        assert_eq!(motor.limits(), Some((-1000, 5000)));
+    }
+}
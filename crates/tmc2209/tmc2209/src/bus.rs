@@ -0,0 +1,222 @@
+//! Several drivers on one UART.
+//!
+//! Up to four TMC2209 share the single-wire line, each strapped to its own slave address
+//! with MS1/MS2. `Bus` owns the UART and hands out a `Tmc2209` per address, whose port
+//! borrows the UART for each byte. Requests are sent one at a time and a reply follows its
+//! request, so the handles can be used in turns from one context.
+//!
+//! With the usual wiring, TX through a 1 kΩ resistor and RX straight to PDN_UART, every byte
+//! sent is received back. The bus counts these echo bytes and drops them before the next
+//! reply, and while flushing so that writes without reply do not fill the receive FIFO.
+//! Anything else left in the FIFO, like the late reply of a driver that timed out, is
+//! discarded before the next read request, so it cannot shift the replies of the others.
+
+use core::cell::RefCell;
+
+use embedded_hal::serial::{Read, Write};
+
+use crate::{Error, Tmc2209};
+
+/// One UART shared by drivers at different slave addresses.
+#[derive(Debug)]
+pub struct Bus<U> {
+    inner: RefCell<Inner<U>>,
+}
+
+#[derive(Debug)]
+struct Inner<U> {
+    uart: U,
+    /// Sent bytes are received back
+    echo: bool,
+    /// Echo bytes still to be dropped
+    pending_echo: usize,
+    /// Addresses with a handle, one bit each
+    claimed: u8,
+}
+
+impl<U, E> Bus<U>
+where
+    U: Read<u8, Error = E> + Write<u8, Error = E>,
+{
+    /// Bus on `uart`, `echo` if it receives what it sends.
+    pub fn new(uart: U, echo: bool) -> Self {
+        Bus { inner: RefCell::new(Inner { uart, echo, pending_echo: 0, claimed: 0 }) }
+    }
+
+    /// Driver at slave `address` (0-3). Each address has one handle at a time, dropping it
+    /// frees the address.
+    pub fn driver(&self, address: u8) -> Result<Tmc2209<BusPort<'_, U>>, Error<E>> {
+        if address > 3 {
+            return Err(Error::InvalidAddress(address));
+        }
+        let mut inner = self.inner.borrow_mut();
+        if inner.claimed & (1 << address) != 0 {
+            return Err(Error::AddressInUse(address));
+        }
+        inner.claimed |= 1 << address;
+        Tmc2209::new(BusPort { bus: self, address }, address)
+    }
+
+    /// Releases the UART. All handles have been dropped by then.
+    pub fn release(self) -> U {
+        self.inner.into_inner().uart
+    }
+}
+
+/// The share of the bus UART of one driver.
+#[derive(Debug)]
+pub struct BusPort<'a, U> {
+    bus: &'a Bus<U>,
+    address: u8,
+}
+
+impl<U> Drop for BusPort<'_, U> {
+    fn drop(&mut self) {
+        self.bus.inner.borrow_mut().claimed &= !(1 << self.address);
+    }
+}
+
+impl<U, E> Inner<U>
+where
+    U: Read<u8, Error = E>,
+{
+    /// Reads the echo bytes that have arrived.
+    fn drop_echo(&mut self) -> nb::Result<(), E> {
+        while self.pending_echo > 0 {
+            self.uart.read()?;
+            self.pending_echo -= 1;
+        }
+        Ok(())
+    }
+}
+
+impl<U, E> Write<u8> for BusPort<'_, U>
+where
+    U: Read<u8, Error = E> + Write<u8, Error = E>,
+{
+    type Error = E;
+
+    fn write(&mut self, byte: u8) -> nb::Result<(), E> {
+        let mut inner = self.bus.inner.borrow_mut();
+        inner.uart.write(byte)?;
+        if inner.echo {
+            inner.pending_echo += 1;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> nb::Result<(), E> {
+        let mut inner = self.bus.inner.borrow_mut();
+        inner.uart.flush()?;
+        // The echo of the last byte arrives with its stop bit, the rest is dropped by `read`
+        match inner.drop_echo() {
+            Err(nb::Error::Other(e)) => Err(nb::Error::Other(e)),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl<U, E> Read<u8> for BusPort<'_, U>
+where
+    U: Read<u8, Error = E> + Write<u8, Error = E>,
+{
+    type Error = E;
+
+    fn read(&mut self) -> nb::Result<u8, E> {
+        let mut inner = self.bus.inner.borrow_mut();
+        inner.drop_echo()?;
+        inner.uart.read()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::MockUart;
+    use crate::{Gconf, IholdIrun};
+    use crate::{ChopperMode, MotorCurrent};
+
+    fn bus() -> Bus<MockUart> {
+        let mut uart = MockUart::new();
+        uart.echo = true;
+        uart.present = vec![0, 1];
+        Bus::new(uart, true)
+    }
+
+    #[test]
+    fn drivers_share_the_uart() {
+        let bus = bus();
+        let mut x = bus.driver(0).unwrap();
+        let mut y = bus.driver(1).unwrap();
+        x.set_current(&MotorCurrent::default()).unwrap();
+        y.set_chopper_mode(ChopperMode::SpreadCycle).unwrap();
+        // Writes only, the echo is dropped while flushing
+        for irun in 0..32 {
+            x.write(*IholdIrun::default().set_irun(irun)).unwrap();
+        }
+        assert!(bus.inner.borrow().uart.rx.is_empty());
+        assert!(!x.read::<Gconf>().unwrap().en_spreadcycle());
+        assert!(y.read::<Gconf>().unwrap().en_spreadcycle());
+        drop((x, y));
+
+        let uart = bus.release();
+        assert_eq!(uart.registers[0][0x10], 0x0000_1f00);
+        assert_eq!(uart.registers[1][0x10], 0);
+    }
+
+    #[test]
+    fn drops_late_echo() {
+        let bus = bus();
+        let mut x = bus.driver(0).unwrap();
+        x.write(Gconf(0x1c0)).unwrap();
+        // Echo bytes that arrive after the flush are dropped before the reply
+        bus.inner.borrow_mut().pending_echo = 3;
+        bus.inner.borrow_mut().uart.rx.extend([0x00, 0x11, 0x22]);
+        assert_eq!(x.read::<Gconf>(), Ok(Gconf(0x1c0)));
+    }
+
+    #[test]
+    fn discards_late_replies() {
+        let bus = bus();
+        let mut x = bus.driver(0).unwrap();
+        let mut y = bus.driver(1).unwrap();
+        x.write(Gconf(0x1c0)).unwrap();
+
+        // The reply of a slow driver arrives after the timeout
+        let mut z = bus.driver(2).unwrap();
+        z.set_timeout(10);
+        assert_eq!(z.read::<Gconf>(), Err(Error::Timeout));
+        bus.inner.borrow_mut().uart.rx.extend([0x05, 0xff, 0x00, 0, 0, 0, 0, 0x42]);
+        assert_eq!(x.read::<Gconf>(), Ok(Gconf(0x1c0)));
+
+        // And the rest of a partial reply
+        bus.inner.borrow_mut().uart.rx.extend([0x00, 0x00, 0x00]);
+        assert_eq!(y.read::<Gconf>(), Ok(Gconf(0)));
+        assert_eq!(x.read::<Gconf>(), Ok(Gconf(0x1c0)));
+    }
+
+    #[test]
+    fn claims_addresses() {
+        let bus = bus();
+        let x = bus.driver(0).unwrap();
+        assert_eq!(bus.driver(0).err(), Some(Error::AddressInUse(0)));
+        assert_eq!(bus.driver(4).err(), Some(Error::InvalidAddress(4)));
+        drop(x);
+        let mut x = bus.driver(0).unwrap();
+
+        // An absent driver times out, the others keep working
+        let mut z = bus.driver(2).unwrap();
+        z.set_timeout(10);
+        assert_eq!(z.read::<Gconf>(), Err(Error::Timeout));
+        assert_eq!(x.read::<Gconf>(), Ok(Gconf(0)));
+    }
+
+    #[test]
+    fn works_without_echo() {
+        let mut uart = MockUart::new();
+        uart.registers[3][0x00] = 0x40;
+        let bus = Bus::new(uart, false);
+        let mut driver = bus.driver(3).unwrap();
+        assert_eq!(driver.read::<Gconf>(), Ok(Gconf(0x40)));
+    }
+}
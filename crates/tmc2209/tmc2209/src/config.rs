@@ -0,0 +1,306 @@
+//! Motor level configuration: currents in mA, chopper mode, microstep resolution and driver
+//! diagnostics, computed into the registers.
+
+use core::f32::consts::SQRT_2;
+
+use embedded_hal::serial::{Read, Write};
+
+use crate::{Chopconf, DrvStatus, Error, Gconf, IholdIrun, Tmc2209, Tpwmthrs};
+
+/// Internal clock (Hz), the unit of TSTEP
+pub const FCLK: f32 = 12_000_000.0;
+
+/// Full scale sense resistor voltage (V) with vsense off and on
+const VFS: f32 = 0.325;
+const VFS_HIGH_SENSITIVITY: f32 = 0.180;
+
+/// Resistance (Ω) added to the sense resistor by the internal wiring
+const RSENSE_OFFSET: f32 = 0.02;
+
+/// Run and hold current (RMS).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MotorCurrent {
+    /// Sense resistor (Ω), 0.11 on most modules
+    pub rsense: f32,
+    /// Current while moving (mA)
+    pub run: u16,
+    /// Current at standstill (mA), 0 for freewheeling
+    pub hold: u16,
+    /// Time to ramp down to the hold current, in 2^18 clocks per current step (0-15)
+    pub hold_delay: u8,
+}
+
+impl Default for MotorCurrent {
+    fn default() -> Self {
+        MotorCurrent { rsense: 0.11, run: 800, hold: 400, hold_delay: 10 }
+    }
+}
+
+impl MotorCurrent {
+    /// Returns CHOPCONF.vsense and IHOLD_IRUN for these currents.
+    ///
+    /// vsense is switched on for run currents that would use less than half of the current
+    /// scale otherwise, which gives a finer resolution. Currents above the maximum for the
+    /// sense resistor are limited to it.
+    pub fn registers<E>(&self) -> Result<(bool, IholdIrun), Error<E>> {
+        if !(self.rsense > 0.0 && self.rsense.is_finite()) {
+            return Err(Error::InvalidParameter);
+        }
+        let mut vsense = false;
+        if current_scale(self.run, self.rsense, vsense) < 16 {
+            vsense = true;
+        }
+        let mut ihold_irun = IholdIrun::default();
+        ihold_irun
+            .set_irun(current_scale(self.run, self.rsense, vsense))
+            .set_ihold(current_scale(self.hold, self.rsense, vsense))
+            .set_iholddelay(self.hold_delay);
+        Ok((vsense, ihold_irun))
+    }
+}
+
+/// Current scale (0-31) for `current` (mA RMS), rounded down.
+fn current_scale(current: u16, rsense: f32, vsense: bool) -> u8 {
+    let vfs = if vsense { VFS_HIGH_SENSITIVITY } else { VFS };
+    let scale = f32::from(current) / 1000.0 * 32.0 * SQRT_2 * (rsense + RSENSE_OFFSET) / vfs - 1.0;
+    scale.clamp(0.0, 31.0) as u8
+}
+
+/// RMS current (mA) of current scale `cs` (0-31).
+pub fn rms_current(cs: u8, rsense: f32, vsense: bool) -> f32 {
+    let vfs = if vsense { VFS_HIGH_SENSITIVITY } else { VFS };
+    f32::from(cs.min(31) + 1) / 32.0 * vfs / (rsense + RSENSE_OFFSET) / SQRT_2 * 1000.0
+}
+
+/// TSTEP at `velocity` (microsteps/s) with `microsteps` per full step.
+///
+/// TSTEP is the time between two 1/256 microsteps in clocks, so it is lower at higher speeds.
+pub fn tstep(velocity: f32, microsteps: u16) -> u32 {
+    let tstep = FCLK * f32::from(microsteps) / (256.0 * velocity);
+    tstep.min(0xfffff as f32) as u32
+}
+
+/// Chopper mode
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChopperMode {
+    /// Quiet voltage PWM, needed for StallGuard
+    StealthChop,
+    /// Current controlled chopper, more torque at high speed
+    SpreadCycle,
+    /// StealthChop below `velocity` (microsteps/s), SpreadCycle above
+    Hybrid { velocity: f32 },
+}
+
+/// Temperature warnings of DRV_STATUS
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overtemperature {
+    None,
+    /// Above the prewarning threshold (120 °C), reduce the current
+    Prewarning,
+    /// Above 150 °C, the outputs are off until it cools down
+    Shutdown,
+}
+
+/// Driver health read from DRV_STATUS.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Diagnostics {
+    pub overtemperature: Overtemperature,
+    /// Open load on coil A and B. Only meaningful while moving slowly in SpreadCycle, fast
+    /// moves and StealthChop can report it without a fault.
+    pub open_load: [bool; 2],
+    /// Short to ground or low side short on coil A and B, the outputs are off
+    pub short: [bool; 2],
+    /// Actual current scale (0-31)
+    pub current_scale: u8,
+    /// Running in StealthChop
+    pub stealth: bool,
+    /// Motor standing still
+    pub standstill: bool,
+}
+
+impl Diagnostics {
+    /// True without overtemperature shutdown and shorts.
+    pub fn is_ok(&self) -> bool {
+        self.overtemperature != Overtemperature::Shutdown && self.short == [false, false]
+    }
+}
+
+impl From<DrvStatus> for Diagnostics {
+    fn from(status: DrvStatus) -> Self {
+        let overtemperature = if status.ot() {
+            Overtemperature::Shutdown
+        } else if status.otpw() {
+            Overtemperature::Prewarning
+        } else {
+            Overtemperature::None
+        };
+        Diagnostics {
+            overtemperature,
+            open_load: [status.ola(), status.olb()],
+            short: [status.s2ga() || status.s2vsa(), status.s2gb() || status.s2vsb()],
+            current_scale: status.cs_actual(),
+            stealth: status.stealth(),
+            standstill: status.stst(),
+        }
+    }
+}
+
+impl<U, E> Tmc2209<U>
+where
+    U: Read<u8, Error = E> + Write<u8, Error = E>,
+{
+    /// Sets the run and hold current, see `MotorCurrent::registers`. Returns the register
+    /// written, `rms_current` gives the actual currents.
+    pub fn set_current(&mut self, current: &MotorCurrent) -> Result<IholdIrun, Error<E>> {
+        let (vsense, ihold_irun) = current.registers()?;
+        self.modify(|chopconf: &mut Chopconf| {
+            chopconf.set_vsense(vsense);
+        })?;
+        self.write(ihold_irun)?;
+        Ok(ihold_irun)
+    }
+
+    /// Sets the microstep resolution (1-256, a power of two) over UART instead of MS1/MS2.
+    pub fn set_microsteps(&mut self, microsteps: u16) -> Result<(), Error<E>> {
+        if !microsteps.is_power_of_two() || microsteps > 256 {
+            return Err(Error::InvalidMicrosteps(microsteps));
+        }
+        let mres = 8 - microsteps.trailing_zeros() as u8;
+        self.modify(|gconf: &mut Gconf| {
+            gconf.set_mstep_reg_select(true);
+        })?;
+        self.modify(|chopconf: &mut Chopconf| {
+            chopconf.set_mres(mres);
+        })?;
+        Ok(())
+    }
+
+    /// Reads the microstep resolution.
+    pub fn microsteps(&mut self) -> Result<u16, Error<E>> {
+        let chopconf: Chopconf = self.read()?;
+        Ok(256 >> chopconf.mres().min(8))
+    }
+
+    /// Switches between StealthChop and SpreadCycle.
+    ///
+    /// The hybrid threshold is converted to TPWMTHRS with the current microstep resolution.
+    pub fn set_chopper_mode(&mut self, mode: ChopperMode) -> Result<(), Error<E>> {
+        let tpwmthrs = match mode {
+            ChopperMode::Hybrid { velocity } => {
+                if !(velocity > 0.0 && velocity.is_finite()) {
+                    return Err(Error::InvalidParameter);
+                }
+                tstep(velocity, self.microsteps()?)
+            }
+            _ => 0,
+        };
+        self.write(*Tpwmthrs::default().set_tpwmthrs(tpwmthrs))?;
+        self.modify(|gconf: &mut Gconf| {
+            gconf.set_en_spreadcycle(mode == ChopperMode::SpreadCycle);
+        })?;
+        Ok(())
+    }
+
+    /// Reads DRV_STATUS.
+    pub fn diagnostics(&mut self) -> Result<Diagnostics, Error<E>> {
+        let status: DrvStatus = self.read()?;
+        Ok(status.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::MockUart;
+    use crate::Register;
+
+    #[test]
+    fn computes_current_scale() {
+        // 800 mA would be CS 13 with 0.325 V, vsense doubles the resolution
+        let (vsense, ihold_irun) = MotorCurrent::default().registers::<()>().unwrap();
+        assert!(vsense);
+        assert_eq!((ihold_irun.irun(), ihold_irun.ihold(), ihold_irun.iholddelay()), (25, 12, 10));
+        let actual = rms_current(25, 0.11, true);
+        assert!(actual <= 800.0 && actual > 770.0);
+
+        let current = MotorCurrent { run: 1700, hold: 0, ..MotorCurrent::default() };
+        let (vsense, ihold_irun) = current.registers::<()>().unwrap();
+        assert!(!vsense);
+        assert_eq!((ihold_irun.irun(), ihold_irun.ihold()), (29, 0));
+
+        // Limited to full scale
+        let current = MotorCurrent { run: 5000, ..MotorCurrent::default() };
+        assert_eq!(current.registers::<()>().unwrap().1.irun(), 31);
+        let current = MotorCurrent { rsense: 0.0, ..MotorCurrent::default() };
+        assert_eq!(current.registers::<()>(), Err(Error::InvalidParameter));
+    }
+
+    #[test]
+    fn sets_current() {
+        let mut uart = MockUart::new();
+        uart.registers[0][0x6c] = 0x1000_0053;
+        let mut driver = Tmc2209::new(uart, 0).unwrap();
+        driver.set_current(&MotorCurrent::default()).unwrap();
+        let uart = driver.release();
+        assert_eq!(uart.registers[0][0x10], 0x000a_190c);
+        assert_eq!(uart.registers[0][0x6c], 0x1002_0053);
+    }
+
+    #[test]
+    fn sets_microsteps() {
+        let mut uart = MockUart::new();
+        uart.registers[0][0x6c] = 0x1000_0053;
+        let mut driver = Tmc2209::new(uart, 0).unwrap();
+        driver.set_microsteps(16).unwrap();
+        assert_eq!(driver.microsteps(), Ok(16));
+        driver.set_microsteps(1).unwrap();
+        assert_eq!(driver.microsteps(), Ok(1));
+        assert_eq!(driver.set_microsteps(3), Err(Error::InvalidMicrosteps(3)));
+        assert_eq!(driver.set_microsteps(512), Err(Error::InvalidMicrosteps(512)));
+
+        let uart = driver.release();
+        assert_eq!(uart.registers[0][0x6c], 0x1800_0053);
+        assert!(Gconf(uart.registers[0][0x00]).mstep_reg_select());
+    }
+
+    #[test]
+    fn switches_chopper_mode() {
+        let mut uart = MockUart::new();
+        uart.registers[0][0x6c] = Chopconf(0x1000_0053).set_mres(4).bits();
+        let mut driver = Tmc2209::new(uart, 0).unwrap();
+
+        driver.set_chopper_mode(ChopperMode::SpreadCycle).unwrap();
+        assert!(driver.read::<Gconf>().unwrap().en_spreadcycle());
+
+        // 1 rev/s at 1/16 microsteps: 12 MHz / 51200 Hz
+        driver.set_chopper_mode(ChopperMode::Hybrid { velocity: 3200.0 }).unwrap();
+        assert!(!driver.read::<Gconf>().unwrap().en_spreadcycle());
+        assert_eq!(driver.set_chopper_mode(ChopperMode::Hybrid { velocity: 0.0 }), Err(Error::InvalidParameter));
+        let mut uart = driver.release();
+        assert_eq!(uart.registers[0][0x13], 234);
+
+        uart.registers[0][0x13] = 0;
+        let mut driver = Tmc2209::new(uart, 0).unwrap();
+        driver.set_chopper_mode(ChopperMode::StealthChop).unwrap();
+        assert_eq!(driver.release().registers[0][0x13], 0);
+        assert_eq!(tstep(1.0, 256), 0xfffff);
+    }
+
+    #[test]
+    fn reads_diagnostics() {
+        let mut uart = MockUart::new();
+        uart.registers[0][0x6f] = 0x8010_0041;
+        let mut driver = Tmc2209::new(uart, 0).unwrap();
+        let diagnostics = driver.diagnostics().unwrap();
+        assert_eq!(diagnostics.overtemperature, Overtemperature::Prewarning);
+        assert_eq!(diagnostics.open_load, [true, false]);
+        assert_eq!(diagnostics.current_scale, 16);
+        assert!(diagnostics.standstill && !diagnostics.stealth);
+        assert!(diagnostics.is_ok());
+
+        let diagnostics = Diagnostics::from(DrvStatus(0x0000_0022));
+        assert_eq!(diagnostics.overtemperature, Overtemperature::Shutdown);
+        assert_eq!(diagnostics.short, [false, true]);
+        assert!(!diagnostics.is_ok());
+    }
+}
@@ -0,0 +1,127 @@
+//! UART datagrams.
+//!
+//! Every datagram starts with the sync byte and ends with a CRC8 over the bytes before it.
+//! Register data is sent MSB first.
+//!
+//! | datagram      | bytes                                                   |
+//! |---------------|---------------------------------------------------------|
+//! | write         | sync, slave address, register \| 0x80, data[4], CRC     |
+//! | read request  | sync, slave address, register, CRC                      |
+//! | read reply    | sync, 0xFF, register, data[4], CRC                      |
+
+use crate::Error;
+
+/// Sync nibble 0b0101, the upper reserved bits are sent as zero
+pub const SYNC: u8 = 0x05;
+/// Address of the master in read replies
+pub const MASTER_ADDRESS: u8 = 0xff;
+/// Register address flag of write datagrams
+pub const WRITE: u8 = 0x80;
+
+pub const WRITE_LEN: usize = 8;
+pub const READ_REQUEST_LEN: usize = 4;
+pub const REPLY_LEN: usize = 8;
+
+/// CRC8 with polynomial x^8 + x^2 + x + 1, each byte LSB first, as specified by Trinamic.
+pub fn crc8(bytes: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for &byte in bytes {
+        let mut byte = byte;
+        for _ in 0..8 {
+            if (crc >> 7) ^ (byte & 0x01) != 0 {
+                crc = (crc << 1) ^ 0x07;
+            } else {
+                crc <<= 1;
+            }
+            byte >>= 1;
+        }
+    }
+    crc
+}
+
+/// Write datagram setting `register` of the driver at `slave` to `data`.
+pub fn write(slave: u8, register: u8, data: u32) -> [u8; WRITE_LEN] {
+    let [d0, d1, d2, d3] = data.to_be_bytes();
+    let mut datagram = [SYNC, slave, register | WRITE, d0, d1, d2, d3, 0];
+    datagram[WRITE_LEN - 1] = crc8(&datagram[..WRITE_LEN - 1]);
+    datagram
+}
+
+/// Read request for `register` of the driver at `slave`.
+pub fn read_request(slave: u8, register: u8) -> [u8; READ_REQUEST_LEN] {
+    let mut datagram = [SYNC, slave, register, 0];
+    datagram[READ_REQUEST_LEN - 1] = crc8(&datagram[..READ_REQUEST_LEN - 1]);
+    datagram
+}
+
+/// Checks a read reply to a request for `register` and returns the register value.
+pub fn parse_reply<E>(reply: &[u8; REPLY_LEN], register: u8) -> Result<u32, Error<E>> {
+    if reply[REPLY_LEN - 1] != crc8(&reply[..REPLY_LEN - 1]) {
+        return Err(Error::Crc);
+    }
+    if reply[0] & 0x0f != SYNC || reply[1] != MASTER_ADDRESS {
+        return Err(Error::Framing);
+    }
+    if reply[2] != register {
+        return Err(Error::UnexpectedRegister(reply[2]));
+    }
+    Ok(u32::from_be_bytes([reply[3], reply[4], reply[5], reply[6]]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reply as the driver sends it.
+    pub fn reply(register: u8, data: u32) -> [u8; REPLY_LEN] {
+        let mut reply = write(MASTER_ADDRESS, register, data);
+        reply[2] = register;
+        reply[REPLY_LEN - 1] = crc8(&reply[..REPLY_LEN - 1]);
+        reply
+    }
+
+    #[test]
+    fn crc_matches_reference() {
+        // Bitwise reference: reflect the input bytes, MSB first CRC-8/0x07
+        fn reference(bytes: &[u8]) -> u8 {
+            let mut crc = 0u8;
+            for &byte in bytes {
+                crc ^= byte.reverse_bits();
+                for _ in 0..8 {
+                    crc = if crc & 0x80 != 0 { (crc << 1) ^ 0x07 } else { crc << 1 };
+                }
+            }
+            crc
+        }
+        for bytes in [&[0x05, 0x00, 0x00][..], &[0x05, 0x03, 0x6f], &[0x05, 0xff, 0x41, 0, 0, 1, 0x23]] {
+            assert_eq!(crc8(bytes), reference(bytes));
+        }
+        assert_eq!(crc8(&[]), 0);
+    }
+
+    #[test]
+    fn encodes_datagrams() {
+        let datagram = write(2, 0x10, 0x0001_1f10);
+        assert_eq!(datagram[..7], [0x05, 0x02, 0x90, 0x00, 0x01, 0x1f, 0x10]);
+        assert_eq!(datagram[7], crc8(&datagram[..7]));
+
+        let request = read_request(1, 0x6f);
+        assert_eq!(request[..3], [0x05, 0x01, 0x6f]);
+        assert_eq!(request[3], crc8(&request[..3]));
+    }
+
+    #[test]
+    fn validates_replies() {
+        let good = reply(0x6c, 0x1000_0053);
+        assert_eq!(parse_reply::<()>(&good, 0x6c), Ok(0x1000_0053));
+        assert_eq!(parse_reply::<()>(&good, 0x6f), Err(Error::UnexpectedRegister(0x6c)));
+
+        let mut corrupt = good;
+        corrupt[4] ^= 0x01;
+        assert_eq!(parse_reply::<()>(&corrupt, 0x6c), Err(Error::Crc));
+
+        // Well formed, but an echoed request instead of a reply
+        let echo = write(0, 0x6c, 0);
+        assert_eq!(parse_reply::<()>(&echo, 0x6c), Err(Error::Framing));
+    }
+}
@@ -0,0 +1,344 @@
+//! TMC2209 stepper driver configuration over UART.
+//!
+//! The TMC2209 is configured through a single-wire UART on its PDN_UART pin. Up to four
+//! drivers share the line, told apart by the slave address strapped on MS1/MS2 (0-3).
+//! `Tmc2209` reads and writes the typed registers in `registers` with any
+//! [`embedded-hal`] serial port, replies are checked for framing, register and CRC.
+//!
+//! STEP and DIR stay with the step generator, see the `stepper-driver` crate.
+//!
+//! [`embedded-hal`]: https://github.com/rust-embedded/embedded-hal
+//!
+//! # Example
+//!
+//! ```rust,ignore
+//! let mut driver = Tmc2209::new(uart, 0).unwrap();
+//! let mut gconf = driver.read::<Gconf>().unwrap();
+//! gconf.set_pdn_disable(true).set_mstep_reg_select(true);
+//! driver.write(gconf).unwrap();
+//! driver.modify(|chopconf: &mut Chopconf| {
+//!     chopconf.set_mres(4);
+//! }).unwrap();
+//! ```
+
+#![no_std]
+#![deny(missing_debug_implementations)]
+#![deny(unsafe_code)]
+
+#[cfg(test)]
+#[macro_use]
+extern crate std;
+
+pub mod bus;
+pub mod config;
+pub mod datagram;
+pub mod registers;
+#[cfg(feature = "homing")]
+pub mod stallguard;
+
+pub use registers::{
+    Chopconf, DrvStatus, Gconf, IholdIrun, Pwmconf, Readable, Register, SgResult, Sgthrs, Tcoolthrs,
+    Tpwmthrs, Writable,
+};
+pub use bus::{Bus, BusPort};
+pub use config::{ChopperMode, Diagnostics, MotorCurrent, Overtemperature};
+
+use embedded_hal::serial::{Read, Write};
+
+/// Errors
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error<E> {
+    /// UART error
+    Uart(E),
+    /// Slave addresses are 0-3
+    InvalidAddress(u8),
+    /// The bus has a handle for this address already
+    AddressInUse(u8),
+    /// No complete reply within the timeout
+    Timeout,
+    /// Reply with a wrong CRC
+    Crc,
+    /// Reply without the sync byte or the master address
+    Framing,
+    /// Reply for another register than requested
+    UnexpectedRegister(u8),
+    /// Microstep resolution is not a power of two up to 256
+    InvalidMicrosteps(u16),
+    /// Sense resistor or velocity is not positive and finite
+    InvalidParameter,
+}
+
+impl<E> From<E> for Error<E> {
+    fn from(e: E) -> Self {
+        Error::Uart(e)
+    }
+}
+
+/// Polls of the UART before a reply counts as missing
+pub const DEFAULT_TIMEOUT: u32 = 100_000;
+
+/// Most bytes dropped before a read request, a full receive FIFO
+const STALE_BYTES: usize = 128;
+
+/// A TMC2209 on a UART.
+///
+/// The UART has to receive only the driver's replies. On a single-wire connection that
+/// echoes the requests back, use a `Bus`, which drops the echo.
+#[derive(Debug)]
+pub struct Tmc2209<U> {
+    uart: U,
+    address: u8,
+    timeout: u32,
+}
+
+impl<U, E> Tmc2209<U>
+where
+    U: Read<u8, Error = E> + Write<u8, Error = E>,
+{
+    /// Driver at slave `address` (0-3, set by MS1/MS2).
+    pub fn new(uart: U, address: u8) -> Result<Self, Error<E>> {
+        if address > 3 {
+            return Err(Error::InvalidAddress(address));
+        }
+        Ok(Tmc2209 { uart, address, timeout: DEFAULT_TIMEOUT })
+    }
+
+    pub fn address(&self) -> u8 {
+        self.address
+    }
+
+    /// Sets how many times the UART is polled for a reply byte before giving up.
+    pub fn set_timeout(&mut self, polls: u32) {
+        self.timeout = polls;
+    }
+
+    /// Reads a register, the reply is validated.
+    ///
+    /// Bytes received before the request, such as a reply that came after a timeout, are
+    /// discarded so they do not shift this reply.
+    pub fn read<R: Readable>(&mut self) -> Result<R, Error<E>> {
+        discard(&mut self.uart)?;
+        send(&mut self.uart, &datagram::read_request(self.address, R::ADDRESS))?;
+        let mut reply = [0; datagram::REPLY_LEN];
+        receive(&mut self.uart, &mut reply, self.timeout)?;
+        datagram::parse_reply(&reply, R::ADDRESS).map(R::from_bits)
+    }
+
+    /// Writes a register. The driver does not reply to writes.
+    pub fn write<R: Writable>(&mut self, register: R) -> Result<(), Error<E>> {
+        send(&mut self.uart, &datagram::write(self.address, R::ADDRESS, register.bits()))
+    }
+
+    /// Reads a register, changes it with `f` and writes it back. Returns the written value.
+    pub fn modify<R, F>(&mut self, f: F) -> Result<R, Error<E>>
+    where
+        R: Readable + Writable,
+        F: FnOnce(&mut R),
+    {
+        let mut register = self.read::<R>()?;
+        f(&mut register);
+        self.write(register)?;
+        Ok(register)
+    }
+
+    /// Releases the UART.
+    pub fn release(self) -> U {
+        self.uart
+    }
+}
+
+/// Sends `bytes` and waits until they are out.
+fn send<U, E>(uart: &mut U, bytes: &[u8]) -> Result<(), Error<E>>
+where
+    U: Write<u8, Error = E>,
+{
+    for &byte in bytes {
+        nb::block!(uart.write(byte))?;
+    }
+    nb::block!(uart.flush())?;
+    Ok(())
+}
+
+/// Reads what the UART has received so far, at most `STALE_BYTES` bytes in case the line
+/// keeps receiving.
+fn discard<U, E>(uart: &mut U) -> Result<(), Error<E>>
+where
+    U: Read<u8, Error = E>,
+{
+    for _ in 0..STALE_BYTES {
+        match uart.read() {
+            Ok(_) => (),
+            Err(nb::Error::WouldBlock) => break,
+            Err(nb::Error::Other(e)) => return Err(Error::Uart(e)),
+        }
+    }
+    Ok(())
+}
+
+/// Fills `buffer`, polling the UART at most `timeout` times per byte.
+fn receive<U, E>(uart: &mut U, buffer: &mut [u8], timeout: u32) -> Result<(), Error<E>>
+where
+    U: Read<u8, Error = E>,
+{
+    for byte in buffer.iter_mut() {
+        let mut polls = 0;
+        *byte = loop {
+            match uart.read() {
+                Ok(byte) => break byte,
+                Err(nb::Error::WouldBlock) if polls < timeout => polls += 1,
+                Err(nb::Error::WouldBlock) => return Err(Error::Timeout),
+                Err(nb::Error::Other(e)) => return Err(Error::Uart(e)),
+            }
+        };
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::convert::Infallible;
+    use std::collections::VecDeque;
+    use std::vec::Vec;
+
+    /// Byte level model of TMC2209s on a UART: decodes the datagrams sent to it and queues
+    /// the replies of the addressed driver.
+    #[derive(Debug)]
+    pub struct MockUart {
+        /// Register files of the drivers at addresses 0-3
+        pub registers: [[u32; 128]; 4],
+        /// Bytes sent, in order
+        pub sent: Vec<u8>,
+        /// Bytes to receive
+        pub rx: VecDeque<u8>,
+        /// Every byte sent is received too, like on a single wire
+        pub echo: bool,
+        /// Flips a bit in the next reply
+        pub corrupt_next: bool,
+        /// Drivers that answer, all when empty
+        pub present: Vec<u8>,
+        pending: Vec<u8>,
+    }
+
+    impl MockUart {
+        pub fn new() -> Self {
+            MockUart {
+                registers: [[0; 128]; 4],
+                sent: Vec::new(),
+                rx: VecDeque::new(),
+                echo: false,
+                corrupt_next: false,
+                present: Vec::new(),
+                pending: Vec::new(),
+            }
+        }
+
+        fn datagram(&mut self) {
+            let bytes = core::mem::take(&mut self.pending);
+            assert_eq!(bytes[bytes.len() - 1], datagram::crc8(&bytes[..bytes.len() - 1]), "bad CRC");
+            let slave = bytes[1] as usize;
+            if !self.present.is_empty() && !self.present.contains(&bytes[1]) {
+                return;
+            }
+            let register = bytes[2] & 0x7f;
+            if bytes.len() == datagram::WRITE_LEN {
+                let data = u32::from_be_bytes([bytes[3], bytes[4], bytes[5], bytes[6]]);
+                self.registers[slave][register as usize] = data;
+            } else {
+                let data = self.registers[slave][register as usize];
+                let mut reply = datagram::write(datagram::MASTER_ADDRESS, register, data);
+                reply[2] = register;
+                reply[7] = datagram::crc8(&reply[..7]);
+                if core::mem::take(&mut self.corrupt_next) {
+                    reply[5] ^= 0x10;
+                }
+                self.rx.extend(reply.iter());
+            }
+        }
+    }
+
+    impl Write<u8> for MockUart {
+        type Error = Infallible;
+
+        fn write(&mut self, byte: u8) -> nb::Result<(), Infallible> {
+            self.sent.push(byte);
+            if self.echo {
+                self.rx.push_back(byte);
+            }
+            self.pending.push(byte);
+            let complete = match self.pending.len() {
+                datagram::READ_REQUEST_LEN => self.pending[2] & datagram::WRITE == 0,
+                datagram::WRITE_LEN => true,
+                _ => false,
+            };
+            if complete {
+                self.datagram();
+            }
+            Ok(())
+        }
+
+        fn flush(&mut self) -> nb::Result<(), Infallible> {
+            Ok(())
+        }
+    }
+
+    impl Read<u8> for MockUart {
+        type Error = Infallible;
+
+        fn read(&mut self) -> nb::Result<u8, Infallible> {
+            self.rx.pop_front().ok_or(nb::Error::WouldBlock)
+        }
+    }
+
+    #[test]
+    fn writes_registers() {
+        let mut driver = Tmc2209::new(MockUart::new(), 3).unwrap();
+        let mut ihold_irun = IholdIrun::default();
+        ihold_irun.set_ihold(8).set_irun(20);
+        driver.write(ihold_irun).unwrap();
+
+        let uart = driver.release();
+        assert_eq!(uart.registers[3][0x10], 0x0000_1408);
+        assert_eq!(uart.sent, datagram::write(3, 0x10, 0x1408).to_vec());
+    }
+
+    #[test]
+    fn reads_registers() {
+        let mut uart = MockUart::new();
+        uart.registers[1][0x6c] = 0x1000_0053;
+        let mut driver = Tmc2209::new(uart, 1).unwrap();
+        let chopconf: Chopconf = driver.read().unwrap();
+        assert_eq!(chopconf.toff(), 3);
+
+        let chopconf = driver.modify(|c: &mut Chopconf| {
+            c.set_mres(2);
+        }).unwrap();
+        assert_eq!(chopconf.bits(), 0x1200_0053);
+        assert_eq!(driver.release().registers[1][0x6c], 0x1200_0053);
+    }
+
+    #[test]
+    fn rejects_bad_replies() {
+        let mut uart = MockUart::new();
+        uart.corrupt_next = true;
+        let mut driver = Tmc2209::new(uart, 0).unwrap();
+        assert_eq!(driver.read::<Gconf>(), Err(Error::Crc));
+        assert_eq!(driver.read::<Gconf>(), Ok(Gconf(0)));
+
+        // Unread echo bytes shift the reply
+        let mut uart = driver.release();
+        uart.echo = true;
+        let mut driver = Tmc2209::new(uart, 0).unwrap();
+        assert_eq!(driver.read::<DrvStatus>(), Err(Error::Crc));
+    }
+
+    #[test]
+    fn times_out_without_reply() {
+        let mut uart = MockUart::new();
+        uart.present = vec![2];
+        let mut driver = Tmc2209::new(uart, 1).unwrap();
+        driver.set_timeout(10);
+        assert_eq!(driver.read::<SgResult>(), Err(Error::Timeout));
+        assert_eq!(Tmc2209::new(MockUart::new(), 4).err(), Some(Error::InvalidAddress(4)));
+    }
+}
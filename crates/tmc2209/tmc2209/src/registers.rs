@@ -0,0 +1,380 @@
+//! Typed registers.
+//!
+//! Each register is a newtype over its 32 bit value with accessors for the fields.
+//! `default()` is all zero, not the power-on value; use `Tmc2209::modify` to change a few
+//! fields of a readable register and keep the rest.
+
+/// A register of the TMC2209.
+pub trait Register: Copy {
+    /// Register address
+    const ADDRESS: u8;
+
+    fn from_bits(bits: u32) -> Self;
+
+    fn bits(&self) -> u32;
+}
+
+/// Register that can be read over UART.
+pub trait Readable: Register {}
+
+/// Register that can be written over UART.
+pub trait Writable: Register {}
+
+macro_rules! register {
+    ($(#[$doc:meta])* $name:ident = $address:expr, $($access:ident),+) => {
+        $(#[$doc])*
+        #[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+        pub struct $name(pub u32);
+
+        impl Register for $name {
+            const ADDRESS: u8 = $address;
+
+            fn from_bits(bits: u32) -> Self {
+                $name(bits)
+            }
+
+            fn bits(&self) -> u32 {
+                self.0
+            }
+        }
+
+        $(impl $access for $name {})+
+    };
+}
+
+/// Single bit field.
+macro_rules! flag {
+    ($(#[$doc:meta])* $get:ident, $set:ident, $bit:expr) => {
+        $(#[$doc])*
+        pub fn $get(&self) -> bool {
+            self.0 & (1 << $bit) != 0
+        }
+
+        pub fn $set(&mut self, value: bool) -> &mut Self {
+            if value {
+                self.0 |= 1 << $bit;
+            } else {
+                self.0 &= !(1 << $bit);
+            }
+            self
+        }
+    };
+}
+
+/// Multi-bit field, setters drop the bits that do not fit.
+macro_rules! field {
+    ($(#[$doc:meta])* $get:ident, $set:ident, $ty:ty, $shift:expr, $width:expr) => {
+        $(#[$doc])*
+        pub fn $get(&self) -> $ty {
+            ((self.0 >> $shift) & ((1 << $width) - 1)) as $ty
+        }
+
+        pub fn $set(&mut self, value: $ty) -> &mut Self {
+            let mask: u32 = ((1 << $width) - 1) << $shift;
+            self.0 = (self.0 & !mask) | ((u32::from(value) << $shift) & mask);
+            self
+        }
+    };
+}
+
+register!(
+    /// Global configuration flags
+    Gconf = 0x00, Readable, Writable
+);
+
+impl Gconf {
+    flag!(
+        /// Use the voltage on VREF as current reference
+        i_scale_analog, set_i_scale_analog, 0
+    );
+    flag!(
+        /// Internal sense resistors, use the current supplied into VREF as reference
+        internal_rsense, set_internal_rsense, 1
+    );
+    flag!(
+        /// SpreadCycle instead of StealthChop
+        en_spreadcycle, set_en_spreadcycle, 2
+    );
+    flag!(
+        /// Inverse motor direction
+        shaft, set_shaft, 3
+    );
+    flag!(
+        /// INDEX shows the overtemperature prewarning instead of the first microstep position
+        index_otpw, set_index_otpw, 4
+    );
+    flag!(
+        /// INDEX outputs step pulses from the internal pulse generator
+        index_step, set_index_step, 5
+    );
+    flag!(
+        /// PDN_UART is used for UART only, standstill current reduction is disabled on it
+        pdn_disable, set_pdn_disable, 6
+    );
+    flag!(
+        /// Microstep resolution from MRES instead of the MS1/MS2 pins
+        mstep_reg_select, set_mstep_reg_select, 7
+    );
+    flag!(
+        /// Software pulse generator filtering of STEP
+        multistep_filt, set_multistep_filt, 8
+    );
+}
+
+register!(
+    /// Run and hold current
+    IholdIrun = 0x10, Writable
+);
+
+impl IholdIrun {
+    field!(
+        /// Standstill current (0-31, 1/32 .. 32/32 of the full scale)
+        ihold, set_ihold, u8, 0, 5
+    );
+    field!(
+        /// Motor run current (0-31)
+        irun, set_irun, u8, 8, 5
+    );
+    field!(
+        /// Number of 2^18 clock steps per current decrement down to IHOLD after standstill
+        iholddelay, set_iholddelay, u8, 16, 4
+    );
+}
+
+register!(
+    /// Upper velocity threshold (TSTEP) for StealthChop, SpreadCycle above it
+    Tpwmthrs = 0x13, Writable
+);
+
+impl Tpwmthrs {
+    field!(
+        /// Threshold in TSTEP units (20 bits), 0 keeps StealthChop at all speeds
+        tpwmthrs, set_tpwmthrs, u32, 0, 20
+    );
+}
+
+register!(
+    /// Lower velocity threshold (TSTEP) for CoolStep and the StallGuard DIAG output
+    Tcoolthrs = 0x14, Writable
+);
+
+impl Tcoolthrs {
+    field!(
+        /// Threshold in TSTEP units (20 bits)
+        tcoolthrs, set_tcoolthrs, u32, 0, 20
+    );
+}
+
+register!(
+    /// StallGuard threshold
+    Sgthrs = 0x40, Writable
+);
+
+impl Sgthrs {
+    field!(
+        /// A stall is signalled on DIAG when SG_RESULT falls to 2 * SGTHRS or below, higher is
+        /// more sensitive
+        sgthrs, set_sgthrs, u8, 0, 8
+    );
+}
+
+register!(
+    /// StallGuard result, lower is higher load
+    SgResult = 0x41, Readable
+);
+
+impl SgResult {
+    field!(
+        /// StallGuard value (10 bits)
+        sg_result, set_sg_result, u16, 0, 10
+    );
+}
+
+register!(
+    /// Chopper and driver configuration
+    Chopconf = 0x6c, Readable, Writable
+);
+
+impl Chopconf {
+    field!(
+        /// Off time, 0 disables the driver
+        toff, set_toff, u8, 0, 4
+    );
+    field!(
+        /// Hysteresis start value
+        hstrt, set_hstrt, u8, 4, 3
+    );
+    field!(
+        /// Hysteresis low value
+        hend, set_hend, u8, 7, 4
+    );
+    field!(
+        /// Comparator blank time
+        tbl, set_tbl, u8, 15, 2
+    );
+    flag!(
+        /// High sensitivity, low sense resistor voltage
+        vsense, set_vsense, 17
+    );
+    field!(
+        /// Microstep resolution, 0 is 256 microsteps and 8 full steps
+        mres, set_mres, u8, 24, 4
+    );
+    flag!(
+        /// Interpolation to 256 microsteps
+        intpol, set_intpol, 28
+    );
+    flag!(
+        /// Step on both edges of STEP
+        dedge, set_dedge, 29
+    );
+    flag!(
+        /// Short to ground protection disabled
+        diss2g, set_diss2g, 30
+    );
+    flag!(
+        /// Low side short protection disabled
+        diss2vs, set_diss2vs, 31
+    );
+}
+
+register!(
+    /// StealthChop PWM configuration
+    Pwmconf = 0x70, Readable, Writable
+);
+
+impl Pwmconf {
+    field!(
+        /// User defined PWM amplitude offset
+        pwm_ofs, set_pwm_ofs, u8, 0, 8
+    );
+    field!(
+        /// Velocity dependent PWM amplitude gradient
+        pwm_grad, set_pwm_grad, u8, 8, 8
+    );
+    field!(
+        /// PWM frequency selection
+        pwm_freq, set_pwm_freq, u8, 16, 2
+    );
+    flag!(
+        /// Automatic current control
+        pwm_autoscale, set_pwm_autoscale, 18
+    );
+    flag!(
+        /// Automatic tuning of PWM_GRAD
+        pwm_autograd, set_pwm_autograd, 19
+    );
+    field!(
+        /// Standstill mode when the hold current is 0
+        freewheel, set_freewheel, u8, 20, 2
+    );
+    field!(
+        /// Regulation loop gradient
+        pwm_reg, set_pwm_reg, u8, 24, 4
+    );
+    field!(
+        /// PWM automatic scale amplitude limit when switching on
+        pwm_lim, set_pwm_lim, u8, 28, 4
+    );
+}
+
+register!(
+    /// Driver status flags and current level
+    DrvStatus = 0x6f, Readable
+);
+
+impl DrvStatus {
+    flag!(
+        /// Overtemperature prewarning
+        otpw, set_otpw, 0
+    );
+    flag!(
+        /// Overtemperature, the driver is shut down
+        ot, set_ot, 1
+    );
+    flag!(
+        /// Short to ground on phase A
+        s2ga, set_s2ga, 2
+    );
+    flag!(
+        /// Short to ground on phase B
+        s2gb, set_s2gb, 3
+    );
+    flag!(
+        /// Low side short on phase A
+        s2vsa, set_s2vsa, 4
+    );
+    flag!(
+        /// Low side short on phase B
+        s2vsb, set_s2vsb, 5
+    );
+    flag!(
+        /// Open load on phase A
+        ola, set_ola, 6
+    );
+    flag!(
+        /// Open load on phase B
+        olb, set_olb, 7
+    );
+    flag!(
+        /// Temperature above 120 °C
+        t120, set_t120, 8
+    );
+    flag!(
+        /// Temperature above 143 °C
+        t143, set_t143, 9
+    );
+    flag!(
+        /// Temperature above 150 °C
+        t150, set_t150, 10
+    );
+    flag!(
+        /// Temperature above 157 °C
+        t157, set_t157, 11
+    );
+    field!(
+        /// Actual motor current scale (0-31)
+        cs_actual, set_cs_actual, u8, 16, 5
+    );
+    flag!(
+        /// Driver in StealthChop mode
+        stealth, set_stealth, 30
+    );
+    flag!(
+        /// Motor standstill
+        stst, set_stst, 31
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fields_pack_into_bits() {
+        let mut ihold_irun = IholdIrun::default();
+        ihold_irun.set_ihold(16).set_irun(31).set_iholddelay(1);
+        assert_eq!(ihold_irun.bits(), 0x0001_1f10);
+        // Out of range values are cut to the field width
+        ihold_irun.set_irun(0x3f);
+        assert_eq!(ihold_irun.irun(), 31);
+        assert_eq!(ihold_irun.ihold(), 16);
+
+        // Power-on value
+        let mut chopconf = Chopconf(0x1000_0053);
+        assert_eq!((chopconf.toff(), chopconf.hstrt(), chopconf.hend()), (3, 5, 0));
+        assert_eq!(chopconf.mres(), 0);
+        assert!(chopconf.intpol());
+        chopconf.set_mres(4).set_vsense(true).set_intpol(false);
+        assert_eq!(chopconf.bits(), 0x0402_0053);
+    }
+
+    #[test]
+    fn status_flags() {
+        let status = DrvStatus::from_bits(0xc01f_00c3);
+        assert!(status.otpw() && status.ot() && status.ola() && status.olb());
+        assert!(!status.s2ga() && !status.t120());
+        assert_eq!(status.cs_actual(), 31);
+        assert!(status.stealth() && status.stst());
+    }
+}
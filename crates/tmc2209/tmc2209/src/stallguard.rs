@@ -0,0 +1,493 @@
+//! Sensorless homing with StallGuard.
+//!
+//! In StealthChop the TMC2209 measures the motor load (StallGuard4, keep
+//! `Gconf::en_spreadcycle` off). Driven against the end stop, the load goes up and SG_RESULT
+//! drops; once it is at or below 2 * SGTHRS the driver signals a stall, which ends the move
+//! and becomes the home position. No limit switch needed.
+//!
+//! The stall is read from the DIAG output (`Diag`), checked before every step, or by polling
+//! SG_RESULT over UART (`PollSgResult`), which takes long enough to only do it every few steps.
+//! SG_RESULT is not meaningful while the motor starts, so the first `ignore_steps` are not
+//! checked.
+//!
+//! A good SGTHRS depends on the motor, current and speed, `Tmc2209::calibrate_stallguard`
+//! searches one.
+
+use embedded_hal::digital::v2::{InputPin, OutputPin};
+use embedded_hal::serial::{Read, Write};
+use stepper_driver::{ControlPins, HomingError, ModePins, MotorDriver, Params, StepGenerator};
+
+use crate::{Error, SgResult, Sgthrs, Tcoolthrs, Tmc2209};
+
+/// Errors of sensorless homing, `E` is the UART and `P` the pin error type
+#[derive(Debug, PartialEq)]
+pub enum SensorlessError<E, P> {
+    /// Configuring or polling the TMC2209 failed
+    Uart(Error<E>),
+    /// Moving failed, `Error::Homing(HomingError::MaxTravel)` if there was no stall
+    Motor(stepper_driver::Error<P>),
+    /// No threshold of the calibration sweep homed reliably
+    NoThreshold,
+}
+
+impl<E, P> From<Error<E>> for SensorlessError<E, P> {
+    fn from(e: Error<E>) -> Self {
+        SensorlessError::Uart(e)
+    }
+}
+
+impl<E, P> From<stepper_driver::Error<P>> for SensorlessError<E, P> {
+    fn from(e: stepper_driver::Error<P>) -> Self {
+        SensorlessError::Motor(e)
+    }
+}
+
+/// Sensorless homing parameters. Distances are in microsteps (STEP pulses), speeds in full
+/// steps/s.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SensorlessConfig {
+    /// Direction towards the end stop, see `MotorDriver::set_direction`
+    pub clockwise: bool,
+    /// Homing speed. StallGuard needs some back EMF, about 1 rev/s works for most motors.
+    pub speed: f32,
+    /// Stall threshold written to SGTHRS, higher is more sensitive
+    pub sgthrs: u8,
+    /// Written to TCOOLTHRS, stalls are only signalled while TSTEP is at or below it. The
+    /// default signals them at any speed.
+    pub tcoolthrs: u32,
+    /// Steps at the start of each approach that are not checked for a stall
+    pub ignore_steps: u32,
+    /// Longest distance travelled looking for the end stop
+    pub max_travel: u32,
+    /// Position assigned to the stall point
+    pub home_position: i64,
+}
+
+impl Default for SensorlessConfig {
+    fn default() -> Self {
+        SensorlessConfig {
+            clockwise: false,
+            speed: 200.0,
+            sgthrs: 64,
+            tcoolthrs: 0xfffff,
+            ignore_steps: 64,
+            max_travel: 100_000,
+            home_position: 0,
+        }
+    }
+}
+
+/// SGTHRS sweep of `Tmc2209::calibrate_stallguard`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Calibration {
+    /// First, most sensitive threshold tried
+    pub max: u8,
+    /// Last threshold tried
+    pub min: u8,
+    /// Decrement between the thresholds tried
+    pub step: u8,
+    /// Distance backed off from the stall point before approaching it again
+    pub back_off: u32,
+    /// How far a repeated stall may be from the first one
+    pub tolerance: u32,
+    /// Repeated approaches that have to stall at the same point
+    pub repeats: u8,
+}
+
+impl Default for Calibration {
+    fn default() -> Self {
+        Calibration { max: 255, min: 0, step: 8, back_off: 400, tolerance: 8, repeats: 3 }
+    }
+}
+
+/// Source of the stall signal.
+pub trait StallDetector<U, E, P> {
+    /// Checks for a stall before the next step, `steps` into the approach.
+    fn stalled(&mut self, driver: &mut Tmc2209<U>, sgthrs: u8, steps: u64)
+        -> Result<bool, SensorlessError<E, P>>;
+}
+
+/// The DIAG output, high on a stall.
+#[derive(Debug)]
+pub struct Diag<PIN>(pub PIN);
+
+impl<U, E, PIN> StallDetector<U, E, PIN::Error> for Diag<PIN>
+where
+    PIN: InputPin,
+{
+    fn stalled(&mut self, _driver: &mut Tmc2209<U>, _sgthrs: u8, _steps: u64)
+        -> Result<bool, SensorlessError<E, PIN::Error>> {
+        self.0.is_high().map_err(|e| SensorlessError::Motor(stepper_driver::Error::Pin(e)))
+    }
+}
+
+/// SG_RESULT read over UART every `interval` steps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PollSgResult {
+    pub interval: u32,
+}
+
+impl<U, E, P> StallDetector<U, E, P> for PollSgResult
+where
+    U: Read<u8, Error = E> + Write<u8, Error = E>,
+{
+    fn stalled(&mut self, driver: &mut Tmc2209<U>, sgthrs: u8, steps: u64)
+        -> Result<bool, SensorlessError<E, P>> {
+        if !steps.is_multiple_of(u64::from(self.interval.max(1))) {
+            return Ok(false);
+        }
+        let result: SgResult = driver.read()?;
+        Ok(result.sg_result() <= 2 * u16::from(sgthrs))
+    }
+}
+
+impl<U, E> Tmc2209<U>
+where
+    U: Read<u8, Error = E> + Write<u8, Error = E>,
+{
+    /// Homes `motor` against the end stop and zeroes the position there.
+    ///
+    /// Writes TCOOLTHRS and SGTHRS, moves towards the end stop until `detector` reports a
+    /// stall and sets the position to `config.home_position`. Software travel limits are
+    /// ignored while homing. Fails with `Error::Homing(HomingError::MaxTravel)` if there is no
+    /// stall within `config.max_travel`; the axis is still unhomed then.
+    pub fn home_sensorless<G, CHIP, EN, MS, CTL, D>(&mut self,
+                                                    motor: &mut MotorDriver<G, CHIP, EN, MS, CTL>,
+                                                    detector: &mut D,
+                                                    config: &SensorlessConfig)
+        -> Result<(), SensorlessError<E, G::Error>>
+    where
+        G: StepGenerator,
+        CHIP: Params,
+        EN: OutputPin<Error = G::Error>,
+        MS: ModePins<Error = G::Error>,
+        CTL: ControlPins<Error = G::Error>,
+        D: StallDetector<U, E, G::Error>,
+    {
+        let limits = motor.limits();
+        motor.set_limits(None);
+        let result = self.seek_home(motor, detector, config);
+        motor.set_limits(limits);
+        result?;
+        motor.set_position(config.home_position);
+        Ok(())
+    }
+
+    /// Searches a stall threshold that finds the end stop reliably and returns it.
+    ///
+    /// Sweeps SGTHRS from `calibration.max` down to `calibration.min`. At each threshold the
+    /// axis is homed, then backed off and driven at the end stop again `calibration.repeats`
+    /// times. Too sensitive thresholds stall early while running free, so the stall points
+    /// scatter. The first threshold whose stalls all land at the first one is returned, with
+    /// the axis homed there. Subtracting a margin makes it more robust against load changes.
+    ///
+    /// `config.sgthrs` is not used. Fails with `SensorlessError::NoThreshold` if no threshold
+    /// works.
+    pub fn calibrate_stallguard<G, CHIP, EN, MS, CTL, D>(&mut self,
+                                                         motor: &mut MotorDriver<G, CHIP, EN, MS, CTL>,
+                                                         detector: &mut D,
+                                                         config: &SensorlessConfig,
+                                                         calibration: &Calibration)
+        -> Result<u8, SensorlessError<E, G::Error>>
+    where
+        G: StepGenerator,
+        CHIP: Params,
+        EN: OutputPin<Error = G::Error>,
+        MS: ModePins<Error = G::Error>,
+        CTL: ControlPins<Error = G::Error>,
+        D: StallDetector<U, E, G::Error>,
+    {
+        let limits = motor.limits();
+        motor.set_limits(None);
+        let result = self.sweep_stallguard(motor, detector, config, calibration);
+        motor.set_limits(limits);
+        let sgthrs = result?;
+        motor.set_position(config.home_position);
+        Ok(sgthrs)
+    }
+
+    fn seek_home<G, CHIP, EN, MS, CTL, D>(&mut self,
+                                          motor: &mut MotorDriver<G, CHIP, EN, MS, CTL>,
+                                          detector: &mut D,
+                                          config: &SensorlessConfig)
+        -> Result<(), SensorlessError<E, G::Error>>
+    where
+        G: StepGenerator,
+        CHIP: Params,
+        EN: OutputPin<Error = G::Error>,
+        MS: ModePins<Error = G::Error>,
+        CTL: ControlPins<Error = G::Error>,
+        D: StallDetector<U, E, G::Error>,
+    {
+        self.configure_stallguard(config)?;
+        match self.seek_stall(motor, detector, config, config.max_travel)? {
+            Some(_) => Ok(()),
+            None => Err(stepper_driver::Error::Homing(HomingError::MaxTravel).into()),
+        }
+    }
+
+    fn sweep_stallguard<G, CHIP, EN, MS, CTL, D>(&mut self,
+                                                 motor: &mut MotorDriver<G, CHIP, EN, MS, CTL>,
+                                                 detector: &mut D,
+                                                 config: &SensorlessConfig,
+                                                 calibration: &Calibration)
+        -> Result<u8, SensorlessError<E, G::Error>>
+    where
+        G: StepGenerator,
+        CHIP: Params,
+        EN: OutputPin<Error = G::Error>,
+        MS: ModePins<Error = G::Error>,
+        CTL: ControlPins<Error = G::Error>,
+        D: StallDetector<U, E, G::Error>,
+    {
+        let back_off = i64::from(calibration.back_off);
+        let away = if config.clockwise { -back_off } else { back_off };
+        let mut trial = *config;
+        trial.sgthrs = calibration.max;
+        while trial.sgthrs >= calibration.min {
+            self.configure_stallguard(&trial)?;
+            if self.seek_stall(motor, detector, &trial, config.max_travel)?.is_none() {
+                // Less sensitive thresholds will not stall either
+                break;
+            }
+            let mut reliable = true;
+            for _ in 0..calibration.repeats {
+                motor.move_until(away, config.speed, |_| false)?;
+                let max_steps = calibration.back_off.saturating_add(calibration.tolerance);
+                match self.seek_stall(motor, detector, &trial, max_steps)? {
+                    Some(steps) if steps.abs_diff(u64::from(calibration.back_off))
+                        <= u64::from(calibration.tolerance) => {}
+                    _ => {
+                        reliable = false;
+                        break;
+                    }
+                }
+            }
+            if reliable {
+                return Ok(trial.sgthrs);
+            }
+            trial.sgthrs = match trial.sgthrs.checked_sub(calibration.step.max(1)) {
+                Some(sgthrs) => sgthrs,
+                None => break,
+            };
+        }
+        Err(SensorlessError::NoThreshold)
+    }
+
+    fn configure_stallguard(&mut self, config: &SensorlessConfig) -> Result<(), Error<E>> {
+        self.write(*Tcoolthrs::default().set_tcoolthrs(config.tcoolthrs))?;
+        self.write(*Sgthrs::default().set_sgthrs(config.sgthrs))?;
+        Ok(())
+    }
+
+    /// Moves towards the end stop until a stall, at most `max_steps` steps. Returns the steps
+    /// to the stall, `None` without one.
+    fn seek_stall<G, CHIP, EN, MS, CTL, D>(&mut self,
+                                           motor: &mut MotorDriver<G, CHIP, EN, MS, CTL>,
+                                           detector: &mut D,
+                                           config: &SensorlessConfig,
+                                           max_steps: u32)
+        -> Result<Option<u64>, SensorlessError<E, G::Error>>
+    where
+        G: StepGenerator,
+        CHIP: Params,
+        EN: OutputPin<Error = G::Error>,
+        MS: ModePins<Error = G::Error>,
+        CTL: ControlPins<Error = G::Error>,
+        D: StallDetector<U, E, G::Error>,
+    {
+        let max_steps = i64::from(max_steps);
+        let delta = if config.clockwise { max_steps } else { -max_steps };
+        let mut stall = Ok(false);
+        let steps = motor.move_until(delta, config.speed, |steps| {
+            if steps < u64::from(config.ignore_steps) {
+                return false;
+            }
+            stall = detector.stalled(self, config.sgthrs, steps);
+            !matches!(stall, Ok(false))
+        })?;
+        Ok(if stall? { Some(steps) } else { None })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::MockUart;
+    use core::convert::Infallible;
+    use embedded_hal::blocking::delay::DelayUs;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use stepper_driver::{a4988, GpioStepGenerator};
+
+    /// Motor against an end stop at `stop_at`, running free with SG_RESULT 300 and a dip to
+    /// 100 every 50 steps.
+    #[derive(Debug, Default)]
+    struct Axis {
+        position: i64,
+        clockwise: bool,
+        step_high: bool,
+        stop_at: i64,
+        sgthrs: u32,
+    }
+
+    impl Axis {
+        fn sg_result(&self) -> u32 {
+            if self.position <= self.stop_at {
+                0
+            } else if self.position.rem_euclid(50) == 0 {
+                100
+            } else {
+                300
+            }
+        }
+    }
+
+    /// DIR, STEP or DIAG
+    #[derive(Debug, Clone)]
+    struct Pin(Rc<RefCell<Axis>>, bool);
+
+    impl OutputPin for Pin {
+        type Error = Infallible;
+
+        fn set_low(&mut self) -> Result<(), Infallible> {
+            let mut axis = self.0.borrow_mut();
+            if self.1 {
+                axis.clockwise = true;
+            } else {
+                axis.step_high = false;
+            }
+            Ok(())
+        }
+
+        fn set_high(&mut self) -> Result<(), Infallible> {
+            let mut axis = self.0.borrow_mut();
+            if self.1 {
+                axis.clockwise = false;
+            } else if !axis.step_high {
+                axis.step_high = true;
+                // Steps against the end stop are lost
+                if axis.clockwise || axis.position > axis.stop_at {
+                    axis.position += if axis.clockwise { 1 } else { -1 };
+                }
+            }
+            Ok(())
+        }
+    }
+
+    impl InputPin for Pin {
+        type Error = Infallible;
+
+        fn is_high(&self) -> Result<bool, Infallible> {
+            let axis = self.0.borrow();
+            Ok(axis.sg_result() <= 2 * axis.sgthrs)
+        }
+
+        fn is_low(&self) -> Result<bool, Infallible> {
+            self.is_high().map(|high| !high)
+        }
+    }
+
+    /// TMC2209 at address 0 reporting the SG_RESULT of the axis.
+    #[derive(Debug)]
+    struct SimUart(MockUart, Rc<RefCell<Axis>>);
+
+    impl Write<u8> for SimUart {
+        type Error = Infallible;
+
+        fn write(&mut self, byte: u8) -> nb::Result<(), Infallible> {
+            self.0.registers[0][0x41] = self.1.borrow().sg_result();
+            self.0.write(byte)?;
+            self.1.borrow_mut().sgthrs = self.0.registers[0][0x40];
+            Ok(())
+        }
+
+        fn flush(&mut self) -> nb::Result<(), Infallible> {
+            self.0.flush()
+        }
+    }
+
+    impl Read<u8> for SimUart {
+        type Error = Infallible;
+
+        fn read(&mut self) -> nb::Result<u8, Infallible> {
+            self.0.read()
+        }
+    }
+
+    struct NoDelay;
+
+    impl DelayUs<u32> for NoDelay {
+        fn delay_us(&mut self, _us: u32) {}
+    }
+
+    type Motor = MotorDriver<GpioStepGenerator<NoDelay, Pin, Pin>, a4988>;
+
+    fn setup(stop_at: i64) -> (Tmc2209<SimUart>, Motor, Diag<Pin>) {
+        let axis = Rc::new(RefCell::new(Axis { stop_at, ..Axis::default() }));
+        let driver = Tmc2209::new(SimUart(MockUart::new(), axis.clone()), 0).unwrap();
+        let dir = Pin(axis.clone(), true);
+        let step = Pin(axis.clone(), false);
+        let motor = MotorDriver::a4988(NoDelay, dir, step, 200, 16, 60.0).unwrap();
+        (driver, motor, Diag(Pin(axis, false)))
+    }
+
+    fn position(diag: &Diag<Pin>) -> i64 {
+        diag.0 .0.borrow().position
+    }
+
+    #[test]
+    fn homes_on_diag() {
+        let (mut driver, mut motor, mut diag) = setup(-1000);
+        motor.set_position(250);
+        motor.set_limits(Some((0, 500)));
+        let config = SensorlessConfig { sgthrs: 40, ..SensorlessConfig::default() };
+        driver.home_sensorless(&mut motor, &mut diag, &config).unwrap();
+
+        assert_eq!(position(&diag), -1000);
+        assert_eq!(motor.position(), 0);
+        assert_eq!(motor.limits(), Some((0, 500)));
+        let uart = driver.release().0;
+        assert_eq!((uart.registers[0][0x14], uart.registers[0][0x40]), (0xfffff, 40));
+    }
+
+    #[test]
+    fn homes_on_polled_sg_result() {
+        let (mut driver, mut motor, diag) = setup(-1000);
+        let config = SensorlessConfig { sgthrs: 40, home_position: -5, ..SensorlessConfig::default() };
+        driver.home_sensorless(&mut motor, &mut PollSgResult { interval: 16 }, &config).unwrap();
+        assert_eq!(position(&diag), -1000);
+        assert_eq!(motor.position(), -5);
+    }
+
+    #[test]
+    fn gives_up_without_stall() {
+        let (mut driver, mut motor, mut diag) = setup(-1000);
+        let config = SensorlessConfig { sgthrs: 40, max_travel: 500, ..SensorlessConfig::default() };
+        let result = driver.home_sensorless(&mut motor, &mut diag, &config);
+        assert_eq!(result, Err(SensorlessError::Motor(stepper_driver::Error::Homing(HomingError::MaxTravel))));
+        assert_eq!(position(&diag), -500);
+
+        // Too sensitive, stalls at the first dip after the ignored steps
+        let config = SensorlessConfig { sgthrs: 50, ..SensorlessConfig::default() };
+        driver.home_sensorless(&mut motor, &mut diag, &config).unwrap();
+        assert_eq!(position(&diag), -600);
+    }
+
+    #[test]
+    fn calibration_finds_threshold() {
+        let (mut driver, mut motor, mut diag) = setup(-1000);
+        let config = SensorlessConfig::default();
+        let sgthrs = driver
+            .calibrate_stallguard(&mut motor, &mut diag, &config, &Calibration::default())
+            .unwrap();
+        // 2 * 47 is the first below the dips
+        assert_eq!(sgthrs, 47);
+        assert_eq!(position(&diag), -1000);
+        assert_eq!(motor.position(), 0);
+
+        let calibration = Calibration { min: 100, ..Calibration::default() };
+        let result = driver.calibrate_stallguard(&mut motor, &mut diag, &config, &calibration);
+        assert_eq!(result, Err(SensorlessError::NoThreshold));
+    }
+}
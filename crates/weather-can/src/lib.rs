@@ -0,0 +1,295 @@
+//! CAN telemetry of the weather kit.
+//!
+//! Each station broadcasts its readings once per measurement cycle as standard (11 bit) data
+//! frames, one per quantity. The identifier carries the message kind and the station's node
+//! ID, so a receiver can filter on either:
+//!
+//! | bits 10-9 | bits 8-6 | bits 5-0 |
+//! |-----------|----------|----------|
+//! | 0b10      | kind     | node ID  |
+//!
+//! which puts the telemetry at 0x400-0x5ff, below the priority of lower identifiers.
+//!
+//! Byte 0 of every payload is the sequence counter of the cycle, the same for all messages
+//! of one cycle and wrapping at 256. The values follow as little endian scaled integers:
+//!
+//! | kind            | payload after the sequence counter                         | DLC |
+//! |-----------------|------------------------------------------------------------|-----|
+//! | 0 status        | uptime u32 (s), humidity errors u8, light errors u8, flags | 8   |
+//! | 1 temperature   | i16 (0.01 °C)                                              | 3   |
+//! | 2 pressure      | station u24 (Pa), sea level u24 (Pa)                       | 7   |
+//! | 3 humidity      | u16 (0.01 %RH)                                             | 3   |
+//! | 4 illuminance   | u32 (0.01 lx)                                              | 5   |
+//!
+//! Out of range values saturate. Humidity and illuminance are not sent in a cycle where the
+//! sensor failed, the status flags tell which were.
+//!
+//! No allocation and only `core`, so the same code encodes in the firmware and decodes in
+//! host tools, with any `embedded-hal` CAN frame type.
+
+#![no_std]
+#![deny(missing_debug_implementations)]
+#![deny(unsafe_code)]
+
+use embedded_hal::can::{Frame, Id, StandardId};
+
+/// First identifier of the telemetry range
+pub const BASE_ID: u16 = 0x400;
+/// Mask of the identifier bits that mark telemetry
+const RANGE_MASK: u16 = 0x600;
+/// Highest node ID
+pub const MAX_NODE: u8 = 0x3f;
+
+/// Status flag: the humidity of this cycle was read
+pub const FLAG_HUMIDITY: u8 = 0x01;
+/// Status flag: the illuminance of this cycle was read
+pub const FLAG_ILLUMINANCE: u8 = 0x02;
+
+/// Errors
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// Node IDs are 0-63
+    InvalidNode(u8),
+    /// Extended or remote frame, or an identifier outside the telemetry range
+    NotTelemetry,
+    /// Kind in the identifier is not defined
+    UnknownKind(u8),
+    /// Payload length does not match the kind
+    Length(usize),
+    /// The frame type rejected the frame
+    Frame,
+}
+
+/// Station health, sent every cycle.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Status {
+    pub uptime_s: u32,
+    /// Failed humidity reads since start, saturating
+    pub humidity_errors: u8,
+    /// Failed illuminance reads since start, saturating
+    pub light_errors: u8,
+    /// `FLAG_HUMIDITY`, `FLAG_ILLUMINANCE`
+    pub flags: u8,
+}
+
+/// Telemetry message, values as sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Message {
+    Status(Status),
+    Temperature { centi_celsius: i16 },
+    Pressure { pascal: u32, sea_level_pascal: u32 },
+    Humidity { centi_percent: u16 },
+    Illuminance { centi_lux: u32 },
+}
+
+impl Message {
+    /// Temperature message from °C.
+    pub fn temperature(celsius: f32) -> Self {
+        Message::Temperature { centi_celsius: round(celsius * 100.0) as i16 }
+    }
+
+    /// Pressure message from station and sea level pressure in hPa.
+    pub fn pressure(hpa: f32, sea_level_hpa: f32) -> Self {
+        Message::Pressure {
+            pascal: (round(hpa * 100.0) as u32).min(U24_MAX),
+            sea_level_pascal: (round(sea_level_hpa * 100.0) as u32).min(U24_MAX),
+        }
+    }
+
+    /// Humidity message from %RH.
+    pub fn humidity(percent: f32) -> Self {
+        Message::Humidity { centi_percent: round(percent * 100.0) as u16 }
+    }
+
+    /// Illuminance message from lx.
+    pub fn illuminance(lux: f32) -> Self {
+        Message::Illuminance { centi_lux: round(lux * 100.0) as u32 }
+    }
+
+    /// Kind field of the identifier
+    pub fn kind(&self) -> u8 {
+        match self {
+            Message::Status(_) => 0,
+            Message::Temperature { .. } => 1,
+            Message::Pressure { .. } => 2,
+            Message::Humidity { .. } => 3,
+            Message::Illuminance { .. } => 4,
+        }
+    }
+}
+
+const U24_MAX: u32 = 0x00ff_ffff;
+
+/// Rounds half away from zero, the `as` cast after it saturates.
+fn round(value: f32) -> f32 {
+    if value >= 0.0 { value + 0.5 } else { value - 0.5 }
+}
+
+/// A message of one station in one cycle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Telemetry {
+    /// Node ID of the station (0-63)
+    pub node: u8,
+    /// Measurement cycle, wrapping
+    pub sequence: u8,
+    pub message: Message,
+}
+
+impl Telemetry {
+    /// Standard identifier of the frame.
+    pub fn id(&self) -> Result<u16, Error> {
+        if self.node > MAX_NODE {
+            return Err(Error::InvalidNode(self.node));
+        }
+        Ok(BASE_ID | u16::from(self.message.kind()) << 6 | u16::from(self.node))
+    }
+
+    /// Writes the payload into `data` and returns its length.
+    pub fn encode(&self, data: &mut [u8; 8]) -> usize {
+        data[0] = self.sequence;
+        match self.message {
+            Message::Status(status) => {
+                data[1..5].copy_from_slice(&status.uptime_s.to_le_bytes());
+                data[5] = status.humidity_errors;
+                data[6] = status.light_errors;
+                data[7] = status.flags;
+                8
+            }
+            Message::Temperature { centi_celsius } => {
+                data[1..3].copy_from_slice(&centi_celsius.to_le_bytes());
+                3
+            }
+            Message::Pressure { pascal, sea_level_pascal } => {
+                data[1..4].copy_from_slice(&pascal.min(U24_MAX).to_le_bytes()[..3]);
+                data[4..7].copy_from_slice(&sea_level_pascal.min(U24_MAX).to_le_bytes()[..3]);
+                7
+            }
+            Message::Humidity { centi_percent } => {
+                data[1..3].copy_from_slice(&centi_percent.to_le_bytes());
+                3
+            }
+            Message::Illuminance { centi_lux } => {
+                data[1..5].copy_from_slice(&centi_lux.to_le_bytes());
+                5
+            }
+        }
+    }
+
+    /// Decodes the frame with standard identifier `id` and payload `data`.
+    pub fn decode(id: u16, data: &[u8]) -> Result<Self, Error> {
+        if id & RANGE_MASK != BASE_ID {
+            return Err(Error::NotTelemetry);
+        }
+        let kind = (id >> 6 & 0x07) as u8;
+        let node = (id & u16::from(MAX_NODE)) as u8;
+        let expected = match kind {
+            0 => 8,
+            1 | 3 => 3,
+            2 => 7,
+            4 => 5,
+            _ => return Err(Error::UnknownKind(kind)),
+        };
+        if data.len() != expected {
+            return Err(Error::Length(data.len()));
+        }
+        let u24 = |b: &[u8]| u32::from_le_bytes([b[0], b[1], b[2], 0]);
+        let message = match kind {
+            0 => Message::Status(Status {
+                uptime_s: u32::from_le_bytes([data[1], data[2], data[3], data[4]]),
+                humidity_errors: data[5],
+                light_errors: data[6],
+                flags: data[7],
+            }),
+            1 => Message::Temperature { centi_celsius: i16::from_le_bytes([data[1], data[2]]) },
+            2 => Message::Pressure { pascal: u24(&data[1..4]), sea_level_pascal: u24(&data[4..7]) },
+            3 => Message::Humidity { centi_percent: u16::from_le_bytes([data[1], data[2]]) },
+            _ => Message::Illuminance { centi_lux: u32::from_le_bytes([data[1], data[2], data[3], data[4]]) },
+        };
+        Ok(Telemetry { node, sequence: data[0], message })
+    }
+
+    /// Builds the CAN frame.
+    pub fn to_frame<F: Frame>(&self) -> Result<F, Error> {
+        let id = StandardId::new(self.id()?).ok_or(Error::NotTelemetry)?;
+        let mut data = [0; 8];
+        let len = self.encode(&mut data);
+        F::new(id, &data[..len]).ok_or(Error::Frame)
+    }
+
+    /// Decodes a received CAN frame.
+    pub fn from_frame<F: Frame>(frame: &F) -> Result<Self, Error> {
+        match frame.id() {
+            Id::Standard(id) if frame.is_data_frame() => Self::decode(id.as_raw(), frame.data()),
+            _ => Err(Error::NotTelemetry),
+        }
+    }
+}
+
+/// Number of cycles missed between two received sequence counters of a station.
+pub fn missed(previous: u8, sequence: u8) -> u8 {
+    sequence.wrapping_sub(previous).wrapping_sub(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use can_test_frame::TestFrame;
+
+    fn round_trip(telemetry: Telemetry) -> TestFrame {
+        let frame: TestFrame = telemetry.to_frame().unwrap();
+        assert_eq!(Telemetry::from_frame(&frame), Ok(telemetry));
+        frame
+    }
+
+    #[test]
+    fn encodes_frames() {
+        let frame = round_trip(Telemetry { node: 5, sequence: 42, message: Message::temperature(-12.345) });
+        assert_eq!(frame.id(), Id::Standard(StandardId::new(0x445).unwrap()));
+        assert_eq!(frame.data(), [42, 0x2d, 0xfb]);
+
+        let frame = round_trip(Telemetry { node: 63, sequence: 0, message: Message::pressure(1013.25, 1019.0) });
+        assert_eq!(frame.id(), Id::Standard(StandardId::new(0x4bf).unwrap()));
+        assert_eq!(frame.data(), [0, 0xcd, 0x8b, 0x01, 0x0c, 0x8e, 0x01]);
+
+        let status = Status { uptime_s: 86_400, humidity_errors: 3, light_errors: 0, flags: FLAG_ILLUMINANCE };
+        let frame = round_trip(Telemetry { node: 1, sequence: 255, message: Message::Status(status) });
+        assert_eq!(frame.id(), Id::Standard(StandardId::new(0x401).unwrap()));
+        assert_eq!(frame.data(), [255, 0x80, 0x51, 0x01, 0x00, 3, 0, 2]);
+
+        round_trip(Telemetry { node: 2, sequence: 7, message: Message::humidity(55.5) });
+        round_trip(Telemetry { node: 2, sequence: 7, message: Message::illuminance(54_612.5) });
+    }
+
+    #[test]
+    fn scales_and_saturates() {
+        assert_eq!(Message::temperature(21.456), Message::Temperature { centi_celsius: 2146 });
+        assert_eq!(Message::temperature(500.0), Message::Temperature { centi_celsius: i16::MAX });
+        assert_eq!(Message::humidity(-1.0), Message::Humidity { centi_percent: 0 });
+        assert_eq!(Message::illuminance(f32::NAN), Message::Illuminance { centi_lux: 0 });
+        let message = Message::pressure(200_000.0, 1013.25);
+        assert_eq!(message, Message::Pressure { pascal: U24_MAX, sea_level_pascal: 101_325 });
+    }
+
+    #[test]
+    fn rejects_invalid_frames() {
+        let telemetry = Telemetry { node: 64, sequence: 0, message: Message::humidity(50.0) };
+        assert_eq!(telemetry.to_frame::<TestFrame>(), Err(Error::InvalidNode(64)));
+
+        assert_eq!(Telemetry::decode(0x000, &[0, 0, 0]), Err(Error::NotTelemetry));
+        assert_eq!(Telemetry::decode(0x645, &[0, 0, 0]), Err(Error::NotTelemetry));
+        assert_eq!(Telemetry::decode(0x5c0, &[0, 0, 0]), Err(Error::UnknownKind(7)));
+        assert_eq!(Telemetry::decode(0x445, &[0, 0, 0, 0]), Err(Error::Length(4)));
+
+        let remote = TestFrame::new_remote(StandardId::new(0x445).unwrap(), 3).unwrap();
+        assert_eq!(Telemetry::from_frame(&remote), Err(Error::NotTelemetry));
+        let extended = TestFrame::new(embedded_hal::can::ExtendedId::new(0x445).unwrap(), &[0; 3]).unwrap();
+        assert_eq!(Telemetry::from_frame(&extended), Err(Error::NotTelemetry));
+    }
+
+    #[test]
+    fn counts_missed_cycles() {
+        assert_eq!(missed(10, 11), 0);
+        assert_eq!(missed(10, 13), 2);
+        assert_eq!(missed(255, 1), 1);
+    }
+}
@@ -0,0 +1,414 @@
+//! History buffers and chart widgets for the OLED.
+//!
+//! [`History`] keeps a decimated series of readings, [`Chart`] draws such a series as a
+//! sparkline or a bar graph into any `DrawTarget<Color = BinaryColor>`, auto-scaling the
+//! vertical axis to the data.
+
+use core::fmt::Write;
+
+use embedded_graphics::{
+    mono_font::{ascii::FONT_6X10, MonoTextStyle},
+    pixelcolor::BinaryColor,
+    prelude::*,
+    primitives::{Line, PrimitiveStyle, Rectangle},
+    text::{Baseline, Text},
+};
+use heapless::{Deque, String};
+
+const LABEL_STYLE: MonoTextStyle<'static, BinaryColor> = MonoTextStyle::new(&FONT_6X10, BinaryColor::On);
+const LABEL_CHAR_WIDTH: u32 = 6;
+const LABEL_HEIGHT: u32 = 10;
+
+/// Fixed size history of readings taken at most every `interval_s` seconds.
+///
+/// The oldest value is dropped once `N` values are stored, so `N * interval_s` is the time
+/// span covered, e.g. `History<96>` with a 15 minute interval holds 24 hours.
+#[derive(Debug, Clone)]
+pub struct History<const N: usize> {
+    interval_s: u32,
+    last_s: Option<u32>,
+    values: Deque<f32, N>,
+}
+
+impl<const N: usize> History<N> {
+    pub const fn new(interval_s: u32) -> Self {
+        History { interval_s, last_s: None, values: Deque::new() }
+    }
+
+    /// Records `value` taken at `now_s` (monotonic seconds), unless the previous value is
+    /// less than one interval old.
+    pub fn push(&mut self, now_s: u32, value: f32) {
+        if let Some(last) = self.last_s {
+            if now_s.wrapping_sub(last) < self.interval_s {
+                return;
+            }
+        }
+        if self.values.is_full() {
+            self.values.pop_front();
+        }
+        let _ = self.values.push_back(value);
+        self.last_s = Some(now_s);
+    }
+
+    /// Values from oldest to newest.
+    pub fn iter(&self) -> impl Iterator<Item = f32> + Clone + '_ {
+        self.values.iter().copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.values.clear();
+        self.last_s = None;
+    }
+}
+
+/// Vertical range a chart is scaled to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Scale {
+    pub min: f32,
+    pub max: f32,
+}
+
+impl Scale {
+    /// Fits the scale to `data`, widened around its center to at least `min_span`.
+    ///
+    /// Returns `None` for an empty series.
+    pub fn fit<I: Iterator<Item = f32>>(data: I, min_span: f32) -> Option<Scale> {
+        let mut scale: Option<Scale> = None;
+        for v in data {
+            scale = Some(match scale {
+                Some(s) => Scale { min: s.min.min(v), max: s.max.max(v) },
+                None => Scale { min: v, max: v },
+            });
+        }
+        let mut scale = scale?;
+        if scale.max - scale.min < min_span {
+            let center = (scale.max + scale.min) / 2.0;
+            scale.min = center - min_span / 2.0;
+            scale.max = center + min_span / 2.0;
+        }
+        Some(scale)
+    }
+
+    /// Maps `value` to a pixel row inside `top..=bottom`, `max` at the top.
+    fn y(&self, value: f32, top: i32, bottom: i32) -> i32 {
+        let span = self.max - self.min;
+        let ratio = if span > 0.0 { (value - self.min) / span } else { 0.5 };
+        bottom - (ratio.clamp(0.0, 1.0) * (bottom - top) as f32 + 0.5) as i32
+    }
+}
+
+/// How a chart plots its data
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Plot {
+    /// Connected line through all values.
+    Line,
+    /// One bar per value, from the bottom of the plot.
+    Bars,
+}
+
+/// Chart of a data series, drawn into `area`.
+///
+/// With axes enabled the max and min labels are printed left of the plot, top and bottom
+/// aligned, and the y and x axes are drawn along the plot edges.
+#[derive(Debug, Clone)]
+pub struct Chart<I> {
+    area: Rectangle,
+    data: I,
+    plot: Plot,
+    axes: bool,
+    precision: usize,
+    min_span: f32,
+}
+
+impl<I> Chart<I>
+    where
+        I: Iterator<Item = f32> + Clone,
+{
+    /// Bare line chart without axes or labels.
+    pub fn sparkline(area: Rectangle, data: I) -> Self {
+        Chart { area, data, plot: Plot::Line, axes: false, precision: 1, min_span: 1.0 }
+    }
+
+    /// Bar graph with axes and min/max labels.
+    pub fn bar_graph(area: Rectangle, data: I) -> Self {
+        Chart { area, data, plot: Plot::Bars, axes: true, precision: 1, min_span: 1.0 }
+    }
+
+    pub fn with_axes(mut self, axes: bool) -> Self {
+        self.axes = axes;
+        self
+    }
+
+    /// Number of decimals in the min/max labels.
+    pub fn with_precision(mut self, precision: usize) -> Self {
+        self.precision = precision;
+        self
+    }
+
+    /// Smallest vertical range, keeps sensor noise from filling the whole plot.
+    pub fn with_min_span(mut self, min_span: f32) -> Self {
+        self.min_span = min_span;
+        self
+    }
+
+    /// Scale the data is plotted with, `None` if there is no data.
+    pub fn scale(&self) -> Option<Scale> {
+        Scale::fit(self.data.clone(), self.min_span)
+    }
+
+    fn label(&self, value: f32) -> String<12> {
+        let mut s = String::new();
+        let _ = write!(s, "{:.*}", self.precision, value);
+        s
+    }
+
+    fn draw_axes<D>(&self, target: &mut D, scale: Scale) -> Result<Rectangle, D::Error>
+        where
+            D: DrawTarget<Color = BinaryColor>,
+    {
+        let max = self.label(scale.max);
+        let min = self.label(scale.min);
+        let label_width = max.len().max(min.len()) as u32 * LABEL_CHAR_WIDTH;
+
+        let top_left = self.area.top_left;
+        let bottom = top_left.y + self.area.size.height as i32 - 1;
+        Text::with_baseline(&max, top_left, LABEL_STYLE, Baseline::Top).draw(target)?;
+        Text::with_baseline(&min, Point::new(top_left.x, bottom), LABEL_STYLE, Baseline::Bottom)
+            .draw(target)?;
+
+        // Axes sit one pixel outside the plot so they never overlap data.
+        let axis_x = top_left.x + label_width as i32 + 1;
+        let right = top_left.x + self.area.size.width as i32 - 1;
+        let stroke = PrimitiveStyle::with_stroke(BinaryColor::On, 1);
+        Line::new(Point::new(axis_x, top_left.y), Point::new(axis_x, bottom))
+            .into_styled(stroke)
+            .draw(target)?;
+        Line::new(Point::new(axis_x, bottom), Point::new(right, bottom))
+            .into_styled(stroke)
+            .draw(target)?;
+
+        let height = self.area.size.height.saturating_sub(1);
+        let width = self.area.size.width.saturating_sub(label_width + 2);
+        Ok(Rectangle::new(Point::new(axis_x + 1, top_left.y), Size::new(width, height)))
+    }
+}
+
+impl<I> Drawable for Chart<I>
+    where
+        I: Iterator<Item = f32> + Clone,
+{
+    type Color = BinaryColor;
+    type Output = ();
+
+    fn draw<D>(&self, target: &mut D) -> Result<(), D::Error>
+        where
+            D: DrawTarget<Color = BinaryColor>,
+    {
+        let scale = match self.scale() {
+            Some(scale) => scale,
+            None => return Ok(()),
+        };
+        let plot = if self.axes && self.area.size.height >= 2 * LABEL_HEIGHT {
+            self.draw_axes(target, scale)?
+        } else {
+            self.area
+        };
+        if plot.is_zero_sized() {
+            return Ok(());
+        }
+
+        let count = self.data.clone().count() as i32;
+        let left = plot.top_left.x;
+        let width = plot.size.width as i32;
+        let top = plot.top_left.y;
+        let bottom = top + plot.size.height as i32 - 1;
+        let stroke = PrimitiveStyle::with_stroke(BinaryColor::On, 1);
+
+        match self.plot {
+            Plot::Line => {
+                let x = |i: i32| if count > 1 { left + i * (width - 1) / (count - 1) } else { left };
+                let mut previous: Option<Point> = None;
+                for (i, v) in self.data.clone().enumerate() {
+                    let point = Point::new(x(i as i32), scale.y(v, top, bottom));
+                    match previous {
+                        Some(p) => Line::new(p, point).into_styled(stroke).draw(target)?,
+                        None => Pixel(point, BinaryColor::On).draw(target)?,
+                    }
+                    previous = Some(point);
+                }
+            }
+            Plot::Bars => {
+                // Leave a one pixel gap between bars when there is room for it.
+                let slot = (width / count).max(1);
+                let bar = if slot > 2 { slot - 1 } else { slot };
+                for (i, v) in self.data.clone().enumerate() {
+                    let x = left + i as i32 * width / count;
+                    let y = scale.y(v, top, bottom);
+                    Rectangle::with_corners(Point::new(x, y), Point::new(x + bar - 1, bottom))
+                        .into_styled(PrimitiveStyle::with_fill(BinaryColor::On))
+                        .draw(target)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embedded_graphics::mock_display::MockDisplay;
+
+    use super::*;
+
+    fn display() -> MockDisplay<BinaryColor> {
+        let mut display = MockDisplay::new();
+        // Neighbouring line segments share their end points
+        display.set_allow_overdraw(true);
+        display
+    }
+
+    fn area(width: u32, height: u32) -> Rectangle {
+        Rectangle::new(Point::zero(), Size::new(width, height))
+    }
+
+    #[test]
+    fn scale_fits_data() {
+        assert_eq!(Scale::fit([].into_iter(), 1.0), None);
+        assert_eq!(Scale::fit([3.0, -1.0, 2.0].into_iter(), 1.0), Some(Scale { min: -1.0, max: 3.0 }));
+        // Narrow data is widened around its center
+        assert_eq!(Scale::fit([10.0, 11.0].into_iter(), 4.0), Some(Scale { min: 8.5, max: 12.5 }));
+        assert_eq!(Scale::fit([5.0].into_iter(), 0.0), Some(Scale { min: 5.0, max: 5.0 }));
+    }
+
+    #[test]
+    fn scale_maps_max_to_top() {
+        let scale = Scale { min: 0.0, max: 10.0 };
+        assert_eq!(scale.y(10.0, 20, 30), 20);
+        assert_eq!(scale.y(0.0, 20, 30), 30);
+        assert_eq!(scale.y(5.0, 20, 30), 25);
+        // Out of range values stay inside the plot
+        assert_eq!(scale.y(-5.0, 20, 30), 30);
+        assert_eq!(scale.y(15.0, 20, 30), 20);
+        // A flat scale puts everything in the middle
+        assert_eq!(Scale { min: 1.0, max: 1.0 }.y(1.0, 20, 30), 25);
+    }
+
+    #[test]
+    fn history_keeps_one_value_per_interval() {
+        let mut history = History::<3>::new(60);
+        history.push(0, 1.0);
+        history.push(59, 2.0);
+        history.push(60, 3.0);
+        history.push(100, 4.0);
+        history.push(130, 5.0);
+        assert!(history.iter().eq([1.0, 3.0, 5.0]));
+
+        // The oldest value is dropped when full
+        history.push(190, 6.0);
+        assert_eq!(history.len(), 3);
+        assert!(history.iter().eq([3.0, 5.0, 6.0]));
+
+        history.clear();
+        assert!(history.is_empty());
+        history.push(0, 7.0);
+        assert!(history.iter().eq([7.0]));
+    }
+
+    #[test]
+    fn history_survives_clock_wrap() {
+        let mut history = History::<4>::new(60);
+        history.push(u32::MAX - 30, 1.0);
+        history.push(10, 2.0);
+        history.push(40, 3.0);
+        assert!(history.iter().eq([1.0, 3.0]));
+    }
+
+    #[test]
+    fn sparkline() {
+        let mut display = display();
+        let data = [0.0, 1.0, 2.0, 3.0, 3.0, 1.0];
+        Chart::sparkline(area(6, 4), data.into_iter()).draw(&mut display).unwrap();
+        display.assert_pattern(&[
+            "   ## ",
+            "  # # ",
+            " #   #",
+            "#     ",
+        ]);
+    }
+
+    #[test]
+    fn sparkline_of_single_value() {
+        let mut display = display();
+        Chart::sparkline(area(5, 5), [5.0].into_iter()).draw(&mut display).unwrap();
+        display.assert_pattern(&["     ", "     ", "#    "]);
+    }
+
+    #[test]
+    fn bars_with_gaps() {
+        let mut display = display();
+        let data = [1.0, 2.0, 3.0, 4.0];
+        Chart::bar_graph(area(12, 4), data.into_iter()).draw(&mut display).unwrap();
+        display.assert_pattern(&[
+            "         ##",
+            "      ## ##",
+            "   ## ## ##",
+            "## ## ## ##",
+        ]);
+    }
+
+    #[test]
+    fn bars_without_room_for_gaps() {
+        let mut display = display();
+        let data = [2.0, 1.0, 2.0, 1.0];
+        Chart::bar_graph(area(4, 2), data.into_iter()).draw(&mut display).unwrap();
+        display.assert_pattern(&[
+            "# # ",
+            "####",
+        ]);
+    }
+
+    #[test]
+    fn axes_and_labels() {
+        let mut display = display();
+        let chart = Chart::sparkline(area(40, 20), [0.0, 9.0].into_iter()).with_axes(true).with_precision(0);
+        chart.draw(&mut display).unwrap();
+
+        // One character labels, the y axis one pixel right of them
+        let axis_x = LABEL_CHAR_WIDTH as i32 + 1;
+        assert!((0..20).all(|y| display.get_pixel(Point::new(axis_x, y)) == Some(BinaryColor::On)));
+        assert!((axis_x..40).all(|x| display.get_pixel(Point::new(x, 19)) == Some(BinaryColor::On)));
+        // The plot starts right of the axis, with the max in the top and the min in the bottom row
+        assert_eq!(display.get_pixel(Point::new(axis_x + 1, 18)), Some(BinaryColor::On));
+        assert_eq!(display.get_pixel(Point::new(39, 0)), Some(BinaryColor::On));
+        assert_eq!(display.affected_area(), area(40, 20));
+    }
+
+    #[test]
+    fn axes_need_room_for_both_labels() {
+        let mut display = display();
+        let chart = Chart::sparkline(area(8, 4), [0.0, 1.0].into_iter()).with_axes(true);
+        chart.draw(&mut display).unwrap();
+        // Drawn as a bare sparkline
+        display.assert_pattern(&[
+            "      ##",
+            "    ##  ",
+            "  ##    ",
+            "##      ",
+        ]);
+    }
+
+    #[test]
+    fn no_data_draws_nothing() {
+        let mut display = display();
+        Chart::bar_graph(area(40, 20), [].into_iter()).draw(&mut display).unwrap();
+        assert_eq!(display, MockDisplay::new());
+    }
+}
@@ -0,0 +1,400 @@
+//! Barometric pressure trend and Zambretti short-term forecast.
+//!
+//! [`PressureTrend`] keeps a decimated history of sea-level pressure over the last three hours
+//! and derives the tendency from a least-squares fit. [`zambretti`] turns the current
+//! sea-level pressure, the tendency and the month into one of the 26 classic Zambretti
+//! forecasts.
+
+use heapless::Deque;
+use libm::{powf, roundf};
+
+/// Length of the trend window (seconds).
+pub const TREND_WINDOW_S: u32 = 3 * 60 * 60;
+
+/// Change over the trend window below which pressure is considered steady (hPa per 3 h).
+const STEADY_THRESHOLD_HPA: f32 = 1.6;
+
+/// Minimum history span before a trend is reported (seconds).
+const MIN_SPAN_S: u32 = 30 * 60;
+
+/// Reduces station pressure to mean sea level (hypsometric formula).
+pub fn sea_level_pressure(station_hpa: f32, altitude_m: f32, temp_c: f32) -> f32 {
+    let lapse = 0.0065 * altitude_m;
+    station_hpa * powf(1.0 - lapse / (temp_c + lapse + 273.15), -5.257)
+}
+
+/// Pressure tendency
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tendency {
+    Rising,
+    Steady,
+    Falling,
+}
+
+/// Pressure tendency together with its rate of change.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Trend {
+    pub tendency: Tendency,
+    /// Rate of change (hPa/h), positive when rising.
+    pub rate_hpa_per_h: f32,
+}
+
+/// Tracks pressure over a 3 hour window using `N` evenly spaced samples.
+///
+/// Samples pushed more often than every `TREND_WINDOW_S / N` seconds are dropped, so the
+/// tracker can be fed straight from the measurement loop. `N` must not be 0.
+pub struct PressureTrend<const N: usize> {
+    samples: Deque<(u32, f32), N>,
+}
+
+impl<const N: usize> PressureTrend<N> {
+    /// Shortest spacing of the samples (seconds), fails to compile for `N = 0`.
+    const INTERVAL_S: u32 = {
+        assert!(N > 0, "PressureTrend needs room for at least one sample");
+        TREND_WINDOW_S / N as u32
+    };
+
+    pub const fn new() -> Self {
+        let _ = Self::INTERVAL_S;
+        PressureTrend { samples: Deque::new() }
+    }
+
+    /// Records a sea-level pressure reading taken at `now_s` (monotonic seconds).
+    pub fn push(&mut self, now_s: u32, hpa: f32) {
+        if let Some(&(last, _)) = self.samples.back() {
+            if now_s.wrapping_sub(last) < Self::INTERVAL_S {
+                return;
+            }
+        }
+        while let Some(&(first, _)) = self.samples.front() {
+            if now_s.wrapping_sub(first) > TREND_WINDOW_S || self.samples.is_full() {
+                self.samples.pop_front();
+            } else {
+                break;
+            }
+        }
+        // Cannot fail, there is room after the loop above.
+        let _ = self.samples.push_back((now_s, hpa));
+    }
+
+    /// Most recent reading.
+    pub fn latest(&self) -> Option<f32> {
+        self.samples.back().map(|&(_, hpa)| hpa)
+    }
+
+    /// Current trend, `None` until at least 30 minutes of history are available.
+    pub fn trend(&self) -> Option<Trend> {
+        let (t0, _) = *self.samples.front()?;
+        let (t1, _) = *self.samples.back()?;
+        if t1.wrapping_sub(t0) < MIN_SPAN_S {
+            return None;
+        }
+
+        // Least-squares slope, with time relative to the first sample in hours.
+        let n = self.samples.len() as f32;
+        let (mut sx, mut sy, mut sxx, mut sxy) = (0.0, 0.0, 0.0, 0.0);
+        for &(t, p) in self.samples.iter() {
+            let x = t.wrapping_sub(t0) as f32 / 3600.0;
+            sx += x;
+            sy += p;
+            sxx += x * x;
+            sxy += x * p;
+        }
+        let rate = (n * sxy - sx * sy) / (n * sxx - sx * sx);
+
+        let change = rate * (TREND_WINDOW_S as f32 / 3600.0);
+        let tendency = if change >= STEADY_THRESHOLD_HPA {
+            Tendency::Rising
+        } else if change <= -STEADY_THRESHOLD_HPA {
+            Tendency::Falling
+        } else {
+            Tendency::Steady
+        };
+        Some(Trend { tendency, rate_hpa_per_h: rate })
+    }
+
+    pub fn clear(&mut self) {
+        self.samples.clear();
+    }
+}
+
+impl<const N: usize> Default for PressureTrend<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Hemisphere, used to decide whether a month is summer or winter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hemisphere {
+    Northern,
+    Southern,
+}
+
+/// Zambretti forecast letters A-Z.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Forecast {
+    SettledFine,
+    FineWeather,
+    BecomingFine,
+    FineBecomingLessSettled,
+    FinePossiblyShowers,
+    FairlyFineImproving,
+    FairlyFinePossiblyShowersEarly,
+    FairlyFineShoweryLater,
+    ShoweryEarlyImproving,
+    ChangeableMending,
+    FairlyFineShowersLikely,
+    RatherUnsettledClearingLater,
+    UnsettledProbablyImproving,
+    ShoweryBrightIntervals,
+    ShoweryBecomingLessSettled,
+    ChangeableSomeRain,
+    UnsettledShortFineIntervals,
+    UnsettledRainLater,
+    UnsettledRainAtTimes,
+    VeryUnsettledFinerAtTimes,
+    RainAtTimesWorseLater,
+    RainAtTimesVeryUnsettled,
+    RainAtFrequentIntervals,
+    VeryUnsettledRain,
+    StormyPossiblyImproving,
+    StormyMuchRain,
+}
+
+impl Forecast {
+    /// Zambretti letter, `'A'` (best) to `'Z'` (worst).
+    pub fn letter(&self) -> char {
+        (b'A' + *self as u8) as char
+    }
+
+    pub fn description(&self) -> &'static str {
+        use Forecast::*;
+        match *self {
+            SettledFine => "Settled fine",
+            FineWeather => "Fine weather",
+            BecomingFine => "Becoming fine",
+            FineBecomingLessSettled => "Fine, becoming less settled",
+            FinePossiblyShowers => "Fine, possibly showers",
+            FairlyFineImproving => "Fairly fine, improving",
+            FairlyFinePossiblyShowersEarly => "Fairly fine, possibly showers early",
+            FairlyFineShoweryLater => "Fairly fine, showery later",
+            ShoweryEarlyImproving => "Showery early, improving",
+            ChangeableMending => "Changeable, mending",
+            FairlyFineShowersLikely => "Fairly fine, showers likely",
+            RatherUnsettledClearingLater => "Rather unsettled, clearing later",
+            UnsettledProbablyImproving => "Unsettled, probably improving",
+            ShoweryBrightIntervals => "Showery, bright intervals",
+            ShoweryBecomingLessSettled => "Showery, becoming less settled",
+            ChangeableSomeRain => "Changeable, some rain",
+            UnsettledShortFineIntervals => "Unsettled, short fine intervals",
+            UnsettledRainLater => "Unsettled, rain later",
+            UnsettledRainAtTimes => "Unsettled, rain at times",
+            VeryUnsettledFinerAtTimes => "Very unsettled, finer at times",
+            RainAtTimesWorseLater => "Rain at times, worse later",
+            RainAtTimesVeryUnsettled => "Rain at times, becoming very unsettled",
+            RainAtFrequentIntervals => "Rain at frequent intervals",
+            VeryUnsettledRain => "Very unsettled, rain",
+            StormyPossiblyImproving => "Stormy, possibly improving",
+            StormyMuchRain => "Stormy, much rain",
+        }
+    }
+}
+
+const FALLING: [Forecast; 9] = {
+    use Forecast::*;
+    [
+        SettledFine, FineWeather, FineBecomingLessSettled, FairlyFineShoweryLater,
+        ShoweryBecomingLessSettled, UnsettledRainLater, RainAtTimesWorseLater,
+        RainAtTimesVeryUnsettled, VeryUnsettledRain,
+    ]
+};
+
+const STEADY: [Forecast; 10] = {
+    use Forecast::*;
+    [
+        SettledFine, FineWeather, FinePossiblyShowers, FairlyFineShowersLikely,
+        ShoweryBrightIntervals, ChangeableSomeRain, UnsettledRainAtTimes,
+        RainAtFrequentIntervals, VeryUnsettledRain, StormyMuchRain,
+    ]
+};
+
+const RISING: [Forecast; 13] = {
+    use Forecast::*;
+    [
+        SettledFine, FineWeather, BecomingFine, FairlyFineImproving,
+        FairlyFinePossiblyShowersEarly, ShoweryEarlyImproving, ChangeableMending,
+        RatherUnsettledClearingLater, UnsettledProbablyImproving, UnsettledShortFineIntervals,
+        VeryUnsettledFinerAtTimes, StormyPossiblyImproving, StormyMuchRain,
+    ]
+};
+
+/// Zambretti forecast from sea-level pressure (hPa), tendency and month (1-12).
+///
+/// Uses the numeric form of the Zambretti dial: falling `Z = 127 - 0.12 P`, steady
+/// `Z = 144 - 0.13 P`, rising `Z = 185 - 0.16 P`, with the pressure nudged up when rising in
+/// summer and down when falling in winter.
+pub fn zambretti(sea_level_hpa: f32, tendency: Tendency, month: u8, hemisphere: Hemisphere) -> Forecast {
+    let northern_summer = (4..=9).contains(&month);
+    let summer = match hemisphere {
+        Hemisphere::Northern => northern_summer,
+        Hemisphere::Southern => !northern_summer,
+    };
+
+    // Seasonal correction of 7% of the 950-1050 hPa dial range.
+    let mut p = sea_level_hpa;
+    match tendency {
+        Tendency::Rising if summer => p += 7.0,
+        Tendency::Falling if !summer => p -= 7.0,
+        _ => (),
+    }
+
+    let (z, first, table): (f32, u8, &[Forecast]) = match tendency {
+        Tendency::Falling => (127.0 - 0.12 * p, 1, &FALLING),
+        Tendency::Steady => (144.0 - 0.13 * p, 10, &STEADY),
+        Tendency::Rising => (185.0 - 0.16 * p, 20, &RISING),
+    };
+    let index = (roundf(z) as i32 - first as i32).clamp(0, table.len() as i32 - 1);
+    table[index as usize]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// (altitude m, pressure hPa, temperature °C) of the ICAO standard atmosphere, which
+    /// reduces to 1013.25 hPa at sea level.
+    const STANDARD_ATMOSPHERE: [(f32, f32, f32); 5] = [
+        (0.0, 1013.25, 15.0),
+        (250.0, 983.63, 13.38),
+        (500.0, 954.61, 11.75),
+        (1000.0, 898.75, 8.5),
+        (2000.0, 794.95, 2.0),
+    ];
+
+    #[test]
+    fn sea_level_pressure_of_standard_atmosphere() {
+        for (altitude_m, station_hpa, temp_c) in STANDARD_ATMOSPHERE {
+            let reduced = sea_level_pressure(station_hpa, altitude_m, temp_c);
+            assert!((reduced - 1013.25).abs() < 0.3, "{} m: {} hPa", altitude_m, reduced);
+        }
+    }
+
+    #[test]
+    fn sea_level_pressure_depends_on_temperature() {
+        // Colder air is denser, so the same station pressure reduces to more at sea level
+        let cold = sea_level_pressure(950.0, 500.0, -10.0);
+        let warm = sea_level_pressure(950.0, 500.0, 30.0);
+        assert!(cold > warm);
+        assert_eq!(sea_level_pressure(1000.0, 0.0, 20.0), 1000.0);
+    }
+
+    /// Tracker fed every `step_s` seconds from `start_s` for `duration_s`, with pressure
+    /// changing by `rate` hPa/h from 1013 hPa.
+    fn linear<const N: usize>(start_s: u32, step_s: u32, duration_s: u32, rate: f32) -> PressureTrend<N> {
+        let mut trend = PressureTrend::new();
+        for t in (0..=duration_s).step_by(step_s as usize) {
+            trend.push(start_s.wrapping_add(t), 1013.0 + rate * t as f32 / 3600.0);
+        }
+        trend
+    }
+
+    fn times<const N: usize>(trend: &PressureTrend<N>) -> impl Iterator<Item = u32> + '_ {
+        trend.samples.iter().map(|&(t, _)| t)
+    }
+
+    #[test]
+    fn trend_keeps_one_sample_per_interval() {
+        // 36 samples over 3 h, one per 300 s
+        let mut trend = PressureTrend::<36>::new();
+        for (t, hpa) in [(0, 1000.0), (100, 1001.0), (299, 1002.0), (300, 1003.0), (650, 1004.0)] {
+            trend.push(t, hpa);
+        }
+        assert!(times(&trend).eq([0, 300, 650]));
+        assert_eq!(trend.latest(), Some(1004.0));
+    }
+
+    #[test]
+    fn trend_forgets_samples_older_than_the_window() {
+        // Room for 72 samples, one every 600 s over 4 h keeps the last 3 h
+        let trend = linear::<72>(0, 600, 4 * 3600, 0.0);
+        assert_eq!(times(&trend).next(), Some(3600));
+        assert_eq!(trend.samples.len(), 19);
+
+        // A full history drops the oldest sample even inside the window
+        let trend = linear::<36>(0, 300, 4 * 3600, 0.0);
+        assert_eq!(trend.samples.len(), 36);
+        assert_eq!(times(&trend).next(), Some(4 * 3600 - 35 * 300));
+    }
+
+    #[test]
+    fn trend_survives_clock_wrap() {
+        let trend = linear::<36>(u32::MAX - 900, 300, 3600, -1.0);
+        assert_eq!(trend.samples.len(), 13);
+        let result = trend.trend().unwrap();
+        assert_eq!(result.tendency, Tendency::Falling);
+        assert!((result.rate_hpa_per_h + 1.0).abs() < 0.01, "{}", result.rate_hpa_per_h);
+    }
+
+    #[test]
+    fn trend_needs_half_an_hour() {
+        assert_eq!(PressureTrend::<36>::new().trend(), None);
+        assert_eq!(linear::<36>(0, 300, 1500, 3.0).trend(), None);
+        let trend = linear::<36>(0, 300, 1800, 3.0).trend().unwrap();
+        assert_eq!(trend.tendency, Tendency::Rising);
+        assert!((trend.rate_hpa_per_h - 3.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn tendency_limits() {
+        // ±1.6 hPa per 3 h
+        for (rate, tendency) in [
+            (0.55, Tendency::Rising),
+            (0.5, Tendency::Steady),
+            (0.0, Tendency::Steady),
+            (-0.5, Tendency::Steady),
+            (-0.55, Tendency::Falling),
+        ] {
+            let trend = linear::<36>(0, 300, 3 * 3600, rate).trend().unwrap();
+            assert_eq!(trend.tendency, tendency, "{} hPa/h", rate);
+        }
+    }
+
+    /// Letters of the Zambretti dial, best to worst, for each tendency.
+    #[test]
+    fn zambretti_tables() {
+        assert!(FALLING.iter().map(Forecast::letter).eq("ABDHORUVX".chars()));
+        assert!(STEADY.iter().map(Forecast::letter).eq("ABEKNPSWXZ".chars()));
+        assert!(RISING.iter().map(Forecast::letter).eq("ABCFGIJLMQTYZ".chars()));
+        assert_eq!(Forecast::StormyMuchRain.letter(), 'Z');
+        assert_eq!(Forecast::FinePossiblyShowers.description(), "Fine, possibly showers");
+    }
+
+    #[test]
+    fn zambretti_forecasts() {
+        use Forecast::*;
+        use Hemisphere::*;
+        for (hpa, tendency, month, hemisphere, forecast) in [
+            // Z = 127 - 0.12 * 1000 = 7
+            (1000.0, Tendency::Falling, 7, Northern, RainAtTimesWorseLater),
+            // Winter: 993 hPa, Z = 7.84
+            (1000.0, Tendency::Falling, 1, Northern, RainAtTimesVeryUnsettled),
+            // Z = 144 - 0.13 * 1020 = 11.4
+            (1020.0, Tendency::Steady, 1, Northern, FineWeather),
+            (1020.0, Tendency::Steady, 7, Southern, FineWeather),
+            // Summer: 1007 hPa, Z = 23.88
+            (1000.0, Tendency::Rising, 7, Northern, FairlyFinePossiblyShowersEarly),
+            // Z = 185 - 0.16 * 1000 = 25
+            (1000.0, Tendency::Rising, 1, Northern, ShoweryEarlyImproving),
+            // January is summer in the south
+            (1000.0, Tendency::Rising, 1, Southern, FairlyFinePossiblyShowersEarly),
+            (1000.0, Tendency::Rising, 7, Southern, ShoweryEarlyImproving),
+            // Off the dial
+            (1100.0, Tendency::Falling, 7, Northern, SettledFine),
+            (940.0, Tendency::Steady, 7, Northern, StormyMuchRain),
+            (900.0, Tendency::Rising, 1, Northern, StormyMuchRain),
+        ] {
+            assert_eq!(zambretti(hpa, tendency, month, hemisphere), forecast, "{} hPa {:?} month {}", hpa, tendency, month);
+        }
+    }
+}
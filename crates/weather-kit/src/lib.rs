@@ -0,0 +1,14 @@
+//! The parts of the weather kit firmware that do not touch the hardware.
+//!
+//! [`meteo`] derives dew point, heat index and the other humidity quantities from a
+//! temperature and humidity reading, [`forecast`] reduces pressure to sea level and tracks
+//! its trend for the Zambretti forecast. [`ui`] draws the display pages and [`chart`] the
+//! history charts on them through `embedded-graphics`. The firmware uses them as
+//! `hal_exp::meteo`, `hal_exp::forecast`, `hal_exp::ui` and `hal_exp::chart`.
+
+#![no_std]
+
+pub mod chart;
+pub mod forecast;
+pub mod meteo;
+pub mod ui;
@@ -0,0 +1,215 @@
+//! Derived meteorological quantities.
+//!
+//! Pure functions computing dew point, heat index, humidex, absolute humidity and vapor
+//! pressure deficit from the air temperature (°C) and relative humidity (%) read from the
+//! BMP180 and DHT11 sensors.
+
+use libm::{expf, logf, sqrtf};
+
+/// Magnus coefficients (Sonntag 1990), valid for -45..60 °C over water.
+const MAGNUS_B: f32 = 17.62;
+const MAGNUS_C: f32 = 243.12;
+/// Saturation vapor pressure at 0 °C (hPa).
+const MAGNUS_E0: f32 = 6.112;
+
+const KELVIN: f32 = 273.15;
+
+/// Saturation vapor pressure over water in hPa.
+pub fn saturation_vapor_pressure(temp_c: f32) -> f32 {
+    MAGNUS_E0 * expf(MAGNUS_B * temp_c / (MAGNUS_C + temp_c))
+}
+
+/// Actual vapor pressure in hPa.
+pub fn vapor_pressure(temp_c: f32, rh: f32) -> f32 {
+    saturation_vapor_pressure(temp_c) * clamp_rh(rh) / 100.0
+}
+
+/// Dew point in °C (Magnus formula).
+pub fn dew_point(temp_c: f32, rh: f32) -> f32 {
+    // ln(0) is undefined, 0.1 % is well below the DHT11 resolution anyway.
+    let rh = clamp_rh(rh).max(0.1);
+    let gamma = logf(rh / 100.0) + MAGNUS_B * temp_c / (MAGNUS_C + temp_c);
+    MAGNUS_C * gamma / (MAGNUS_B - gamma)
+}
+
+/// Heat index (apparent temperature) in °C, using the NOAA/NWS algorithm.
+///
+/// Below ~27 °C the Steadman simple formula is used, above it the Rothfusz regression with
+/// the NWS low and high humidity adjustments.
+pub fn heat_index(temp_c: f32, rh: f32) -> f32 {
+    let t = celsius_to_fahrenheit(temp_c);
+    let rh = clamp_rh(rh);
+
+    let simple = 0.5 * (t + 61.0 + (t - 68.0) * 1.2 + rh * 0.094);
+    if (simple + t) / 2.0 < 80.0 {
+        return fahrenheit_to_celsius(simple);
+    }
+
+    let mut hi = -42.379 + 2.049_015_2 * t + 10.143_331 * rh
+        - 0.224_755_4 * t * rh
+        - 0.006_837_83 * t * t
+        - 0.054_817_17 * rh * rh
+        + 0.001_228_74 * t * t * rh
+        + 0.000_852_82 * t * rh * rh
+        - 0.000_001_99 * t * t * rh * rh;
+
+    if rh < 13.0 && (80.0..=112.0).contains(&t) {
+        hi -= (13.0 - rh) / 4.0 * sqrtf((17.0 - (t - 95.0).abs()) / 17.0);
+    } else if rh > 85.0 && (80.0..=87.0).contains(&t) {
+        hi += (rh - 85.0) / 10.0 * ((87.0 - t) / 5.0);
+    }
+
+    fahrenheit_to_celsius(hi)
+}
+
+/// Humidex in °C, as defined by Environment Canada.
+pub fn humidex(temp_c: f32, rh: f32) -> f32 {
+    let td = dew_point(temp_c, rh) + KELVIN;
+    let e = 6.11 * expf(5417.753 * (1.0 / 273.16 - 1.0 / td));
+    temp_c + 0.5555 * (e - 10.0)
+}
+
+/// Absolute humidity in g/m³.
+pub fn absolute_humidity(temp_c: f32, rh: f32) -> f32 {
+    // e [hPa] * 100 / (Rv [J/(kg·K)] * T [K]) * 1000 [g/kg]
+    vapor_pressure(temp_c, rh) * 100_000.0 / (461.5 * (temp_c + KELVIN))
+}
+
+/// Vapor pressure deficit in kPa.
+pub fn vapor_pressure_deficit(temp_c: f32, rh: f32) -> f32 {
+    (saturation_vapor_pressure(temp_c) - vapor_pressure(temp_c, rh)) / 10.0
+}
+
+pub fn celsius_to_fahrenheit(temp_c: f32) -> f32 {
+    temp_c * 9.0 / 5.0 + 32.0
+}
+
+pub fn fahrenheit_to_celsius(temp_f: f32) -> f32 {
+    (temp_f - 32.0) * 5.0 / 9.0
+}
+
+fn clamp_rh(rh: f32) -> f32 {
+    rh.clamp(0.0, 100.0)
+}
+
+/// All derived quantities for a single temperature/humidity reading.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Derived {
+    /// Dew point (°C).
+    pub dew_point: f32,
+    /// Heat index (°C).
+    pub heat_index: f32,
+    /// Humidex (°C).
+    pub humidex: f32,
+    /// Absolute humidity (g/m³).
+    pub absolute_humidity: f32,
+    /// Vapor pressure deficit (kPa).
+    pub vapor_pressure_deficit: f32,
+}
+
+impl Derived {
+    /// Computes every derived quantity from temperature (°C) and relative humidity (%).
+    pub fn new(temp_c: f32, rh: f32) -> Self {
+        Derived {
+            dew_point: dew_point(temp_c, rh),
+            heat_index: heat_index(temp_c, rh),
+            humidex: humidex(temp_c, rh),
+            absolute_humidity: absolute_humidity(temp_c, rh),
+            vapor_pressure_deficit: vapor_pressure_deficit(temp_c, rh),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// (air °C, relative humidity %, dew point °C), NOAA/NWS dew point calculator.
+    const DEW_POINT: [(f32, f32, f32); 8] = [
+        (0.0, 80.0, -3.0),
+        (10.0, 90.0, 8.4),
+        (15.0, 40.0, 1.5),
+        (20.0, 50.0, 9.3),
+        (25.0, 60.0, 16.7),
+        (30.0, 70.0, 23.9),
+        (35.0, 20.0, 8.7),
+        (-10.0, 60.0, -16.4),
+    ];
+
+    /// (air °F, relative humidity %, heat index °F), NWS heat index chart.
+    const HEAT_INDEX: [(f32, f32, f32); 16] = [
+        (80.0, 40.0, 80.0),
+        (80.0, 60.0, 82.0),
+        (84.0, 70.0, 90.0),
+        (86.0, 40.0, 85.0),
+        (86.0, 90.0, 105.0),
+        (88.0, 60.0, 95.0),
+        (90.0, 40.0, 91.0),
+        (90.0, 50.0, 95.0),
+        (90.0, 70.0, 106.0),
+        (92.0, 80.0, 121.0),
+        (94.0, 55.0, 106.0),
+        (96.0, 45.0, 104.0),
+        (100.0, 40.0, 109.0),
+        (100.0, 55.0, 124.0),
+        (104.0, 40.0, 119.0),
+        (110.0, 40.0, 136.0),
+    ];
+
+    fn assert_close(actual: f32, expected: f32, tolerance: f32, case: impl core::fmt::Debug) {
+        assert!(
+            (actual - expected).abs() <= tolerance,
+            "{:?}: got {}, expected {} ± {}",
+            case,
+            actual,
+            expected,
+            tolerance
+        );
+    }
+
+    #[test]
+    fn dew_point_matches_noaa() {
+        for (temp_c, rh, expected) in DEW_POINT {
+            assert_close(dew_point(temp_c, rh), expected, 0.1, (temp_c, rh));
+        }
+        // Saturated air is at its dew point
+        assert_close(dew_point(18.0, 100.0), 18.0, 0.01, (18.0, 100.0));
+    }
+
+    #[test]
+    fn heat_index_matches_nws_chart() {
+        // The chart is rounded to whole degrees
+        for (temp_f, rh, expected) in HEAT_INDEX {
+            let heat_index = celsius_to_fahrenheit(heat_index(fahrenheit_to_celsius(temp_f), rh));
+            assert_close(heat_index, expected, 1.0, (temp_f, rh));
+        }
+    }
+
+    #[test]
+    fn heat_index_adjustments() {
+        // Low humidity adjustment (NWS example: 95 °F at 10 % is about 89 °F instead of 91 °F)
+        let dry = celsius_to_fahrenheit(heat_index(fahrenheit_to_celsius(95.0), 10.0));
+        assert_close(dry, 89.0, 1.0, (95.0, 10.0));
+        // Below 80 °F the simple formula stays close to the air temperature
+        let mild = celsius_to_fahrenheit(heat_index(fahrenheit_to_celsius(70.0), 50.0));
+        assert_close(mild, 69.5, 1.0, (70.0, 50.0));
+    }
+
+    #[test]
+    fn humidex_matches_environment_canada() {
+        // 30 °C with a 15 °C dew point gives a humidex of 34
+        let rh = 100.0 * vapor_pressure(15.0, 100.0) / saturation_vapor_pressure(30.0);
+        assert_close(humidex(30.0, rh), 34.0, 0.5, rh);
+    }
+
+    #[test]
+    fn humidity_quantities() {
+        // Saturated air at 30 °C holds 30.4 g/m³
+        assert_close(absolute_humidity(30.0, 100.0), 30.4, 0.2, (30.0, 100.0));
+        assert_close(vapor_pressure_deficit(25.0, 50.0), 1.58, 0.01, (25.0, 50.0));
+        assert_close(vapor_pressure_deficit(25.0, 100.0), 0.0, 1e-6, (25.0, 100.0));
+        // Out of range humidity is clamped
+        assert_eq!(vapor_pressure(20.0, 120.0), saturation_vapor_pressure(20.0));
+        assert!(dew_point(20.0, 0.0).is_finite());
+    }
+}
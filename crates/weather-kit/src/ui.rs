@@ -0,0 +1,663 @@
+//! Multi-page user interface for the 128x64 SSD1306 display.
+//!
+//! Everything is drawn through [`DrawTarget`], so the pages render the same way into the
+//! buffered `ssd1306` display on the device and into a `MockDisplay` on the host.
+
+use core::fmt::{self, Write};
+
+use embedded_graphics::{
+    mono_font::{
+        ascii::{FONT_10X20, FONT_6X10},
+        MonoTextStyle,
+    },
+    pixelcolor::BinaryColor,
+    prelude::*,
+    primitives::{Line, PrimitiveStyle, Rectangle, Triangle},
+    text::{Alignment, Baseline, Text, TextStyle, TextStyleBuilder},
+};
+use heapless::String;
+
+use crate::chart::{Chart, History};
+use crate::forecast::{Forecast, Tendency, Trend};
+use crate::meteo::Derived;
+
+const WIDTH: i32 = 128;
+const HEIGHT: i32 = 64;
+/// Height of the page title bar
+const HEADER_HEIGHT: i32 = 11;
+const ICON_SIZE: i32 = 9;
+
+/// Interval between history samples (seconds)
+pub const HISTORY_INTERVAL_S: u32 = 15 * 60;
+/// Number of history samples, 24 hours at `HISTORY_INTERVAL_S`
+pub const HISTORY_LEN: usize = 96;
+
+const SMALL: MonoTextStyle<'static, BinaryColor> = MonoTextStyle::new(&FONT_6X10, BinaryColor::On);
+const SMALL_INVERTED: MonoTextStyle<'static, BinaryColor> = MonoTextStyle::new(&FONT_6X10, BinaryColor::Off);
+const BIG: MonoTextStyle<'static, BinaryColor> = MonoTextStyle::new(&FONT_10X20, BinaryColor::On);
+
+const TOP_LEFT: TextStyle = TextStyleBuilder::new()
+    .alignment(Alignment::Left)
+    .baseline(Baseline::Top)
+    .build();
+const TOP_RIGHT: TextStyle = TextStyleBuilder::new()
+    .alignment(Alignment::Right)
+    .baseline(Baseline::Top)
+    .build();
+
+/// One set of sensor readings shown by the UI.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Readings {
+    /// Temperature (°C)
+    pub temperature: f32,
+    /// Relative humidity (%), `None` if the DHT11 read failed
+    pub humidity: Option<f32>,
+    /// Station pressure (hPa)
+    pub pressure: f32,
+    /// Pressure reduced to sea level (hPa)
+    pub sea_level_pressure: f32,
+    /// Illuminance (lx), `None` if the BH1750 read failed
+    pub illuminance: Option<f32>,
+    pub derived: Option<Derived>,
+    pub trend: Option<Trend>,
+    pub forecast: Option<Forecast>,
+}
+
+/// Firmware status shown on the system page.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemStatus {
+    pub uptime_s: u32,
+    /// Number of measurement cycles
+    pub samples: u32,
+    pub humidity_errors: u32,
+    pub light_errors: u32,
+}
+
+/// Minimum and maximum of one quantity.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Range {
+    pub min: f32,
+    pub max: f32,
+}
+
+impl Range {
+    fn update(range: &mut Option<Range>, value: f32) {
+        match range {
+            Some(r) => {
+                r.min = r.min.min(value);
+                r.max = r.max.max(value);
+            }
+            None => *range = Some(Range { min: value, max: value }),
+        }
+    }
+}
+
+/// Minimum and maximum values seen since the last reset.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MinMax {
+    pub temperature: Option<Range>,
+    pub humidity: Option<Range>,
+    pub pressure: Option<Range>,
+    pub illuminance: Option<Range>,
+}
+
+impl MinMax {
+    pub fn update(&mut self, readings: &Readings) {
+        Range::update(&mut self.temperature, readings.temperature);
+        Range::update(&mut self.pressure, readings.sea_level_pressure);
+        if let Some(humidity) = readings.humidity {
+            Range::update(&mut self.humidity, humidity);
+        }
+        if let Some(illuminance) = readings.illuminance {
+            Range::update(&mut self.illuminance, illuminance);
+        }
+    }
+
+    pub fn reset(&mut self) {
+        *self = Default::default();
+    }
+}
+
+/// Last 24 hours of readings shown on the chart pages.
+#[derive(Debug, Clone)]
+pub struct Histories {
+    pub temperature: History<HISTORY_LEN>,
+    /// Sea-level pressure
+    pub pressure: History<HISTORY_LEN>,
+    pub illuminance: History<HISTORY_LEN>,
+}
+
+impl Histories {
+    pub const fn new() -> Self {
+        Histories {
+            temperature: History::new(HISTORY_INTERVAL_S),
+            pressure: History::new(HISTORY_INTERVAL_S),
+            illuminance: History::new(HISTORY_INTERVAL_S),
+        }
+    }
+
+    pub fn update(&mut self, now_s: u32, readings: &Readings) {
+        self.temperature.push(now_s, readings.temperature);
+        self.pressure.push(now_s, readings.sea_level_pressure);
+        if let Some(illuminance) = readings.illuminance {
+            self.illuminance.push(now_s, illuminance);
+        }
+    }
+}
+
+impl Default for Histories {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// UI pages, in display order
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Page {
+    Overview,
+    Temperature,
+    Humidity,
+    Pressure,
+    Light,
+    TemperatureHistory,
+    PressureHistory,
+    LightHistory,
+    MinMax,
+    System,
+}
+
+impl Page {
+    pub const ALL: [Page; 10] = [
+        Page::Overview,
+        Page::Temperature,
+        Page::Humidity,
+        Page::Pressure,
+        Page::Light,
+        Page::TemperatureHistory,
+        Page::PressureHistory,
+        Page::LightHistory,
+        Page::MinMax,
+        Page::System,
+    ];
+
+    pub fn title(&self) -> &'static str {
+        match *self {
+            Page::Overview => "Weather",
+            Page::Temperature => "Temperature",
+            Page::Humidity => "Humidity",
+            Page::Pressure => "Pressure",
+            Page::Light => "Light",
+            Page::TemperatureHistory => "Temp 24h C",
+            Page::PressureHistory => "Pressure 24h hPa",
+            Page::LightHistory => "Light 24h lx",
+            Page::MinMax => "Min / Max",
+            Page::System => "System",
+        }
+    }
+
+    fn index(&self) -> usize {
+        *self as usize
+    }
+
+    pub fn next(&self) -> Page {
+        Page::ALL[(self.index() + 1) % Page::ALL.len()]
+    }
+
+    pub fn previous(&self) -> Page {
+        Page::ALL[(self.index() + Page::ALL.len() - 1) % Page::ALL.len()]
+    }
+}
+
+/// Page state, min/max statistics and history of the display.
+#[derive(Debug, Clone)]
+pub struct Ui {
+    page: Page,
+    min_max: MinMax,
+    histories: Histories,
+}
+
+impl Ui {
+    pub fn new() -> Self {
+        Ui { page: Page::Overview, min_max: MinMax::default(), histories: Histories::new() }
+    }
+
+    pub fn page(&self) -> Page {
+        self.page
+    }
+
+    pub fn set_page(&mut self, page: Page) {
+        self.page = page;
+    }
+
+    pub fn next_page(&mut self) {
+        self.page = self.page.next();
+    }
+
+    pub fn previous_page(&mut self) {
+        self.page = self.page.previous();
+    }
+
+    pub fn min_max(&self) -> &MinMax {
+        &self.min_max
+    }
+
+    pub fn reset_min_max(&mut self) {
+        self.min_max.reset();
+    }
+
+    pub fn histories(&self) -> &Histories {
+        &self.histories
+    }
+
+    /// Feeds new readings taken at `now_s` (monotonic seconds) into the min/max statistics
+    /// and the history.
+    pub fn update(&mut self, now_s: u32, readings: &Readings) {
+        self.min_max.update(readings);
+        self.histories.update(now_s, readings);
+    }
+
+    /// Draws the current page. The target is expected to be cleared beforehand.
+    pub fn draw<D>(&self, target: &mut D, readings: &Readings, status: &SystemStatus) -> Result<(), D::Error>
+        where
+            D: DrawTarget<Color = BinaryColor>,
+    {
+        self.draw_header(target)?;
+        match self.page {
+            Page::Overview => draw_overview(target, readings),
+            Page::Temperature => draw_temperature(target, readings),
+            Page::Humidity => draw_humidity(target, readings),
+            Page::Pressure => draw_pressure(target, readings),
+            Page::Light => draw_light(target, readings),
+            Page::TemperatureHistory => {
+                let data = self.histories.temperature.iter();
+                Chart::sparkline(chart_area(), data).with_axes(true).draw(target)
+            }
+            Page::PressureHistory => {
+                let data = self.histories.pressure.iter();
+                Chart::sparkline(chart_area(), data).with_axes(true).with_min_span(2.0).draw(target)
+            }
+            Page::LightHistory => {
+                let data = self.histories.illuminance.iter();
+                Chart::bar_graph(chart_area(), data).with_precision(0).with_min_span(10.0).draw(target)
+            }
+            Page::MinMax => draw_min_max(target, &self.min_max),
+            Page::System => draw_system(target, status),
+        }
+    }
+
+    fn draw_header<D>(&self, target: &mut D) -> Result<(), D::Error>
+        where
+            D: DrawTarget<Color = BinaryColor>,
+    {
+        Rectangle::new(Point::zero(), Size::new(WIDTH as u32, HEADER_HEIGHT as u32))
+            .into_styled(PrimitiveStyle::with_fill(BinaryColor::On))
+            .draw(target)?;
+        Text::with_text_style(self.page.title(), Point::new(2, 1), SMALL_INVERTED, TOP_LEFT)
+            .draw(target)?;
+        let index: String<8> = format(format_args!("{}/{}", self.page.index() + 1, Page::ALL.len()));
+        Text::with_text_style(&index, Point::new(WIDTH - 2, 1), SMALL_INVERTED, TOP_RIGHT)
+            .draw(target)?;
+        Ok(())
+    }
+}
+
+impl Default for Ui {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Formats into a fixed capacity string, truncating on overflow.
+fn format<const N: usize>(args: fmt::Arguments) -> String<N> {
+    let mut s = String::new();
+    let _ = s.write_fmt(args);
+    s
+}
+
+/// Draws `value` in the big font with `unit` in the small font right after it.
+///
+/// `top_left` is the top left corner of the number, returns the x coordinate after the unit.
+pub fn draw_big_number<D>(target: &mut D, top_left: Point, value: &str, unit: &str) -> Result<i32, D::Error>
+    where
+        D: DrawTarget<Color = BinaryColor>,
+{
+    let next = Text::with_text_style(value, top_left, BIG, TOP_LEFT).draw(target)?;
+    // Align the unit with the bottom of the big digits.
+    let unit_pos = Point::new(next.x + 2, top_left.y + 6);
+    let next = Text::with_text_style(unit, unit_pos, SMALL, TOP_LEFT).draw(target)?;
+    Ok(next.x)
+}
+
+/// Draws a trend arrow with its top left corner at `top_left`.
+pub fn draw_trend_icon<D>(target: &mut D, top_left: Point, tendency: Tendency) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = BinaryColor>,
+{
+    let fill = PrimitiveStyle::with_fill(BinaryColor::On);
+    let stroke = PrimitiveStyle::with_stroke(BinaryColor::On, 1);
+    let s = ICON_SIZE - 1;
+    let mid = s / 2;
+    let p = |x, y| top_left + Point::new(x, y);
+    match tendency {
+        Tendency::Rising => {
+            Triangle::new(p(mid, 0), p(0, mid), p(s, mid)).into_styled(fill).draw(target)?;
+            Line::new(p(mid, mid), p(mid, s)).into_styled(stroke).draw(target)
+        }
+        Tendency::Falling => {
+            Triangle::new(p(mid, s), p(0, mid), p(s, mid)).into_styled(fill).draw(target)?;
+            Line::new(p(mid, 0), p(mid, mid)).into_styled(stroke).draw(target)
+        }
+        Tendency::Steady => {
+            Triangle::new(p(s, mid), p(mid, 0), p(mid, s)).into_styled(fill).draw(target)?;
+            Line::new(p(0, mid), p(mid, mid)).into_styled(stroke).draw(target)
+        }
+    }
+}
+
+/// Area below the header used by the chart pages
+fn chart_area() -> Rectangle {
+    let top = HEADER_HEIGHT + 2;
+    Rectangle::new(Point::new(0, top), Size::new(WIDTH as u32, (HEIGHT - top) as u32))
+}
+
+fn draw_line<D>(target: &mut D, row: i32, label: &str, value: &str) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = BinaryColor>,
+{
+    let y = HEADER_HEIGHT + 2 + row * 10;
+    Text::with_text_style(label, Point::new(0, y), SMALL, TOP_LEFT).draw(target)?;
+    Text::with_text_style(value, Point::new(WIDTH - 1, y), SMALL, TOP_RIGHT).draw(target)?;
+    Ok(())
+}
+
+fn optional<const N: usize>(value: Option<f32>, precision: usize, unit: &str) -> String<N> {
+    match value {
+        Some(v) => format(format_args!("{:.*}{}", precision, v, unit)),
+        None => format(format_args!("--{}", unit)),
+    }
+}
+
+fn draw_overview<D>(target: &mut D, r: &Readings) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = BinaryColor>,
+{
+    let temp: String<8> = format(format_args!("{:.1}", r.temperature));
+    draw_big_number(target, Point::new(0, HEADER_HEIGHT + 2), &temp, "C")?;
+    let humidity: String<8> = optional(r.humidity, 0, "%");
+    Text::with_text_style(&humidity, Point::new(WIDTH - 1, HEADER_HEIGHT + 8), SMALL, TOP_RIGHT)
+        .draw(target)?;
+
+    let y = HEADER_HEIGHT + 24;
+    if let Some(trend) = r.trend {
+        draw_trend_icon(target, Point::new(0, y), trend.tendency)?;
+    }
+    let pressure: String<12> = format(format_args!("{:.1}hPa", r.sea_level_pressure));
+    Text::with_text_style(&pressure, Point::new(ICON_SIZE + 3, y), SMALL, TOP_LEFT).draw(target)?;
+    let light: String<10> = optional(r.illuminance, 0, "lx");
+    Text::with_text_style(&light, Point::new(WIDTH - 1, y), SMALL, TOP_RIGHT).draw(target)?;
+
+    if let Some(forecast) = r.forecast {
+        let description = forecast.description();
+        let fit = description.len().min((WIDTH / 6) as usize);
+        Text::with_text_style(&description[..fit], Point::new(0, HEIGHT - 10), SMALL, TOP_LEFT)
+            .draw(target)?;
+    }
+    Ok(())
+}
+
+fn draw_temperature<D>(target: &mut D, r: &Readings) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = BinaryColor>,
+{
+    let temp: String<8> = format(format_args!("{:.1}", r.temperature));
+    draw_big_number(target, Point::new(0, HEADER_HEIGHT + 2), &temp, "C")?;
+    let fahrenheit: String<8> = format(format_args!("{:.1}F", crate::meteo::celsius_to_fahrenheit(r.temperature)));
+    Text::with_text_style(&fahrenheit, Point::new(WIDTH - 1, HEADER_HEIGHT + 8), SMALL, TOP_RIGHT)
+        .draw(target)?;
+
+    let derived = r.derived;
+    let heat_index: String<8> = optional(derived.map(|d| d.heat_index), 1, "C");
+    draw_line(target, 3, "Heat index", &heat_index)?;
+    let humidex: String<8> = optional(derived.map(|d| d.humidex), 1, "C");
+    draw_line(target, 4, "Humidex", &humidex)
+}
+
+fn draw_humidity<D>(target: &mut D, r: &Readings) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = BinaryColor>,
+{
+    let humidity: String<8> = optional(r.humidity, 0, "");
+    draw_big_number(target, Point::new(0, HEADER_HEIGHT + 2), &humidity, "%RH")?;
+
+    let derived = r.derived;
+    let dew_point: String<8> = optional(derived.map(|d| d.dew_point), 1, "C");
+    draw_line(target, 2, "Dew point", &dew_point)?;
+    let absolute: String<12> = optional(derived.map(|d| d.absolute_humidity), 1, "g/m3");
+    draw_line(target, 3, "Absolute", &absolute)?;
+    let vpd: String<10> = optional(derived.map(|d| d.vapor_pressure_deficit), 2, "kPa");
+    draw_line(target, 4, "VPD", &vpd)
+}
+
+fn draw_pressure<D>(target: &mut D, r: &Readings) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = BinaryColor>,
+{
+    let sea_level: String<8> = format(format_args!("{:.1}", r.sea_level_pressure));
+    let x = draw_big_number(target, Point::new(0, HEADER_HEIGHT + 2), &sea_level, "hPa")?;
+    if let Some(trend) = r.trend {
+        draw_trend_icon(target, Point::new(x + 3, HEADER_HEIGHT + 7), trend.tendency)?;
+    }
+
+    let station: String<12> = format(format_args!("{:.1}hPa", r.pressure));
+    draw_line(target, 2, "Station", &station)?;
+    let rate: String<12> = optional(r.trend.map(|t| t.rate_hpa_per_h), 2, "hPa/h");
+    draw_line(target, 3, "Trend", &rate)?;
+    let forecast: String<4> = match r.forecast {
+        Some(f) => format(format_args!("{}", f.letter())),
+        None => format(format_args!("-")),
+    };
+    draw_line(target, 4, "Zambretti", &forecast)
+}
+
+fn draw_light<D>(target: &mut D, r: &Readings) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = BinaryColor>,
+{
+    let light: String<10> = optional(r.illuminance, 0, "");
+    draw_big_number(target, Point::new(0, HEADER_HEIGHT + 2), &light, "lx")?;
+    let condition = match r.illuminance {
+        None => "No reading",
+        Some(lx) if lx < 10.0 => "Dark",
+        Some(lx) if lx < 200.0 => "Dim",
+        Some(lx) if lx < 1000.0 => "Indoor",
+        Some(lx) if lx < 10000.0 => "Overcast",
+        Some(_) => "Daylight",
+    };
+    draw_line(target, 3, "Condition", condition)
+}
+
+fn draw_min_max<D>(target: &mut D, m: &MinMax) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = BinaryColor>,
+{
+    fn range<const N: usize>(range: Option<Range>, precision: usize) -> String<N> {
+        match range {
+            Some(r) => format(format_args!("{:.*} / {:.*}", precision, r.min, precision, r.max)),
+            None => format(format_args!("-- / --")),
+        }
+    }
+
+    draw_line(target, 0, "Temp C", &range::<16>(m.temperature, 1))?;
+    draw_line(target, 1, "RH %", &range::<16>(m.humidity, 0))?;
+    draw_line(target, 2, "hPa", &range::<16>(m.pressure, 1))?;
+    draw_line(target, 3, "lx", &range::<16>(m.illuminance, 0))
+}
+
+fn draw_system<D>(target: &mut D, s: &SystemStatus) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = BinaryColor>,
+{
+    let uptime: String<16> = format(format_args!(
+        "{}d {:02}:{:02}:{:02}",
+        s.uptime_s / 86400,
+        s.uptime_s / 3600 % 24,
+        s.uptime_s / 60 % 60,
+        s.uptime_s % 60
+    ));
+    draw_line(target, 0, "Uptime", &uptime)?;
+    let samples: String<12> = format(format_args!("{}", s.samples));
+    draw_line(target, 1, "Samples", &samples)?;
+    let humidity_errors: String<12> = format(format_args!("{}", s.humidity_errors));
+    draw_line(target, 2, "DHT11 errors", &humidity_errors)?;
+    let light_errors: String<12> = format(format_args!("{}", s.light_errors));
+    draw_line(target, 3, "BH1750 errors", &light_errors)
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::{env, fs, path::PathBuf, string::String as StdString};
+
+    use embedded_graphics::mock_display::{ColorMapping, MockDisplay};
+
+    use super::*;
+    use crate::forecast::{zambretti, Hemisphere};
+
+    const HALF: i32 = 64;
+
+    /// Draws the current page and returns the screen as text, one line per pixel row:
+    /// `#` on, `.` off and blank where nothing was drawn.
+    fn render(ui: &Ui, readings: &Readings, status: &SystemStatus) -> StdString {
+        // A MockDisplay is 64x64, the screen is drawn as two halves
+        let halves = [0, HALF].map(|x| {
+            let mut display = MockDisplay::new();
+            display.set_allow_overdraw(true);
+            display.set_allow_out_of_bounds_drawing(true);
+            ui.draw(&mut display.translated(Point::new(-x, 0)), readings, status).unwrap();
+            display
+        });
+        let mut screen = StdString::new();
+        for y in 0..HEIGHT {
+            let row: StdString = (0..WIDTH)
+                .map(|x| halves[(x / HALF) as usize].get_pixel(Point::new(x % HALF, y)))
+                .map(|pixel| pixel.map_or(' ', BinaryColor::color_to_char))
+                .collect();
+            screen.push_str(row.trim_end());
+            screen.push('\n');
+        }
+        screen
+    }
+
+    /// Compares `screen` with `snapshots/<name>.txt`, run with `UPDATE_SNAPSHOTS=1` to
+    /// write the snapshots after an intended change.
+    fn assert_snapshot(name: &str, screen: &str) {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("snapshots").join(name).with_extension("txt");
+        if env::var_os("UPDATE_SNAPSHOTS").is_some() {
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, screen).unwrap();
+            return;
+        }
+        let expected = fs::read_to_string(&path)
+            .unwrap_or_else(|_| panic!("no snapshot {}, run with UPDATE_SNAPSHOTS=1", path.display()));
+        assert!(screen == expected, "{} differs from the snapshot, rendered:\n{}", name, screen);
+    }
+
+    fn readings() -> Readings {
+        let (temperature, humidity) = (23.4, 55.0);
+        Readings {
+            temperature,
+            humidity: Some(humidity),
+            pressure: 978.3,
+            sea_level_pressure: 1012.6,
+            illuminance: Some(420.0),
+            derived: Some(Derived::new(temperature, humidity)),
+            trend: Some(Trend { tendency: Tendency::Falling, rate_hpa_per_h: -0.8 }),
+            forecast: Some(zambretti(1012.6, Tendency::Falling, 10, Hemisphere::Northern)),
+        }
+    }
+
+    fn status() -> SystemStatus {
+        SystemStatus { uptime_s: 93_784, samples: 1563, humidity_errors: 2, light_errors: 0 }
+    }
+
+    /// UI fed with a day of readings: a temperature swing, falling pressure and daylight.
+    fn ui_after_a_day() -> Ui {
+        let mut ui = Ui::new();
+        for i in 0..HISTORY_LEN as u32 {
+            let mut r = readings();
+            r.temperature = 20.0 + 4.0 * libm::sinf(i as f32 * 0.065);
+            r.sea_level_pressure = 1016.0 - i as f32 * 0.04;
+            let daylight = (28..76).contains(&i);
+            r.illuminance = Some(if daylight { 200.0 + ((i - 28) * (76 - i)) as f32 * 15.0 } else { 2.0 });
+            ui.update(i * HISTORY_INTERVAL_S, &r);
+        }
+        ui
+    }
+
+    #[test]
+    fn pages_match_snapshots() {
+        let mut ui = ui_after_a_day();
+        for page in Page::ALL {
+            ui.set_page(page);
+            let name = std::format!("{:02}-{:?}", page.index() + 1, page);
+            assert_snapshot(&name, &render(&ui, &readings(), &status()));
+        }
+    }
+
+    #[test]
+    fn missing_readings_match_snapshots() {
+        let readings = Readings {
+            humidity: None,
+            illuminance: None,
+            derived: None,
+            trend: None,
+            forecast: None,
+            ..readings()
+        };
+        let mut ui = Ui::new();
+        for page in [Page::Overview, Page::Humidity, Page::Pressure, Page::MinMax] {
+            ui.set_page(page);
+            let name = std::format!("missing-{:?}", page);
+            assert_snapshot(&name, &render(&ui, &readings, &status()));
+        }
+    }
+
+    #[test]
+    fn empty_history_draws_only_the_header() {
+        let mut ui = Ui::new();
+        for page in [Page::TemperatureHistory, Page::PressureHistory, Page::LightHistory] {
+            ui.set_page(page);
+            let screen = render(&ui, &readings(), &status());
+            assert!(screen.lines().skip(HEADER_HEIGHT as usize).all(str::is_empty), "{:?}", page);
+        }
+    }
+
+    #[test]
+    fn pages_wrap_around() {
+        let mut ui = Ui::new();
+        ui.previous_page();
+        assert_eq!(ui.page(), Page::System);
+        ui.next_page();
+        assert_eq!(ui.page(), Page::Overview);
+        for page in Page::ALL {
+            assert_eq!(page.next().previous(), page);
+        }
+    }
+
+    #[test]
+    fn min_max_tracks_readings() {
+        let mut ui = Ui::new();
+        ui.update(0, &readings());
+        ui.update(60, &Readings { temperature: 19.0, humidity: None, ..readings() });
+        let min_max = ui.min_max();
+        assert_eq!(min_max.temperature, Some(Range { min: 19.0, max: 23.4 }));
+        assert_eq!(min_max.humidity, Some(Range { min: 55.0, max: 55.0 }));
+        assert_eq!(min_max.pressure, Some(Range { min: 1012.6, max: 1012.6 }));
+        // Within one history interval only the first reading is kept
+        assert_eq!(ui.histories().temperature.len(), 1);
+
+        ui.reset_min_max();
+        assert_eq!(ui.min_max().temperature, None);
+    }
+}
@@ -2,7 +2,7 @@
 #![no_main]
 
 use core::any::Any;
-use embedded_hal::can::{Can, Frame, StandardId};
+use embedded_hal::can::Can;
 use hal::{clock::ClockControl, gpio::IO, i2c::I2C, peripherals::Peripherals, prelude::*, Delay};
 use esp_backtrace as _;
 use esp_hal_common::gpio::{GpioPin, Output};
@@ -15,6 +15,7 @@ use esp_hal_common::twai::filter::{Filter, SingleStandardFilter};
 use esp_hal_common::twai::{BaudRate, TimingConfig};
 use esp_println::logger::init_logger;
 use esp_println::println;
+use weather_can::{Message, Telemetry};
 
 #[entry]
 fn main() -> ! {
@@ -77,9 +78,10 @@ fn main() -> ! {
     loop {
         count += 1;
         if count % 10_000_000 == 13 {
-            // Send a frame
+            // Send a telemetry frame of node 0
+            let telemetry = Telemetry { node: 0, sequence: out, message: Message::temperature(21.5) };
             out = out.wrapping_add(1);
-            let frame = Frame::new(StandardId::new(0).unwrap(), &[0xaa, 0xbb, 0xcc, out]).unwrap();
+            let frame = telemetry.to_frame().unwrap();
             info!("Sent a frame, frame: {:?}", frame);
             let res = can.transmit(&frame);
             info!("Res: {:?}", res);
@@ -0,0 +1,72 @@
+//! Stepper axis commanded over CAN
+//!
+//! This example drives an A4988 axis from CAN commands (see the `stepper-can` crate): the
+//! controller homes it, sets speed and acceleration and sends move, stop and query commands,
+//! the axis acknowledges each one and reports its status when a move ends.
+//!
+//! The following wiring is assumed:
+//! - CAN transceiver TX => GPIO5, RX => GPIO4
+//! - DIR => GPIO2, STEP => GPIO3
+//! - Home switch => GPIO6, closes to GND
+
+#![no_std]
+#![no_main]
+
+use esp_backtrace as _;
+use hal::{clock::ClockControl, gpio::IO, peripherals::Peripherals, prelude::*, twai, Delay};
+use hal::twai::filter::SingleStandardFilter;
+use hal_exp::remote_stepper::RemoteStepper;
+use log::{error, info};
+use stepper_driver::{HomingConfig, MotorDriver};
+
+/// Node ID of this axis on the CAN bus
+const NODE: u8 = 2;
+
+#[entry]
+fn main() -> ! {
+    let peripherals = Peripherals::take();
+    let system = peripherals.SYSTEM.split();
+    let clocks = ClockControl::boot_defaults(system.clock_control).freeze();
+
+    esp_println::logger::init_logger_from_env();
+
+    let io = IO::new(peripherals.GPIO, peripherals.IO_MUX);
+
+    let mut can_config = twai::TwaiConfiguration::new(
+        peripherals.TWAI0,
+        io.pins.gpio5,
+        io.pins.gpio4,
+        &clocks,
+        twai::BaudRate::B125K,
+    );
+    // Commands to node 2 (0x202), the identifier is still checked in `RemoteStepper`
+    const FILTER: SingleStandardFilter =
+        SingleStandardFilter::new(b"01000000010", b"x", [b"xxxxxxxx", b"xxxxxxxx"]);
+    can_config.set_filter(FILTER);
+    let mut can = can_config.start();
+
+    let mut motor = MotorDriver::a4988(
+        Delay::new(&clocks),
+        io.pins.gpio2.into_push_pull_output(),
+        io.pins.gpio3.into_push_pull_output(),
+        200,
+        16,
+        60.0,
+    )
+    .unwrap();
+    // Steps/s², until the controller sets its own
+    motor.set_acceleration(400.0).unwrap();
+    motor.set_limits(Some((0, 200 * 16 * 10)));
+
+    let switch = io.pins.gpio6.into_pull_up_input();
+    let homing = HomingConfig { timeout: Some(30_000_000), ..HomingConfig::default() };
+    let mut axis = RemoteStepper::new(NODE, switch, homing).unwrap();
+    axis.set_speed(200.0);
+
+    info!("Axis {} waiting for commands", NODE);
+    loop {
+        if let Err(err) = axis.poll(&mut can, &mut motor) {
+            error!("Axis error: {:?}", err);
+        }
+    }
+}
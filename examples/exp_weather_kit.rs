@@ -1,25 +1,18 @@
 //! I2C Display example
 //!
 //! This example prints some text on an SSD1306-based
-//! display (via I2C)
+//! display (via I2C) and broadcasts the readings on CAN
 //!
 //! The following wiring is assumed:
-//! - SDA => GPIO3
-//! - SCL => GPIO4
+//! - SDA => GPIO1
+//! - SCL => GPIO2
+//! - CAN transceiver TX => GPIO5, RX => GPIO4
 
 #![no_std]
 #![no_main]
 
 extern crate alloc;
-use embedded_graphics::{
-    mono_font::{
-        ascii::{FONT_6X10, FONT_9X18_BOLD},
-        MonoTextStyleBuilder,
-    },
-    pixelcolor::BinaryColor,
-    prelude::*,
-    text::{Alignment, Text},
-};
+use embedded_graphics::{pixelcolor::BinaryColor, prelude::*};
 use hal::{
     Delay,
     clock::ClockControl,
@@ -28,6 +21,7 @@ use hal::{
     peripherals::Peripherals,
     prelude::*,
     timer::TimerGroup,
+    twai,
 };
 use esp_backtrace as _;
 use nb::block;
@@ -35,10 +29,22 @@ use ssd1306::{prelude::*, I2CDisplayInterface, Ssd1306};
 use log::{error, info};
 use hal_exp::bh1750::{BH1750, MeasurementTime, Resolution};
 use hal_exp::bmp180::{BMP180, Oversampling};
-use alloc::format;
 use hal_exp::shared_i2c::SharedI2cBus;
-
-const DEFAULT_HUMIDITY:u16 = 450;
+use hal_exp::meteo;
+use hal_exp::forecast::{self, Hemisphere, PressureTrend};
+use hal_exp::telemetry::Broadcaster;
+use hal_exp::ui::{Readings, SystemStatus, Ui};
+
+/// Station altitude used to reduce pressure to sea level (meters)
+const ALTITUDE_M: f32 = 50.0;
+/// Current month, the kit has no RTC
+const MONTH: u8 = 6;
+/// Measurement interval (seconds)
+const INTERVAL_S: u32 = 5;
+/// Number of measurement cycles each page stays on screen
+const PAGE_CYCLES: u32 = 2;
+/// Node ID of this station on the CAN bus
+const CAN_NODE: u8 = 1;
 
 #[entry]
 fn main() -> ! {
@@ -70,16 +76,6 @@ fn main() -> ! {
         .into_buffered_graphics_mode();
     display.init().unwrap();
 
-    // Specify different text styles
-    let text_style = MonoTextStyleBuilder::new()
-        .font(&FONT_6X10)
-        .text_color(BinaryColor::On)
-        .build();
-    let text_style_big = MonoTextStyleBuilder::new()
-        .font(&FONT_9X18_BOLD)
-        .text_color(BinaryColor::On)
-        .build();
-
     let mut bh1750 = BH1750::new(shared_i2c.clone(), delay);
     bh1750.set_measurement_time(MeasurementTime::Default).unwrap();
     bh1750.reset().unwrap();
@@ -90,69 +86,87 @@ fn main() -> ! {
     let mut dht11 = dht11::Dht11::new(dht11_pin);
     let mut ets_delay = hal_exp::ets_delay::EtsDelay;
 
+    // Telemetry only sends, all frames are accepted by the default filter
+    let can_config = twai::TwaiConfiguration::new(
+        peripherals.TWAI0,
+        io.pins.gpio5,
+        io.pins.gpio4,
+        &clocks,
+        twai::BaudRate::B125K,
+    );
+    let mut can = can_config.start();
+    let mut broadcaster = Broadcaster::new(CAN_NODE).unwrap();
+
+    // 18 samples over 3 hours, one every 10 minutes
+    let mut pressure_trend: PressureTrend<18> = PressureTrend::new();
+    let mut ui = Ui::new();
+    let mut status = SystemStatus::default();
+
     // Start timer (5 second interval)
-    timer0.start(5u64.secs());
+    timer0.start((INTERVAL_S as u64).secs());
     loop {
         bh1750.set_resolution(Resolution::Lx1_0);
-        let illuminance = bh1750.illuminance();
+        let illuminance = match bh1750.illuminance() {
+            Ok(lx) => Some(lx),
+            Err(err) => {
+                error!("BH1750 error: {:?}", err);
+                status.light_errors += 1;
+                None
+            }
+        };
         let (temp, pressure) = bmp180.temperature_and_pressure(Oversampling::O1).unwrap();
+        let temp_c = temp as f32 / 10.0;
+
+        log::info!("Temp: {:.2}C Pressure: {:.2}kPa", temp_c, pressure as f32 / 1000.0);
 
-        log::info!("Temp: {:.2}C Pressure: {:.2}kPa", temp as f32 / 10.0, pressure as f32 / 1000.0);
+        let sea_level_hpa = forecast::sea_level_pressure(pressure as f32 / 100.0, ALTITUDE_M, temp_c);
+        pressure_trend.push(status.uptime_s, sea_level_hpa);
+        let trend = pressure_trend.trend();
+        let zambretti = trend.map(|trend| forecast::zambretti(sea_level_hpa, trend.tendency, MONTH, Hemisphere::Northern));
+        if let (Some(trend), Some(zambretti)) = (trend, zambretti) {
+            info!("Trend: {:?} {:.2}hPa/h, forecast {}: {}",
+                trend.tendency, trend.rate_hpa_per_h, zambretti.letter(), zambretti.description());
+        }
 
         let humidity = match dht11.perform_measurement(&mut ets_delay) {
-            Ok(res) => res.humidity,
+            Ok(res) => Some(res.humidity as f32 / 10.0),
             Err(err) => {
                 error!("DHT error: {:?}", err);
-                DEFAULT_HUMIDITY
+                status.humidity_errors += 1;
+                None
             }
         };
 
-        // Fill display bufffer with a centered text with two lines (and two text
-        // styles)
-        let t = display.bounding_box();
-        Text::with_alignment(
-            format!("Humidity: {:.1}C", humidity as f32 / 10.0).as_str(),
-            t.center() + Point::new(0, -8),
-            text_style,
-            Alignment::Center,
-        )
-            .draw(&mut display)
-            .unwrap();
-
-        let temp_c = temp as f32 / 10.0;
-        Text::with_alignment(
-            format!("Temp: {:.1}C {:.1}F", temp_c, temp_c * 9.0 / 5.0 + 32.0).as_str(),
-            t.center() + Point::new(0, 2),
-            text_style,
-            Alignment::Center,
-        )
-            .draw(&mut display)
-            .unwrap();
-
-        Text::with_alignment(
-            format!("Pressure: {:.2}atm", pressure as f32 / 101300.0).as_str(),
-            t.center() + Point::new(0, 12),
-            text_style,
-            Alignment::Center,
-        )
-            .draw(&mut display)
-            .unwrap();
-
-        Text::with_alignment(
-            format!("Illuminance: {:.2?}", illuminance.expect("")).as_str(),
-            t.center() + Point::new(0, 22),
-            text_style,
-            Alignment::Center,
-        )
-            .draw(&mut display)
-            .unwrap();
+        let readings = Readings {
+            temperature: temp_c,
+            humidity,
+            pressure: pressure as f32 / 100.0,
+            sea_level_pressure: sea_level_hpa,
+            illuminance,
+            derived: humidity.map(|rh| meteo::Derived::new(temp_c, rh)),
+            trend,
+            forecast: zambretti,
+        };
+        ui.update(status.uptime_s, &readings);
+        status.samples += 1;
+        if status.samples % PAGE_CYCLES == 0 {
+            ui.next_page();
+        }
+        ui.draw(&mut display, &readings, &status).unwrap();
 
         // Write buffer to display
         display.flush().unwrap();
         // Clear display buffer
         display.clear(BinaryColor::Off).unwrap();
 
+        // After the display, so a CAN bus without other nodes does not delay the page
+        match broadcaster.broadcast(&mut can, &mut delay, &readings, &status) {
+            Ok(sent) => info!("CAN: sent {} frames, {} dropped", sent, broadcaster.dropped()),
+            Err(err) => error!("CAN error: {:?}", err),
+        }
+
         // Wait 5 seconds
         block!(timer0.wait()).unwrap();
+        status.uptime_s = status.uptime_s.wrapping_add(INTERVAL_S);
     }
 }
@@ -43,14 +43,14 @@ fn main() -> ! {
     let step_pin = io.pins.gpio3.into_push_pull_output();
 
     info!("hello world 1");
-    let mut driver = MotorDriver::a4988(Delay::new(&clocks), dir_pin, step_pin, 200, 1, 100f32);
+    let mut driver = MotorDriver::a4988(Delay::new(&clocks), dir_pin, step_pin, 200, 1, 100f32).unwrap();
 
     loop {
-        driver.set_speed(100f32);
-        driver.set_direction(true);
-        driver.move_instant(20);
-        driver.set_direction(false);
-        driver.move_instant(10);
+        driver.set_speed(100f32).unwrap();
+        driver.set_direction(true).unwrap();
+        driver.move_instant(20).unwrap();
+        driver.set_direction(false).unwrap();
+        driver.move_instant(10).unwrap();
 
         led.set_high().unwrap();
         delay.delay_ms(500u32);
@@ -0,0 +1,103 @@
+//! Background stepping
+//!
+//! Moves an A4988 driven motor back and forth from the SYSTIMER interrupt while the main
+//! loop keeps blinking the LED.
+//!
+//! The following wiring is assumed:
+//! - DIR => GPIO2
+//! - STEP => GPIO3
+//! - LED => GPIO8
+
+#![no_std]
+#![no_main]
+
+use core::cell::RefCell;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use critical_section::Mutex;
+use esp_backtrace as _;
+use esp_println::println;
+use hal::{
+    clock::ClockControl,
+    gpio::{GpioPin, Output, PushPull, IO},
+    interrupt,
+    interrupt::Priority,
+    peripherals::{self, Peripherals},
+    prelude::*,
+    systimer::SystemTimer,
+    Delay,
+};
+use stepper_driver::{a4988, StepEngine, TrapezoidalProfile};
+
+use hal_exp::step_timer::SystimerStepTimer;
+
+type Engine = StepEngine<
+    GpioPin<Output<PushPull>, 2>,
+    GpioPin<Output<PushPull>, 3>,
+    a4988,
+    SystimerStepTimer<0>,
+>;
+
+static ENGINE: Mutex<RefCell<Option<Engine>>> = Mutex::new(RefCell::new(None));
+static MOVE_DONE: AtomicBool = AtomicBool::new(true);
+
+/// Move length (steps at 1:16)
+const STEPS: u32 = 3200;
+
+fn move_done() {
+    MOVE_DONE.store(true, Ordering::Release);
+}
+
+#[entry]
+fn main() -> ! {
+    let peripherals = Peripherals::take();
+    let system = peripherals.SYSTEM.split();
+    let clocks = ClockControl::boot_defaults(system.clock_control).freeze();
+    let mut delay = Delay::new(&clocks);
+
+    let io = IO::new(peripherals.GPIO, peripherals.IO_MUX);
+    let mut led = io.pins.gpio8.into_push_pull_output();
+    let dir_pin = io.pins.gpio2.into_push_pull_output();
+    let step_pin = io.pins.gpio3.into_push_pull_output();
+
+    let syst = SystemTimer::new(peripherals.SYSTIMER);
+    let timer = SystimerStepTimer::new(syst.alarm0);
+    let mut engine: Engine = StepEngine::new(dir_pin, step_pin, timer).unwrap();
+    engine.set_on_complete(Some(move_done));
+    // Back and forth between 0 and STEPS
+    engine.set_limits(Some((0, STEPS as i64)));
+    critical_section::with(|cs| ENGINE.borrow_ref_mut(cs).replace(engine));
+
+    interrupt::enable(peripherals::Interrupt::SYSTIMER_TARGET0, Priority::Priority3).unwrap();
+
+    let mut clockwise = true;
+    loop {
+        if MOVE_DONE.swap(false, Ordering::Acquire) {
+            println!("Move done, reversing");
+            let profile = TrapezoidalProfile::new(STEPS, 6400.0, 12800.0);
+            critical_section::with(|cs| {
+                let mut engine = ENGINE.borrow_ref_mut(cs);
+                let engine = engine.as_mut().unwrap();
+                if let Err(err) = engine.start_move(profile, clockwise) {
+                    println!("Move refused: {:?}", err);
+                }
+                println!("Position {}", engine.position());
+            });
+            clockwise = !clockwise;
+        }
+
+        led.toggle().unwrap();
+        delay.delay_ms(250u32);
+    }
+}
+
+#[interrupt]
+fn SYSTIMER_TARGET0() {
+    critical_section::with(|cs| {
+        if let Some(engine) = ENGINE.borrow_ref_mut(cs).as_mut() {
+            if let Err(err) = engine.on_interrupt() {
+                println!("Move aborted: {:?}", err);
+            }
+        }
+    });
+}
@@ -0,0 +1,67 @@
+//! Hardware step generation
+//!
+//! Moves a DRV8825 driven motor back and forth with acceleration ramps, the STEP pulses come
+//! from the RMT peripheral instead of a busy-wait loop.
+//!
+//! The following wiring is assumed:
+//! - DIR => GPIO2
+//! - STEP => GPIO3
+//! - LED => GPIO8
+
+#![no_std]
+#![no_main]
+
+use esp_backtrace as _;
+use esp_println::println;
+use hal::{
+    clock::ClockControl,
+    gpio::IO,
+    peripherals::Peripherals,
+    prelude::*,
+    rmt::{Rmt, TxChannelConfig, TxChannelCreator},
+    Delay,
+};
+use stepper_driver::{drv8825, MotorDriver, RmtStepGenerator};
+
+#[entry]
+fn main() -> ! {
+    let peripherals = Peripherals::take();
+    let system = peripherals.SYSTEM.split();
+    let clocks = ClockControl::boot_defaults(system.clock_control).freeze();
+    let mut delay = Delay::new(&clocks);
+
+    let io = IO::new(peripherals.GPIO, peripherals.IO_MUX);
+    let mut led = io.pins.gpio8.into_push_pull_output();
+    let dir_pin = io.pins.gpio2.into_push_pull_output();
+    let step_pin = io.pins.gpio3.into_push_pull_output();
+
+    // 1 µs ticks, STEP idles low
+    let rmt = Rmt::new(peripherals.RMT, 1u32.MHz(), &clocks).unwrap();
+    let channel = rmt
+        .channel0
+        .configure(
+            step_pin,
+            TxChannelConfig {
+                clk_divider: 1,
+                ..TxChannelConfig::default()
+            },
+        )
+        .unwrap();
+    let generator: RmtStepGenerator<_, _, 0, 48> = RmtStepGenerator::new(channel, dir_pin);
+    let mut driver = MotorDriver::<_, drv8825>::new(generator, 200, 16, 60.0).unwrap();
+
+    loop {
+        // 5 revolutions there and back at 60 rpm, then 5 more ramping up to 10 rev/s
+        driver.move_by(16_000).unwrap();
+        driver.move_to(0).unwrap();
+        driver.move_accelerated(1000, 2000.0, 4000.0).unwrap();
+        println!(
+            "Position {}, {} steps emitted",
+            driver.position(),
+            driver.step_generator().steps_emitted()
+        );
+
+        led.toggle().unwrap();
+        delay.delay_ms(500u32);
+    }
+}
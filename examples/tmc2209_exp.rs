@@ -1,48 +1,106 @@
-//! Read calibration data from BMP180 sensor
+//! Configure two TMC2209 on one UART
 //!
-//! This example dumps the calibration data from a BMP180 sensor
+//! This example drives a two-axis head whose TMC2209 share UART1. It sets the motor current,
+//! microstep resolution and chopper mode of both, homes X against its end stop with
+//! StallGuard, then moves both axes with STEP/DIR and logs the driver diagnostics.
 //!
 //! The following wiring is assumed:
-//! - SDA => GPIO1
-//! - SCL => GPIO2
+//! - UART1 TX => PDN_UART of both drivers through 1 kΩ => GPIO4
+//! - UART1 RX => PDN_UART of both drivers => GPIO5
+//! - X: MS1, MS2 => GND (slave address 0), DIR => GPIO2, STEP => GPIO3, DIAG => GPIO6
+//! - Y: MS1 => VIO, MS2 => GND (slave address 1), DIR => GPIO0, STEP => GPIO1
+//! - EN => GND
+//!
+//! Every byte sent on the single wire is received back, the `Bus` drops the echo.
 
 #![no_std]
 #![no_main]
 
-use core::any::Any;
-use hal::{clock::ClockControl, gpio::IO, i2c::I2C, peripherals::Peripherals, prelude::*, Delay};
 use esp_backtrace as _;
-use esp_hal_common::gpio::{GpioPin, Output};
+use hal::{
+    clock::ClockControl,
+    gpio::IO,
+    peripherals::Peripherals,
+    prelude::*,
+    uart::{config::Config, TxRxPins},
+    Delay, Uart,
+};
 use log::info;
-use hal_exp::bh1750::{BH1750, MeasurementTime, Resolution};
 use stepper_driver::MotorDriver;
-use esp_hal_common::gpio;
+use tmc2209::stallguard::{Diag, SensorlessConfig};
+use tmc2209::{Bus, ChopperMode, Gconf, MotorCurrent, Pwmconf};
 
 #[entry]
 fn main() -> ! {
-    hal_exp::util::init_heap();
-
     let peripherals = Peripherals::take();
     let system = peripherals.SYSTEM.split();
     let clocks = ClockControl::boot_defaults(system.clock_control).freeze();
     let mut delay = Delay::new(&clocks);
 
-    let mut io = IO::new(peripherals.GPIO, peripherals.IO_MUX);
-    let mut led = io.pins.gpio8.into_push_pull_output();
-
     esp_println::logger::init_logger_from_env();
 
-    info!("hello world 5");
+    let io = IO::new(peripherals.GPIO, peripherals.IO_MUX);
+    let pins = TxRxPins::new_tx_rx(
+        io.pins.gpio4.into_push_pull_output(),
+        io.pins.gpio5.into_floating_input(),
+    );
+    let uart = Uart::new_with_config(peripherals.UART1, Config::default(), Some(pins), &clocks);
+    let bus = Bus::new(uart, true);
+    let mut x = bus.driver(0).unwrap();
+    let mut y = bus.driver(1).unwrap();
 
-    let mut t = false;
-    loop {
-        info!("Current: {}", t);
-        t = !t;
+    for driver in [&mut x, &mut y] {
+        // UART only on PDN_UART
+        driver
+            .modify(|gconf: &mut Gconf| {
+                gconf.set_pdn_disable(true).set_multistep_filt(true);
+            })
+            .unwrap();
+        driver.set_microsteps(16).unwrap();
+        driver.set_current(&MotorCurrent { run: 800, hold: 300, ..MotorCurrent::default() }).unwrap();
+        // Power-on StealthChop tuning
+        driver.write(Pwmconf(0xc10d_0024)).unwrap();
+    }
+    // SpreadCycle above 3 rev/s on Y, X stays in StealthChop for StallGuard
+    y.set_chopper_mode(ChopperMode::Hybrid { velocity: 9600.0 }).unwrap();
 
-        led.set_high().unwrap();
-        delay.delay_ms(500u32);
+    let mut x_motor = MotorDriver::tmc2209(
+        delay,
+        io.pins.gpio2.into_push_pull_output(),
+        io.pins.gpio3.into_push_pull_output(),
+        200,
+        16,
+        120.0,
+    )
+    .unwrap();
+    let mut y_motor = MotorDriver::tmc2209(
+        delay,
+        io.pins.gpio0.into_push_pull_output(),
+        io.pins.gpio1.into_push_pull_output(),
+        200,
+        16,
+        240.0,
+    )
+    .unwrap();
+
+    let mut diag = Diag(io.pins.gpio6.into_floating_input());
+    let homing = SensorlessConfig { sgthrs: 80, ..SensorlessConfig::default() };
+    x.home_sensorless(&mut x_motor, &mut diag, &homing).unwrap();
+    info!("X homed");
+
+    loop {
+        x_motor.move_to(3200).unwrap();
+        y_motor.move_by(6400).unwrap();
+        x_motor.move_to(0).unwrap();
+        y_motor.move_by(-6400).unwrap();
 
-        led.set_low().unwrap();
+        for (name, driver) in [("X", &mut x), ("Y", &mut y)] {
+            let diagnostics = driver.diagnostics().unwrap();
+            info!(
+                "{}: {:?}, open load {:?}, current scale {}",
+                name, diagnostics.overtemperature, diagnostics.open_load, diagnostics.current_scale
+            );
+        }
         delay.delay_ms(500u32);
     }
 }
@@ -1,4 +1,5 @@
 //! This example sends a CAN message to another ESP and receives it back.
+//! Telemetry frames of the weather kit are decoded and logged.
 //!
 //! Wiring:
 //! This example works without CAN Transceivers by:
@@ -39,7 +40,8 @@ use esp_hal_common::twai::filter::{Filter, SingleStandardFilter};
 use esp_println::logger::init_logger;
 use esp_println::println;
 use hal::entry;
-use log::{info, LevelFilter};
+use log::{info, warn, LevelFilter};
+use weather_can::Telemetry;
 
 #[entry]
 fn main() -> ! {
@@ -55,8 +57,8 @@ fn main() -> ! {
     let can_tx_pin = io.pins.gpio0.into_open_drain_output();
     let can_rx_pin = io.pins.gpio1.into_floating_input();
 
-    // The speed of the CAN bus.
-    const CAN_BAUDRATE: twai::BaudRate = twai::BaudRate::B1000K;
+    // The speed of the CAN bus, the same as the weather kit.
+    const CAN_BAUDRATE: twai::BaudRate = twai::BaudRate::B125K;
 
     // Begin configuring the TWAI peripheral. The peripheral is in a reset like
     // state that prevents transmission but allows configuration.
@@ -73,9 +75,9 @@ fn main() -> ! {
     // standard ids and extended ids may both match a filter. Frame ids should
     // be explicitly checked in the application instead of fully relying on
     // these partial acceptance filters to exactly match.
-    // A filter that matches StandardId::ZERO.
+    // A filter that matches the telemetry range 0x400-0x5ff.
     const FILTER: SingleStandardFilter =
-        SingleStandardFilter::new(b"00000000000", b"x", [b"xxxxxxxx", b"xxxxxxxx"]);
+        SingleStandardFilter::new(b"10xxxxxxxxx", b"x", [b"xxxxxxxx", b"xxxxxxxx"]);
     can_config.set_filter(FILTER);
 
     // let regs = FILTER.to_registers();
@@ -93,17 +95,35 @@ fn main() -> ! {
     //     println!("Sent a frame");
     // }
 
-    // Wait for a frame to be received.
-    info!("Waiting for a can frame...");
-    let frame = block!(can.receive()).unwrap();
-
-    info!("Received a frame: {frame:?}");
-    //
-    // if !IS_FIRST_SENDER {
-    //     // Transmit the frame back to the other ESP
-    //     block!(can.transmit(&frame)).unwrap();
-    //     println!("Sent a frame");
-    // }
-
-    loop {}
+    // Last sequence counter of each node
+    let mut sequences = [None; weather_can::MAX_NODE as usize + 1];
+    loop {
+        // Wait for a frame to be received.
+        info!("Waiting for a can frame...");
+        let frame = block!(can.receive()).unwrap();
+
+        info!("Received a frame: {frame:?}");
+        //
+        // if !IS_FIRST_SENDER {
+        //     // Transmit the frame back to the other ESP
+        //     block!(can.transmit(&frame)).unwrap();
+        //     println!("Sent a frame");
+        // }
+
+        match Telemetry::from_frame(&frame) {
+            Ok(telemetry) => {
+                let last = &mut sequences[telemetry.node as usize];
+                // All messages of a cycle carry its sequence counter
+                if let Some(previous) = last.filter(|&previous| previous != telemetry.sequence) {
+                    let missed = weather_can::missed(previous, telemetry.sequence);
+                    if missed > 0 {
+                        warn!("Node {}: {} cycles missed", telemetry.node, missed);
+                    }
+                }
+                *last = Some(telemetry.sequence);
+                info!("Node {} #{}: {:?}", telemetry.node, telemetry.sequence, telemetry.message);
+            }
+            Err(err) => warn!("Not telemetry: {:?}", err),
+        }
+    }
 }
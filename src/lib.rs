@@ -9,4 +9,9 @@ pub mod bh1750;
 pub mod shared_i2c;
 pub mod ets_delay;
 pub mod dht11;
+pub mod step_timer;
+pub mod telemetry;
 mod backup;
+
+pub use stepper_remote as remote_stepper;
+pub use weather_kit::{chart, forecast, meteo, ui};
@@ -0,0 +1,48 @@
+//! SYSTIMER alarm driving a `stepper_driver::StepEngine`.
+//!
+//! Alarms are programmed as absolute targets, each one relative to the previous target, so
+//! the step timing does not drift with interrupt latency.
+
+use hal::systimer::{Alarm, SystemTimer, Target};
+use stepper_driver::StepTimer;
+
+const TICKS_PER_US: u64 = SystemTimer::TICKS_PER_SECOND / 1_000_000;
+
+pub struct SystimerStepTimer<const CHANNEL: u8> {
+    alarm: Alarm<Target, CHANNEL>,
+    /// Timestamp of the last programmed alarm (ticks)
+    target: u64,
+}
+
+impl<const CHANNEL: u8> SystimerStepTimer<CHANNEL> {
+    /// Takes over `alarm`, its interrupt still has to be enabled in the interrupt controller.
+    pub fn new(alarm: Alarm<Target, CHANNEL>) -> Self {
+        alarm.enable_interrupt(false);
+        alarm.clear_interrupt();
+        SystimerStepTimer { alarm, target: 0 }
+    }
+
+    fn set_target(&mut self, target: u64) {
+        self.target = target;
+        self.alarm.clear_interrupt();
+        self.alarm.set_target(target);
+        self.alarm.enable_interrupt(true);
+    }
+}
+
+impl<const CHANNEL: u8> StepTimer for SystimerStepTimer<CHANNEL> {
+    fn start(&mut self, us: u32) {
+        self.set_target(SystemTimer::now() + us as u64 * TICKS_PER_US);
+    }
+
+    fn schedule(&mut self, us: u32) {
+        // Never program a target in the past, the alarm would not fire until the counter wraps.
+        let target = (self.target + us as u64 * TICKS_PER_US).max(SystemTimer::now() + TICKS_PER_US);
+        self.set_target(target);
+    }
+
+    fn cancel(&mut self) {
+        self.alarm.enable_interrupt(false);
+        self.alarm.clear_interrupt();
+    }
+}
@@ -0,0 +1,99 @@
+//! Broadcast of the weather readings over CAN, see the `weather-can` crate for the messages.
+//!
+//! The TWAI peripheral has one transmit buffer, so each frame waits for the previous one to
+//! leave. A frame that can not be queued within `TX_TIMEOUT_US`, e.g. because no other node
+//! acknowledges, is dropped. The rest of the cycle then gets one try per frame without
+//! waiting, so a lone node loses at most one timeout per measurement.
+
+use embedded_hal::blocking::delay::DelayUs;
+use embedded_hal::can::Can;
+use hal::prelude::nb;
+use weather_can::{Message, Status, Telemetry, FLAG_HUMIDITY, FLAG_ILLUMINANCE};
+
+use crate::ui::{Readings, SystemStatus};
+
+/// Longest wait for the transmit buffer (µs)
+const TX_TIMEOUT_US: u32 = 100_000;
+const TX_POLL_US: u32 = 500;
+
+pub struct Broadcaster {
+    node: u8,
+    sequence: u8,
+    /// Frames that did not get out in time
+    dropped: u32,
+}
+
+impl Broadcaster {
+    /// Broadcaster of station `node` (0-63).
+    pub fn new(node: u8) -> Result<Self, weather_can::Error> {
+        if node > weather_can::MAX_NODE {
+            return Err(weather_can::Error::InvalidNode(node));
+        }
+        Ok(Broadcaster { node, sequence: 0, dropped: 0 })
+    }
+
+    pub fn dropped(&self) -> u32 {
+        self.dropped
+    }
+
+    /// Sends the messages of one measurement cycle and returns the number of frames sent.
+    pub fn broadcast<C, D>(&mut self, can: &mut C, delay: &mut D, readings: &Readings, status: &SystemStatus) -> Result<usize, C::Error>
+    where
+        C: Can,
+        D: DelayUs<u32>,
+    {
+        let mut flags = 0;
+        let mut messages: heapless::Vec<Message, 5> = heapless::Vec::new();
+        messages.push(Message::temperature(readings.temperature)).ok();
+        messages.push(Message::pressure(readings.pressure, readings.sea_level_pressure)).ok();
+        if let Some(humidity) = readings.humidity {
+            flags |= FLAG_HUMIDITY;
+            messages.push(Message::humidity(humidity)).ok();
+        }
+        if let Some(illuminance) = readings.illuminance {
+            flags |= FLAG_ILLUMINANCE;
+            messages.push(Message::illuminance(illuminance)).ok();
+        }
+        messages
+            .push(Message::Status(Status {
+                uptime_s: status.uptime_s,
+                humidity_errors: status.humidity_errors.min(u8::MAX as u32) as u8,
+                light_errors: status.light_errors.min(u8::MAX as u32) as u8,
+                flags,
+            }))
+            .ok();
+
+        let mut sent = 0;
+        let mut timeout = TX_TIMEOUT_US;
+        for message in messages {
+            let telemetry = Telemetry { node: self.node, sequence: self.sequence, message };
+            // The node was checked in `new`
+            let frame: C::Frame = telemetry.to_frame().unwrap();
+            if self.transmit(can, delay, &frame, timeout)? {
+                sent += 1;
+            } else {
+                self.dropped += 1;
+                timeout = 0;
+            }
+        }
+        self.sequence = self.sequence.wrapping_add(1);
+        Ok(sent)
+    }
+
+    /// Queues `frame`, `false` if the transmit buffer stayed busy for `timeout` µs.
+    fn transmit<C: Can, D: DelayUs<u32>>(&mut self, can: &mut C, delay: &mut D, frame: &C::Frame, timeout: u32) -> Result<bool, C::Error> {
+        let mut waited = 0;
+        loop {
+            match can.transmit(frame) {
+                // The TWAI never replaces a pending frame
+                Ok(_) => return Ok(true),
+                Err(nb::Error::Other(e)) => return Err(e),
+                Err(nb::Error::WouldBlock) if waited >= timeout => return Ok(false),
+                Err(nb::Error::WouldBlock) => {
+                    delay.delay_us(TX_POLL_US);
+                    waited += TX_POLL_US;
+                }
+            }
+        }
+    }
+}